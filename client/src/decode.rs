@@ -0,0 +1,126 @@
+use std::fmt;
+
+use crate::types::Field;
+
+/// An error converting a value in a result into a Rust type.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct DecodeError {
+    pub column: String,
+    pub message: String,
+}
+
+impl fmt::Display for DecodeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "error decoding column {:?}: {}",
+            self.column, self.message
+        )
+    }
+}
+
+impl std::error::Error for DecodeError {}
+
+/// A type which can be decoded from a value in a query result.
+///
+/// Values are in their MySQL text representation (Eg. `b"123"` for an `INT64`) and `None` is `NULL`.
+pub trait FromValue: Sized {
+    fn from_value(field: &Field, value: Option<&[u8]>) -> Result<Self, String>;
+}
+
+fn not_null<'a>(field: &Field, value: Option<&'a [u8]>) -> Result<&'a [u8], String> {
+    value.ok_or_else(|| format!("unexpected NULL for {} column", field.ty))
+}
+
+fn text<'a>(field: &Field, value: Option<&'a [u8]>) -> Result<&'a str, String> {
+    std::str::from_utf8(not_null(field, value)?).map_err(|err| err.to_string())
+}
+
+macro_rules! impl_from_value {
+    ($($ty:ty),*) => {
+        $(
+            impl FromValue for $ty {
+                fn from_value(field: &Field, value: Option<&[u8]>) -> Result<Self, String> {
+                    text(field, value)?
+                        .parse()
+                        .map_err(|err| format!("invalid {}: {err}", stringify!($ty)))
+                }
+            }
+        )*
+    };
+}
+
+impl_from_value!(i8, i16, i32, i64, u8, u16, u32, u64, f32, f64);
+
+impl FromValue for bool {
+    fn from_value(field: &Field, value: Option<&[u8]>) -> Result<Self, String> {
+        match text(field, value)? {
+            "0" => Ok(false),
+            "1" => Ok(true),
+            value => Err(format!("invalid bool: {value:?}")),
+        }
+    }
+}
+
+impl FromValue for String {
+    fn from_value(field: &Field, value: Option<&[u8]>) -> Result<Self, String> {
+        text(field, value).map(ToString::to_string)
+    }
+}
+
+impl FromValue for Vec<u8> {
+    fn from_value(field: &Field, value: Option<&[u8]>) -> Result<Self, String> {
+        not_null(field, value).map(<[u8]>::to_vec)
+    }
+}
+
+impl<T: FromValue> FromValue for Option<T> {
+    fn from_value(field: &Field, value: Option<&[u8]>) -> Result<Self, String> {
+        value.map(|v| T::from_value(field, Some(v))).transpose()
+    }
+}
+
+/// A type which can be decoded from a row in a query result, which is implemented for tuples of [`FromValue`] types.
+pub trait FromRow: Sized {
+    fn from_row(fields: &[Field], row: &[Option<Vec<u8>>]) -> Result<Self, DecodeError>;
+}
+
+/// Decode the value in column `index` of a row.
+pub fn decode_column<T: FromValue>(
+    fields: &[Field],
+    row: &[Option<Vec<u8>>],
+    index: usize,
+) -> Result<T, DecodeError> {
+    let field = fields.get(index).ok_or_else(|| DecodeError {
+        column: index.to_string(),
+        message: format!("the result only has {} columns", fields.len()),
+    })?;
+    let value = row.get(index).ok_or_else(|| DecodeError {
+        column: field.name.clone(),
+        message: format!("the row only has {} values", row.len()),
+    })?;
+
+    T::from_value(field, value.as_deref()).map_err(|message| DecodeError {
+        column: field.name.clone(),
+        message,
+    })
+}
+
+macro_rules! impl_from_row {
+    ($($ty:ident => $index:tt),*) => {
+        impl<$($ty: FromValue),*> FromRow for ($($ty,)*) {
+            fn from_row(fields: &[Field], row: &[Option<Vec<u8>>]) -> Result<Self, DecodeError> {
+                Ok(($(decode_column::<$ty>(fields, row, $index)?,)*))
+            }
+        }
+    };
+}
+
+impl_from_row!(A => 0);
+impl_from_row!(A => 0, B => 1);
+impl_from_row!(A => 0, B => 1, C => 2);
+impl_from_row!(A => 0, B => 1, C => 2, D => 3);
+impl_from_row!(A => 0, B => 1, C => 2, D => 3, E => 4);
+impl_from_row!(A => 0, B => 1, C => 2, D => 3, E => 4, F => 5);
+impl_from_row!(A => 0, B => 1, C => 2, D => 3, E => 4, F => 5, G => 6);
+impl_from_row!(A => 0, B => 1, C => 2, D => 3, E => 4, F => 5, G => 6, H => 7);
@@ -0,0 +1,410 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use serde::{Deserialize, Serialize};
+use uuid::Uuid;
+
+/// The state of a client's session, which it sends back with each request.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct TransactionSession {
+    /// The transaction the session is in, if any.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub id: Option<Uuid>,
+    /// The database the session has switched to with `USE`, if it's not the one in the credentials.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub database: Option<String>,
+}
+
+impl TransactionSession {
+    /// The session to return to the client, which is `null` if there is no state to keep.
+    pub fn non_empty(&self) -> Option<&Self> {
+        (self.id.is_some() || self.database.is_some()).then_some(self)
+    }
+}
+
+/// What a session holds on to between requests.
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub enum SessionMode {
+    /// The session is a transaction which ends when it's committed or rolled back.
+    #[default]
+    Transaction,
+    /// The session pins a connection without opening a transaction, so session variables and temporary tables are kept between requests.
+    /// `BEGIN`, `COMMIT` and `ROLLBACK` are run on the connection like any other query.
+    Connection,
+}
+
+/// The body of `/CreateSession`.
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+pub struct CreateSessionRequest {
+    #[serde(default)]
+    pub mode: SessionMode,
+}
+
+/// The body of `/Execute` and `/StreamExecute`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct SqlRequest {
+    pub query: String,
+    pub session: Option<TransactionSession>,
+    /// Values for `:name` placeholders in the query which are bound on the server.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub bind_vars: HashMap<String, BindVariable>,
+}
+
+/// The body of `/ExecuteBatch`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchRequest {
+    pub queries: Vec<BatchQuery>,
+    pub session: Option<TransactionSession>,
+    /// Run the queries in a transaction which is rolled back if any of them fail.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub as_transaction: bool,
+}
+
+#[derive(Debug, Clone, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct BatchQuery {
+    pub query: String,
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub bind_vars: HashMap<String, BindVariable>,
+}
+
+/// A value for a `:name` placeholder in a query.
+///
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/query.proto#L296
+#[derive(Debug, Clone, Default, PartialEq, Deserialize, Serialize)]
+pub struct BindVariable {
+    /// The Vitess type of the value. Like protobuf, this being omitted means `NULL_TYPE`.
+    #[serde(rename = "type", default, skip_serializing_if = "Option::is_none")]
+    pub ty: Option<String>,
+    /// The base64 encoded value in it's MySQL text representation (Eg. `"123"` for an `INT64`).
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub value: Option<String>,
+    /// The values of a `TUPLE`.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub values: Vec<serde_json::Value>,
+}
+
+impl BindVariable {
+    /// A value of the Vitess type `ty` (Eg. `INT64`) from it's MySQL text representation.
+    pub fn new(ty: &str, value: impl AsRef<[u8]>) -> Self {
+        Self {
+            ty: Some(ty.to_string()),
+            value: Some(STANDARD.encode(value)),
+            values: Vec::new(),
+        }
+    }
+
+    /// `NULL`
+    pub fn null() -> Self {
+        Self::default()
+    }
+}
+
+macro_rules! impl_bind_variable {
+    ($($ty:ty => $vitess:literal),* $(,)?) => {
+        $(
+            impl From<$ty> for BindVariable {
+                fn from(value: $ty) -> Self {
+                    Self::new($vitess, value.to_string())
+                }
+            }
+        )*
+    };
+}
+
+impl_bind_variable!(
+    i8 => "INT8",
+    i16 => "INT16",
+    i32 => "INT32",
+    i64 => "INT64",
+    u8 => "UINT8",
+    u16 => "UINT16",
+    u32 => "UINT32",
+    u64 => "UINT64",
+    f32 => "FLOAT32",
+    f64 => "FLOAT64",
+);
+
+impl From<bool> for BindVariable {
+    fn from(value: bool) -> Self {
+        Self::from(i8::from(value))
+    }
+}
+
+impl From<&str> for BindVariable {
+    fn from(value: &str) -> Self {
+        Self::new("VARCHAR", value)
+    }
+}
+
+impl From<String> for BindVariable {
+    fn from(value: String) -> Self {
+        Self::new("VARCHAR", value)
+    }
+}
+
+impl From<&[u8]> for BindVariable {
+    fn from(value: &[u8]) -> Self {
+        Self::new("VARBINARY", value)
+    }
+}
+
+impl From<Vec<u8>> for BindVariable {
+    fn from(value: Vec<u8>) -> Self {
+        Self::new("VARBINARY", value)
+    }
+}
+
+impl<T: Into<BindVariable>> From<Option<T>> for BindVariable {
+    fn from(value: Option<T>) -> Self {
+        value.map_or_else(Self::null, Into::into)
+    }
+}
+
+/// The response of `/Execute`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ExecuteResponse {
+    pub session: Option<TransactionSession>,
+    pub result: Option<QueryResult>,
+    /// An error from the query itself (Eg. a syntax error), which doesn't end the session.
+    pub error: Option<RpcError>,
+    /// How long the query took in seconds.
+    #[serde(default)]
+    pub timing: f64,
+}
+
+/// The response of `/ExecuteBatch`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BatchResponse {
+    pub session: Option<TransactionSession>,
+    pub results: Vec<BatchResult>,
+    #[serde(default)]
+    pub timing: f64,
+}
+
+/// The outcome of a query in a batch, which is either a result or an error.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct BatchResult {
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result: Option<QueryResult>,
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub error: Option<RpcError>,
+}
+
+/// The response of `/CreateSession`.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct CreateSessionResponse {
+    pub session: TransactionSession,
+}
+
+/// The body of a response with a non-`200` status code.
+#[derive(Debug, Clone, Deserialize, Serialize)]
+pub struct ErrorResponse {
+    pub error: RpcError,
+}
+
+/// An error in the format returned by Vitess.
+#[derive(Debug, Clone, PartialEq, Eq, Deserialize, Serialize)]
+pub struct RpcError {
+    pub message: String,
+    /// The Vitess RPC error code (Eg. `INVALID_ARGUMENT`).
+    pub code: String,
+}
+
+/// The result of a query.
+///
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/query.proto#L414
+#[derive(Debug, Clone, Default, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase")]
+pub struct QueryResult {
+    // Like protobuf's JSON encoding 64-bit integers are strings
+    #[serde(default, with = "string")]
+    pub rows_affected: u64,
+    #[serde(default, with = "string::option")]
+    pub insert_id: Option<u64>,
+    #[serde(default)]
+    pub fields: Vec<Field>,
+    #[serde(default)]
+    pub rows: Vec<Row>,
+}
+
+/// The metadata of a column in a query result.
+///
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/query.proto#L380
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+#[serde(rename_all = "camelCase", default)]
+pub struct Field {
+    pub name: String,
+    /// The Vitess type of the column (Eg. `INT64` or `VARCHAR`).
+    #[serde(rename = "type")]
+    pub ty: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub table: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub org_table: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub database: String,
+    #[serde(skip_serializing_if = "String::is_empty")]
+    pub org_name: String,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub column_length: u32,
+    pub charset: u32,
+    #[serde(skip_serializing_if = "is_zero")]
+    pub decimals: u32,
+    pub flags: u32,
+}
+
+// Planetscale omits these fields when they are unset, like protobuf's JSON encoding does.
+fn is_zero(v: &u32) -> bool {
+    *v == 0
+}
+
+/// A row in the Vitess format of the concatenated values and the length of each of them.
+///
+/// A length of `-1` represents `NULL`.
+#[derive(Debug, Clone, Default, PartialEq, Eq, Deserialize, Serialize)]
+pub struct Row {
+    #[serde(default)]
+    pub lengths: Vec<i64>,
+    /// The base64 encoded values.
+    #[serde(default)]
+    pub values: String,
+}
+
+impl Row {
+    pub fn encode(values: impl IntoIterator<Item = Option<impl AsRef<[u8]>>>) -> Self {
+        let mut lengths = Vec::new();
+        let mut buf = Vec::new();
+        for value in values {
+            match value {
+                Some(value) => {
+                    let value = value.as_ref();
+                    lengths.push(
+                        value
+                            .len()
+                            .try_into()
+                            .expect("unable to cast usize to i64. How big are your damn pointers?"),
+                    );
+                    buf.extend_from_slice(value);
+                }
+                None => lengths.push(-1),
+            }
+        }
+
+        Self {
+            lengths,
+            values: STANDARD.encode(buf),
+        }
+    }
+
+    /// Split the row into each of it's values, which are `None` for `NULL`.
+    pub fn decode(&self) -> Result<Vec<Option<Vec<u8>>>, String> {
+        let values = STANDARD
+            .decode(&self.values)
+            .map_err(|err| format!("row values are not valid base64: {err}"))?;
+
+        let mut offset = 0usize;
+        self.lengths
+            .iter()
+            .map(|&len| {
+                let Ok(len) = usize::try_from(len) else {
+                    return Ok(None);
+                };
+                let end = offset
+                    .checked_add(len)
+                    .ok_or("row value lengths overflow")?;
+                let value = values
+                    .get(offset..end)
+                    .ok_or("row values are shorter than their lengths")?;
+                offset = end;
+                Ok(Some(value.to_vec()))
+            })
+            .collect()
+    }
+}
+
+/// (De)serialize a `u64` as a string, which is how protobuf's JSON encoding represents 64-bit integers.
+/// Numbers are also accepted when deserializing.
+mod string {
+    use serde::{Deserialize, Deserializer, Serializer};
+
+    #[derive(Deserialize)]
+    #[serde(untagged)]
+    enum StringOrNumber {
+        String(String),
+        Number(u64),
+    }
+
+    impl StringOrNumber {
+        fn parse<E: serde::de::Error>(self) -> Result<u64, E> {
+            match self {
+                Self::String(s) => s.parse().map_err(E::custom),
+                Self::Number(n) => Ok(n),
+            }
+        }
+    }
+
+    pub fn serialize<S: Serializer>(value: &u64, serializer: S) -> Result<S::Ok, S::Error> {
+        serializer.collect_str(value)
+    }
+
+    pub fn deserialize<'de, D: Deserializer<'de>>(deserializer: D) -> Result<u64, D::Error> {
+        StringOrNumber::deserialize(deserializer)?.parse()
+    }
+
+    pub mod option {
+        use super::*;
+
+        pub fn serialize<S: Serializer>(
+            value: &Option<u64>,
+            serializer: S,
+        ) -> Result<S::Ok, S::Error> {
+            match value {
+                Some(value) => serializer.collect_str(value),
+                None => serializer.serialize_none(),
+            }
+        }
+
+        pub fn deserialize<'de, D: Deserializer<'de>>(
+            deserializer: D,
+        ) -> Result<Option<u64>, D::Error> {
+            Option::<StringOrNumber>::deserialize(deserializer)?
+                .map(StringOrNumber::parse)
+                .transpose()
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_rows() {
+        let values = [Some("a".as_bytes()), None, Some(b""), Some(b"bcd")];
+        let row = Row::encode(values);
+        assert_eq!(row.lengths, [1, -1, 0, 3]);
+        assert_eq!(
+            row.decode().unwrap(),
+            values.map(|value| value.map(<[u8]>::to_vec))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_rows() {
+        let short = Row {
+            lengths: vec![1, 4],
+            values: STANDARD.encode("abc"),
+        };
+        assert!(short.decode().is_err());
+
+        let overflow = Row {
+            lengths: vec![1, i64::MAX, i64::MAX],
+            values: STANDARD.encode("abc"),
+        };
+        assert!(overflow.decode().is_err());
+    }
+}
@@ -1,12 +1,12 @@
-use std::{path::PathBuf, sync::Arc};
+use std::{collections::HashMap, path::PathBuf, sync::Arc};
 
 use argon2::{Argon2, PasswordHash, PasswordVerifier};
 use axum::{
     extract::{Path, Request, State},
-    http::{request::Parts, HeaderValue, StatusCode},
+    http::{request::Parts, HeaderMap, HeaderValue, StatusCode},
     middleware::{self, Next},
     response::{IntoResponse, Response},
-    routing::{delete, get, post},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use include_dir::{include_dir, Dir};
@@ -20,16 +20,31 @@ use tower_service::Service;
 use tracing::{debug, error, warn};
 
 use crate::config::ConfigManager;
+use cancel::QueryKiller;
 
+mod branches;
+mod cancel;
+mod deploys;
+mod migrations;
+mod schema;
 mod settings;
 mod sql;
 
+pub use deploys::fail_interrupted_deploys;
+pub use migrations::Migrations;
+pub use sql::{ConnectionPool, NativeConnection};
+
 #[derive(Clone)]
 pub struct AppState {
+    #[allow(dead_code)] // TODO: Will be used for backups
     pub data_dir: PathBuf,
     pub config: ConfigManager,
     pub db_opts: mysql_async::Opts,
     pub db: mysql_async::Pool,
+    /// The connection pools and sessions of the `psdb` API.
+    pub connections: Arc<ConnectionPool>,
+    /// The online schema changes run since Cityscale started.
+    pub migrations: Arc<Migrations>,
 }
 
 const USERNAME_HEADER: &str = "username";
@@ -58,7 +73,7 @@ static ASSETS_DIR: Dir<'static> = include_dir!("$CARGO_MANIFEST_DIR/web/dist");
 
 pub fn mount(state: Arc<AppState>) -> axum::Router {
     Router::new()
-        .nest("/psdb.v1alpha1.Database", sql::mount())
+        .nest("/psdb.v1alpha1.Database", sql::mount(state.clone()))
         .route(
             "/api/login",
             post(
@@ -70,7 +85,7 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
                         return (StatusCode::UNAUTHORIZED, "Unauthorized");
                     };
 
-                    let Ok(parsed_hash) = PasswordHash::new(&password) else {
+                    let Ok(parsed_hash) = PasswordHash::new(password) else {
                         error!("Failed to parse password hash for user '{}'", data.username);
                         return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error");
                     };
@@ -127,6 +142,10 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
                     ),
                 )
                 .nest("/settings", settings::mount())
+                .merge(branches::mount())
+                .merge(schema::mount())
+                .merge(deploys::mount())
+                .merge(migrations::mount())
                 .route(
                     "/database",
                     get(|State(state): State<Arc<AppState>>| async move {
@@ -149,6 +168,7 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
                                 .into_response();
                         };
 
+                        let config = state.config.get();
                         let dbs = dbs
                             .into_iter()
                             .filter(|name| {
@@ -158,8 +178,11 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
                                     || name == "sys")
                             })
                             .map(|name| {
+                                let db = config.databases.get(&name);
                                 json!({
                                     "name": name,
+                                    "branch": db.and_then(|db| db.branch.clone()),
+                                    "protected": db.is_some_and(|db| db.protected),
                                 })
                             })
                             .collect::<Vec<_>>();
@@ -232,6 +255,13 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
                         )
                             .into_response();
                     };
+                    state.connections.invalidate_database(&db_name);
+                    state.connections.clear_cache();
+
+                    let mut config = state.config.edit();
+                    if config.databases.remove(&db_name).is_some() && config.commit().map_err(|err| error!("Error saving config: {err:?}")).is_err() {
+                        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to commit changes!").into_response();
+                    }
 
                     (StatusCode::OK, "ok").into_response()
                 }))
@@ -288,7 +318,7 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
                             let mut tables = Vec::new();
                             for table_name in table_names {
                                 // TODO: Proper SQL escaping
-                                if !table_name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-' || c == '_' || c == '-') {
+                                if !table_name.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
                                     warn!("Found non-numeric table name '{}', skipping", table_name);
                                     continue;
                                 }
@@ -305,7 +335,7 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
 
                                 tables.push(json!({
                                     "name": table_name,
-                                    "schema": schema.into_iter().nth(0).unwrap_or_default()
+                                    "schema": schema.into_iter().next().unwrap_or_default()
                                 }));
                             }
 
@@ -322,8 +352,12 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
                                     ).into_response();
                             };
 
+                            let (branch, protected) = state.config.get().databases.get(db_name)
+                                .map_or((None, false), |db| (db.branch.clone(), db.protected));
                             let db = json!({
                                 "name": db_name,
+                                "branch": branch,
+                                "protected": protected,
                                 "tables": tables,
                                 "users": users
                                     .into_iter()
@@ -335,58 +369,108 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
                         },
                     ),
                 )
-                .route("/database/:db/execute", post(|State(state): State<Arc<AppState>>, Path(db_name): Path<String>, Json(stmt): Json<String>| async move {
-                    let Ok(mut conn) = state
-                        .db
-                        .get_conn()
-                        .await
-                        .map_err(|err| error!("Error getting DB connection: {err}"))
-                    else {
-                        return (
-                            StatusCode::INTERNAL_SERVER_ERROR,
-                            "Internal Server Error",
-                        )
-                            .into_response();
-                    };
-
-                     // TODO: Proper SQL escaping
-                     if db_name.chars().any(|c| !c.is_alphanumeric() || c == '_' || c == '-') {
-                        return (StatusCode::BAD_REQUEST, "Invalid username").into_response();
-                    }
-                    // TODO: This kicks the connection from the pool. Can we workaround this???
-                    // TODO: Maybe try and use a random user in the SQL connection pooler???
-                    let Ok(_) = conn.change_user(ChangeUserOpts::new().with_db_name(Some(db_name.clone())))
-                        .await
-                        .map_err(|err| error!("Error changing DB to '{}': {err}", db_name)) else {
-                            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+                .route("/database/:db/execute", post(|State(state): State<Arc<AppState>>, Path(db_name): Path<String>, headers: HeaderMap, Json(stmt): Json<String>| {
+                    let killer = QueryKiller::new(state.db.clone());
+                    killer.clone().detach(async move {
+                        let Ok(mut conn) = state
+                            .db
+                            .get_conn()
+                            .await
+                            .map_err(|err| error!("Error getting DB connection: {err}"))
+                        else {
+                            return (
+                                StatusCode::INTERNAL_SERVER_ERROR,
+                                "Internal Server Error",
+                            )
+                                .into_response();
                         };
 
-                    debug!("Executing statement against database {db_name:?}: {stmt}");
-
-                    Json(match conn.exec_iter(stmt, ()).await {
-                        Ok(mut result) =>
-                            json!({
-                                "affected_rows": result.affected_rows(),
-                                "values": result.collect::<Row>().await.unwrap().into_iter().map(|mut row| {
-                                    let mut result = Vec::with_capacity(row.len());
-                                    for i in 0..row.len() {
-                                        let Some(value) = row.take::<Value, _>(i) else {
-                                            continue;
-                                        };
-
-                                        result.push(value.as_sql(true));
-                                    }
-
-                                    result
-                                }).collect::<Vec<_>>()
-                            }),
+                         // TODO: Proper SQL escaping
+                         if db_name.chars().any(|c| !c.is_alphanumeric() || c == '_' || c == '-') {
+                            return (StatusCode::BAD_REQUEST, "Invalid username").into_response();
+                        }
+                        if let Err(message) = sql::check_protected_query(&state.config.get(), &stmt, &db_name) {
+                            return Json(json!({ "error": message })).into_response();
+                        }
+                        // TODO: This kicks the connection from the pool. Can we workaround this???
+                        // TODO: Maybe try and use a random user in the SQL connection pooler???
+                        let Ok(_) = conn.change_user(ChangeUserOpts::new().with_db_name(Some(db_name.clone())))
+                            .await
+                            .map_err(|err| error!("Error changing DB to '{}': {err}", db_name)) else {
+                                return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+                            };
+
+                        debug!("Executing statement against database {db_name:?}: {stmt}");
+
+                        // Admins aren't limited by the default timeout of the `psdb` API, but can still set one
+                        let timeout = match cancel::timeout(&headers, None) {
+                            Ok(timeout) => timeout,
+                            Err(message) => return Json(json!({ "error": message })).into_response(),
+                        };
+                        let conn_id = conn.id();
+                        let result = killer.run(conn_id, timeout, async {
+                            let mut result = conn.exec_iter(stmt.as_str(), ()).await?;
+                            let affected_rows = result.affected_rows();
+                            let rows = result.collect::<Row>().await?;
+                            Ok::<_, Box<dyn std::error::Error + Send + Sync>>((affected_rows, rows))
+                        }).await;
+                        state.connections.invalidate_cache(&db_name, &stmt);
+
+                        Json(match result {
+                            Ok((affected_rows, rows)) =>
+                                json!({
+                                    "affected_rows": affected_rows,
+                                    "values": rows.into_iter().map(|mut row| {
+                                        let mut result = Vec::with_capacity(row.len());
+                                        for i in 0..row.len() {
+                                            let Some(value) = row.take::<Value, _>(i) else {
+                                                continue;
+                                            };
+
+                                            result.push(value.as_sql(true));
+                                        }
+
+                                        result
+                                    }).collect::<Vec<_>>()
+                                }),
+                            Err(err) => {
+                                error!("Error executing statement: {err}");
+                                json!({
+                                    "error": format!("{err}")
+                                })
+                            }
+                        }).into_response()
+                    })
+                }))
+                .route("/database/:db/protected", put(|State(state): State<Arc<AppState>>, Path(db_name): Path<String>, Json(data): Json<SetProtected>| async move {
+                    if !branches::is_valid_name(&db_name) {
+                        return (StatusCode::BAD_REQUEST, "Invalid database name").into_response();
+                    }
+                    let exists = async {
+                        let mut conn = state.db.get_conn().await?;
+                        "SELECT SCHEMA_NAME FROM information_schema.SCHEMATA WHERE SCHEMA_NAME = ?"
+                            .with((&db_name,))
+                            .first::<String, _>(&mut conn)
+                            .await
+                    };
+                    match exists.await {
+                        Ok(Some(_)) => {}
+                        Ok(None) => return (StatusCode::NOT_FOUND, "Database not found").into_response(),
                         Err(err) => {
-                            error!("Error executing statement: {err}");
-                            json!({
-                                "error": format!("{err}")
-                            })
+                            error!("Error checking DB '{db_name}' exists: {err}");
+                            return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
                         }
-                    }).into_response()
+                    }
+
+                    let mut config = state.config.edit();
+                    config.databases.entry(db_name).or_default().protected = data.protected;
+                    config.databases.retain(|_, db| db.branch.is_some() || db.protected);
+
+                    if config.commit().map_err(|err| error!("Error saving config: {err:?}")).is_err() {
+                        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to commit changes!").into_response();
+                    }
+
+                    StatusCode::NO_CONTENT.into_response()
                 }))
                 .route("/database/:db/user", post(|State(state): State<Arc<AppState>>, Path(db_name): Path<String>, Json(data): Json<CreateDatabaseUser>| async move {
                     let Ok(mut conn) = state
@@ -482,9 +566,121 @@ pub fn mount(state: Arc<AppState>) -> axum::Router {
                         )
                             .into_response();
                     };
+                    state.connections.invalidate_user(&username);
 
                     (StatusCode::OK, "ok").into_response()
                 }))
+                .route("/database/:db/user/:username/password", post(|State(state): State<Arc<AppState>>, Path((db_name, username)): Path<(String, String)>| async move {
+                    let Ok(mut conn) = state
+                        .db
+                        .get_conn()
+                        .await
+                        .map_err(|err| error!("Error getting DB connection: {err}"))
+                    else {
+                        return (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Internal Server Error",
+                        )
+                            .into_response();
+                    };
+
+                    let Ok(user) = r#"SELECT USER FROM INFORMATION_SCHEMA.USER_ATTRIBUTES WHERE ATTRIBUTE->>"$.cityscale_db"=:db_name AND USER = :username;"#
+                        .with(params! {
+                            "db_name" => &db_name,
+                            "username" => &username
+                        })
+                        .map(&mut conn, |username: String| username)
+                        .await
+                        .map_err(|err| error!("Error getting user '{}': {err}", username)) else {
+                        return (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Internal Server Error",
+                        )
+                            .into_response();
+                    };
+
+                    if user.is_empty() {
+                        return (StatusCode::NOT_FOUND, "User not found").into_response();
+                    }
+
+                    let password = Alphanumeric.sample_string(&mut rand::thread_rng(), 32);
+
+                    // TODO: Proper SQL escaping
+                    if !username.chars().all(|c| c.is_alphanumeric() || c == '_' || c == '-') {
+                        return (StatusCode::BAD_REQUEST, "Invalid username").into_response();
+                    }
+
+                    let Ok(_) = format!("ALTER USER '{username}'@'%' IDENTIFIED BY '{password}';")
+                        .ignore(&mut conn)
+                        .await
+                        .map_err(|err| error!("Error changing password of user '{}': {err}", username)) else {
+                        return (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Internal Server Error",
+                        )
+                            .into_response();
+                    };
+                    state.connections.invalidate_user(&username);
+
+                    (StatusCode::OK, Json(json!({
+                        "username": username,
+                        "password": password
+                    }))).into_response()
+                }))
+                .route("/pools", get(|State(state): State<Arc<AppState>>| async move {
+                    let Ok(mut conn) = state
+                        .db
+                        .get_conn()
+                        .await
+                        .map_err(|err| error!("Error getting DB connection: {err}"))
+                    else {
+                        return (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Internal Server Error",
+                        )
+                            .into_response();
+                    };
+
+                    // The connections MySQL has open for each user on each database, which includes any opened outside of Cityscale.
+                    let Ok(connections) = "SELECT USER, DB, COUNT(*) FROM INFORMATION_SCHEMA.PROCESSLIST WHERE DB IS NOT NULL GROUP BY USER, DB;"
+                        .with(())
+                        .map(&mut conn, |(username, database, count): (String, String, u64)| ((username, database), count))
+                        .await
+                        .map_err(|err| error!("Error getting connections: {err}")) else {
+                        return (
+                            StatusCode::INTERNAL_SERVER_ERROR,
+                            "Internal Server Error",
+                        )
+                            .into_response();
+                    };
+                    let connections = connections.into_iter().collect::<HashMap<_, _>>();
+
+                    let pools = state.connections.pool_stats().into_iter().map(|stats| {
+                        let key = (stats.username.clone(), stats.database.clone());
+                        json!({
+                            "username": stats.username,
+                            "database": stats.database,
+                            "age": stats.age,
+                            "idle": stats.idle,
+                            "connections": connections.get(&key).copied().unwrap_or(0),
+                        })
+                    }).collect::<Vec<_>>();
+
+                    (StatusCode::OK, Json(json!({
+                        "max_pools": state.config.get().credential_cache_size,
+                        "pools": pools,
+                    }))).into_response()
+                }))
+                .route("/cache", get(|State(state): State<Arc<AppState>>| async move {
+                    Json(json!({
+                        "max_size": state.config.get().result_cache_size,
+                        "stats": state.connections.cache_stats(),
+                    }))
+                }))
+                .route("/cache", delete(|State(state): State<Arc<AppState>>| async move {
+                    state.connections.clear_cache();
+                    StatusCode::NO_CONTENT
+                }))
                 .route_layer(middleware::from_fn_with_state(state.clone(), auth)),
         )
         .fallback({
@@ -526,3 +722,8 @@ pub struct CreateDatabaseUser {
     username: String,
     // role: () // TODO
 }
+
+#[derive(Deserialize)]
+pub struct SetProtected {
+    protected: bool,
+}
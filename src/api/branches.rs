@@ -0,0 +1,315 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::post,
+    Json, Router,
+};
+use mysql_async::{prelude::*, Conn};
+use serde::Deserialize;
+use serde_json::json;
+use tower_cookies::{Cookies, Key};
+use tracing::{debug, error, warn};
+
+use super::{schema, AppState, USERNAME_HEADER};
+use crate::config::Branch;
+
+pub fn mount() -> Router<Arc<AppState>> {
+    Router::new().route(
+        "/database/:db/branch",
+        post(
+            |State(state): State<Arc<AppState>>,
+             cookies: Cookies,
+             Path(parent): Path<String>,
+             Json(data): Json<CreateBranchRequest>| async move {
+                let created_by = cookies
+                    .private(&Key::from(state.config.get().secret.as_bytes()))
+                    .get(USERNAME_HEADER)
+                    .expect("checked in auth middleware")
+                    .value()
+                    .to_string();
+
+                // TODO: This is a crude way to prevent SQL injection, can we do something better here?
+                if !is_valid_name(&parent) || !is_valid_name(&data.name) {
+                    return (StatusCode::BAD_REQUEST, "Invalid database name").into_response();
+                }
+
+                // The branch's session variables and `USE` mustn't leak into the pool, so it gets it's own connection
+                let Ok(mut conn) = Conn::new(state.db_opts.clone())
+                    .await
+                    .map_err(|err| error!("Error getting DB connection: {err}"))
+                else {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                        .into_response();
+                };
+
+                debug!(
+                    "Creating branch {:?} of database {parent:?} (copy data: {})",
+                    data.name, data.copy_data
+                );
+                let result = create_branch(&mut conn, &parent, &data.name, data.copy_data).await;
+                if let Err(err) = conn.disconnect().await {
+                    debug!("Error disconnecting branch connection: {err}");
+                }
+                match result {
+                    Ok(()) => {}
+                    Err(BranchError::NotFound) => {
+                        return (StatusCode::NOT_FOUND, "Database not found").into_response();
+                    }
+                    Err(BranchError::AlreadyExists) => {
+                        return (
+                            StatusCode::CONFLICT,
+                            "A database with that name already exists",
+                        )
+                            .into_response();
+                    }
+                    Err(BranchError::Mysql(err)) => {
+                        error!(
+                            "Error creating branch {:?} of database {parent:?}: {err}",
+                            data.name
+                        );
+                        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                            .into_response();
+                    }
+                }
+
+                let branch = Branch {
+                    parent,
+                    created_at: SystemTime::now()
+                        .duration_since(UNIX_EPOCH)
+                        .map(|d| d.as_secs())
+                        .unwrap_or_default(),
+                    created_by,
+                };
+                let mut config = state.config.edit();
+                config
+                    .databases
+                    .entry(data.name.clone())
+                    .or_default()
+                    .branch = Some(branch.clone());
+
+                if config
+                    .commit()
+                    .map_err(|err| error!("Error saving config: {err:?}"))
+                    .is_err()
+                {
+                    return (
+                        StatusCode::INTERNAL_SERVER_ERROR,
+                        "Failed to commit changes!",
+                    )
+                        .into_response();
+                }
+
+                (
+                    StatusCode::CREATED,
+                    Json(json!({
+                        "name": data.name,
+                        "branch": branch,
+                    })),
+                )
+                    .into_response()
+            },
+        ),
+    )
+}
+
+#[derive(Deserialize)]
+struct CreateBranchRequest {
+    name: String,
+    /// Copy the rows of the parent's tables at a single point in time, otherwise the branch only has it's schema.
+    #[serde(default)]
+    copy_data: bool,
+}
+
+enum BranchError {
+    NotFound,
+    AlreadyExists,
+    Mysql(mysql_async::Error),
+}
+
+impl From<mysql_async::Error> for BranchError {
+    fn from(err: mysql_async::Error) -> Self {
+        Self::Mysql(err)
+    }
+}
+
+pub(super) fn is_valid_name(name: &str) -> bool {
+    !name.is_empty()
+        && name
+            .chars()
+            .all(|c| c.is_alphanumeric() || c == '_' || c == '-')
+}
+
+/// Quote a table or column name from `information_schema`.
+pub(super) fn quote(name: &str) -> String {
+    format!("`{}`", name.replace('`', "``"))
+}
+
+/// Create `name` with the same tables, views and triggers as `parent`.
+///
+/// Routines and events are not copied. If copying fails the partially created branch is dropped.
+async fn create_branch(
+    conn: &mut Conn,
+    parent: &str,
+    name: &str,
+    copy_data: bool,
+) -> Result<(), BranchError> {
+    let charset: Option<(String, String)> = "SELECT DEFAULT_CHARACTER_SET_NAME, DEFAULT_COLLATION_NAME FROM information_schema.SCHEMATA WHERE SCHEMA_NAME = ?"
+        .with((parent,))
+        .first(&mut *conn)
+        .await?;
+    let Some((charset, collation)) = charset else {
+        return Err(BranchError::NotFound);
+    };
+
+    match format!("CREATE DATABASE `{name}` CHARACTER SET {charset} COLLATE {collation}")
+        .ignore(&mut *conn)
+        .await
+    {
+        Err(mysql_async::Error::Server(err)) if err.code == 1007 => {
+            return Err(BranchError::AlreadyExists)
+        }
+        result => result?,
+    }
+
+    // Tables can be created in any order as foreign keys aren't checked
+    "SET SESSION foreign_key_checks = 0"
+        .ignore(&mut *conn)
+        .await?;
+    let mut result = copy_tables(conn, parent, name, copy_data).await;
+    if let Err(err) = "SET SESSION foreign_key_checks = 1"
+        .ignore(&mut *conn)
+        .await
+    {
+        warn!("Error re-enabling foreign key checks: {err}");
+    }
+    if result.is_ok() {
+        result = copy_views_and_triggers(conn, parent, name).await;
+    }
+
+    if let Err(err) = result {
+        if let Err(err) = format!("DROP DATABASE `{name}`").ignore(&mut *conn).await {
+            warn!("Error dropping partially created branch {name:?}: {err}");
+        }
+        return Err(err.into());
+    }
+    Ok(())
+}
+
+async fn copy_tables(
+    conn: &mut Conn,
+    parent: &str,
+    name: &str,
+    copy_data: bool,
+) -> Result<(), mysql_async::Error> {
+    let tables = "SELECT TABLE_NAME FROM information_schema.TABLES WHERE TABLE_SCHEMA = ? AND TABLE_TYPE = 'BASE TABLE' ORDER BY TABLE_NAME"
+        .with((parent,))
+        .map(&mut *conn, |table: String| table)
+        .await?;
+
+    let mut inserts = Vec::new();
+    for table in tables {
+        let Some((_, schema)): Option<(String, String)> =
+            format!("SHOW CREATE TABLE `{parent}`.{}", quote(&table))
+                .first(&mut *conn)
+                .await?
+        else {
+            continue;
+        };
+
+        // The statement isn't qualified with the database, references to tables in the same database are resolved against the new one
+        let schema = schema.replacen("CREATE TABLE ", &format!("CREATE TABLE `{name}`."), 1);
+        let schema = match copy_data {
+            true => schema,
+            false => strip_auto_increment(&schema),
+        };
+        schema.ignore(&mut *conn).await?;
+
+        if copy_data {
+            // Generated columns can't be inserted into
+            let columns = "SELECT COLUMN_NAME FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = ? AND TABLE_NAME = ? AND GENERATION_EXPRESSION = '' ORDER BY ORDINAL_POSITION"
+                .with((parent, &table))
+                .map(&mut *conn, |column: String| quote(&column))
+                .await?
+                .join(", ");
+            inserts.push(format!(
+                "INSERT INTO `{name}`.{table} ({columns}) SELECT {columns} FROM `{parent}`.{table}",
+                table = quote(&table)
+            ));
+        }
+    }
+    if inserts.is_empty() {
+        return Ok(());
+    }
+
+    // The rows are copied in one transaction, after the tables are created as DDL would commit it, so the branch's tables are consistent with each other (Eg. no dangling foreign keys).
+    // `INSERT ... SELECT` locks the rows it reads until the transaction ends, so writes to the parent's tables which have been copied wait for the copy to finish.
+    "START TRANSACTION WITH CONSISTENT SNAPSHOT"
+        .ignore(&mut *conn)
+        .await?;
+    let mut result = Ok(());
+    for insert in inserts {
+        result = insert.ignore(&mut *conn).await;
+        if result.is_err() {
+            break;
+        }
+    }
+    let end = match result {
+        Ok(()) => "COMMIT",
+        Err(_) => "ROLLBACK",
+    };
+    match end.ignore(&mut *conn).await {
+        Err(err) if result.is_ok() => Err(err),
+        _ => result,
+    }
+}
+
+/// Create the views and triggers of `parent` in `name`, which must have the same tables.
+async fn copy_views_and_triggers(
+    conn: &mut Conn,
+    parent: &str,
+    name: &str,
+) -> Result<(), mysql_async::Error> {
+    let (Some(from), Some(to)) = (
+        schema::load(conn, name).await?,
+        schema::load(conn, parent).await?,
+    ) else {
+        return Ok(());
+    };
+    let statements = schema::diff(&from, &to).statements;
+    if statements.is_empty() {
+        return Ok(());
+    }
+
+    // The statements aren't qualified with the database
+    format!("USE `{name}`").ignore(&mut *conn).await?;
+    for statement in statements {
+        statement.ignore(&mut *conn).await?;
+    }
+    Ok(())
+}
+
+/// Remove the `AUTO_INCREMENT` counter from the table options of a `CREATE TABLE` statement so an empty branch starts counting from 1.
+pub(super) fn strip_auto_increment(schema: &str) -> String {
+    // The table options follow the closing parenthesis of the columns (Eg. `) ENGINE=InnoDB AUTO_INCREMENT=5 ...`)
+    let Some(options) = schema.find("\n) ") else {
+        return schema.to_string();
+    };
+    let Some(start) = schema[options..]
+        .find(" AUTO_INCREMENT=")
+        .map(|i| options + i)
+    else {
+        return schema.to_string();
+    };
+    let value = start + " AUTO_INCREMENT=".len();
+    let end = schema[value..]
+        .find(|c: char| !c.is_ascii_digit())
+        .map_or(schema.len(), |i| value + i);
+
+    format!("{}{}", &schema[..start], &schema[end..])
+}
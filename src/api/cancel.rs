@@ -0,0 +1,206 @@
+use std::{
+    fmt,
+    future::Future,
+    sync::{
+        atomic::{AtomicBool, Ordering},
+        Arc,
+    },
+    time::Duration,
+};
+
+use axum::http::HeaderMap;
+use mysql_async::{prelude::Queryable, Pool};
+use tokio::sync::Mutex;
+use tracing::{debug, warn};
+
+/// The header a client can set to override the default query timeout for a request.
+/// This is the timeout header from the Connect protocol so `psdb` clients can set it with their call options.
+const TIMEOUT_HEADER: &str = "connect-timeout-ms";
+
+/// Get the query timeout for a request, which is the user's default or the `Connect-Timeout-Ms` header if it's shorter.
+///
+/// A client can only shorten the timeout it's been given, so a header of `0` or one which isn't a number is an error.
+pub fn timeout(headers: &HeaderMap, default: Option<Duration>) -> Result<Option<Duration>, String> {
+    let Some(value) = headers.get(TIMEOUT_HEADER) else {
+        return Ok(default);
+    };
+
+    match value.to_str().ok().and_then(|v| v.parse::<u64>().ok()) {
+        Some(ms) if ms > 0 => {
+            let timeout = Duration::from_millis(ms);
+            Ok(Some(default.map_or(timeout, |default| default.min(timeout))))
+        }
+        _ => Err(format!(
+            "invalid {TIMEOUT_HEADER} header {value:?}, it must be a positive number of milliseconds"
+        )),
+    }
+}
+
+/// Why a query was stopped before it completed.
+#[derive(Debug, Clone, Copy)]
+pub enum Interrupted {
+    TimedOut(Duration),
+    /// The client went away before the query completed.
+    Canceled,
+}
+
+impl fmt::Display for Interrupted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::TimedOut(timeout) => write!(
+                f,
+                "query exceeded the timeout of {}ms and was killed",
+                timeout.as_millis()
+            ),
+            Self::Canceled => write!(f, "query was killed as the request was canceled"),
+        }
+    }
+}
+
+impl std::error::Error for Interrupted {}
+
+/// Kills the queries of a request using `KILL QUERY` when they timeout or the client goes away.
+#[derive(Clone)]
+pub struct QueryKiller(Arc<Inner>);
+
+struct Inner {
+    /// A connection with permission to kill any query.
+    db: Pool,
+    /// The ID of the connection running a query for this request.
+    /// This is locked while a query is being killed so the connection can't be reused by another request until it's done.
+    running: Mutex<Option<u32>>,
+    canceled: AtomicBool,
+}
+
+impl QueryKiller {
+    pub fn new(db: Pool) -> Self {
+        Self(Arc::new(Inner {
+            db,
+            running: Mutex::new(None),
+            canceled: AtomicBool::new(false),
+        }))
+    }
+
+    /// Run a query on the connection with the ID `conn_id`, killing it if it's still running after `timeout`.
+    pub async fn run<T, E: From<Interrupted>>(
+        &self,
+        conn_id: u32,
+        timeout: Option<Duration>,
+        query: impl Future<Output = Result<T, E>>,
+    ) -> Result<T, E> {
+        if self.0.canceled.load(Ordering::Acquire) {
+            return Err(Interrupted::Canceled.into());
+        }
+
+        *self.0.running.lock().await = Some(conn_id);
+        tokio::pin!(query);
+        let result = match timeout {
+            Some(timeout) => match tokio::time::timeout(timeout, &mut query).await {
+                Ok(result) => result,
+                Err(_) => {
+                    debug!("Killing query on connection {conn_id} as it exceeded it's timeout of {timeout:?}");
+                    kill(&self.0.db, conn_id).await;
+                    // Wait for MySQL to stop the query so the connection can be used again.
+                    // Some queries (Eg. `SLEEP`) return successfully when they are killed so the result can't be trusted.
+                    let _ = query.await;
+                    Err(Interrupted::TimedOut(timeout).into())
+                }
+            },
+            None => query.await,
+        };
+        *self.0.running.lock().await = None;
+
+        if result.is_err() && self.0.canceled.load(Ordering::Acquire) {
+            return Err(Interrupted::Canceled.into());
+        }
+        result
+    }
+
+    /// Cancel the request, killing the query which is currently running and preventing any more from starting.
+    pub fn cancel(&self) {
+        self.0.canceled.store(true, Ordering::Release);
+
+        let inner = self.0.clone();
+        tokio::spawn(async move {
+            let running = inner.running.lock().await;
+            if let Some(conn_id) = *running {
+                debug!("Killing query on connection {conn_id} as the request was canceled");
+                kill(&inner.db, conn_id).await;
+            }
+        });
+    }
+
+    /// Run a request on it's own task, so the connection or session it's using is left in a consistent state even if the client goes away.
+    /// When that happens the query which is running is killed instead of running until MySQL finishes it.
+    pub async fn detach<F>(self, request: F) -> F::Output
+    where
+        F: Future + Send + 'static,
+        F::Output: Send + 'static,
+    {
+        struct CancelOnDrop(Option<QueryKiller>);
+
+        impl Drop for CancelOnDrop {
+            fn drop(&mut self) {
+                if let Some(killer) = self.0.take() {
+                    killer.cancel();
+                }
+            }
+        }
+
+        let mut guard = CancelOnDrop(Some(self));
+        let result = tokio::spawn(request).await;
+        guard.0 = None;
+
+        match result {
+            Ok(result) => result,
+            Err(err) => std::panic::resume_unwind(err.into_panic()),
+        }
+    }
+}
+
+async fn kill(db: &Pool, conn_id: u32) {
+    let result = async {
+        db.get_conn()
+            .await?
+            .query_drop(format!("KILL QUERY {conn_id}"))
+            .await
+    };
+
+    if let Err(err) = result.await {
+        warn!("Error killing query on connection {conn_id}: {err}");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn headers(value: &str) -> HeaderMap {
+        let mut headers = HeaderMap::new();
+        headers.insert(TIMEOUT_HEADER, value.parse().unwrap());
+        headers
+    }
+
+    #[test]
+    fn header_can_only_shorten_the_timeout() {
+        let default = Some(Duration::from_secs(30));
+        assert_eq!(timeout(&HeaderMap::new(), default), Ok(default));
+        assert_eq!(timeout(&HeaderMap::new(), None), Ok(None));
+        assert_eq!(
+            timeout(&headers("500"), default),
+            Ok(Some(Duration::from_millis(500)))
+        );
+        assert_eq!(timeout(&headers("60000"), default), Ok(default));
+        assert_eq!(
+            timeout(&headers("60000"), None),
+            Ok(Some(Duration::from_secs(60)))
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_headers() {
+        for value in ["0", "-1", "1.5", "soon", ""] {
+            assert!(timeout(&headers(value), None).is_err(), "{value:?}");
+        }
+    }
+}
@@ -0,0 +1,465 @@
+use std::{
+    sync::Arc,
+    time::{SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::{get, post},
+    Json, Router,
+};
+use mysql_async::{prelude::*, Conn};
+use serde::Deserialize;
+use tower_cookies::{Cookies, Key};
+use tracing::{debug, error, warn};
+
+use super::{
+    branches::is_valid_name,
+    migrations::{parse_alter, MigrationError, Throttle},
+    schema, AppState, USERNAME_HEADER,
+};
+use crate::config::{Approval, ConfigManager, DeployRequest, DeployState, StateChange};
+
+/// How many deployed and closed requests are kept, so the config doesn't grow forever.
+const FINISHED_DEPLOY_REQUESTS: usize = 100;
+
+pub fn mount() -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/deploy-requests",
+            get(|State(state): State<Arc<AppState>>| async move {
+                Json(state.config.get().deploy_requests.clone())
+            }),
+        )
+        .route(
+            "/deploy-requests",
+            post(|State(state): State<Arc<AppState>>, cookies: Cookies, Json(data): Json<CreateDeployRequest>| async move {
+                let created_by = current_admin(&state, &cookies);
+                if !is_valid_name(&data.branch) {
+                    return (StatusCode::BAD_REQUEST, "Invalid database name").into_response();
+                }
+
+                let parent = {
+                    let config = state.config.get();
+                    if config.deploy_requests.iter().any(|r| r.branch == data.branch && r.is_active()) {
+                        return (StatusCode::CONFLICT, "The branch already has an open deploy request").into_response();
+                    }
+                    config.databases.get(&data.branch).and_then(|db| db.branch.as_ref()).map(|branch| branch.parent.clone())
+                };
+                let Some(parent) = parent else {
+                    return (StatusCode::BAD_REQUEST, "The database isn't a branch").into_response();
+                };
+
+                let Ok(mut conn) = state
+                    .db
+                    .get_conn()
+                    .await
+                    .map_err(|err| error!("Error getting DB connection: {err}"))
+                else {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+                };
+
+                let statements = match schema_changes(&mut conn, &parent, &data.branch).await {
+                    Ok(Some(statements)) => statements,
+                    Ok(None) => return (StatusCode::NOT_FOUND, "Database not found").into_response(),
+                    Err(err) => {
+                        error!("Error loading schemas of {parent:?} and {:?}: {err}", data.branch);
+                        return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+                    }
+                };
+                if statements.is_empty() {
+                    return (StatusCode::BAD_REQUEST, "The branch has no schema changes").into_response();
+                }
+
+                let mut config = state.config.edit();
+                // Another request could have been opened while the schemas were loading
+                if config.deploy_requests.iter().any(|r| r.branch == data.branch && r.is_active()) {
+                    return (StatusCode::CONFLICT, "The branch already has an open deploy request").into_response();
+                }
+
+                let created_at = now();
+                let mut request = DeployRequest {
+                    id: config.deploy_requests.iter().map(|r| r.id).max().unwrap_or_default() + 1,
+                    branch: data.branch,
+                    into: parent,
+                    statements,
+                    created_by: created_by.clone(),
+                    created_at,
+                    approvals: Vec::new(),
+                    state: DeployState::Open,
+                    history: Vec::new(),
+                };
+                request.set_state(DeployState::Open, &created_by, None);
+                if config.deploy_request_approvals == 0 {
+                    request.set_state(DeployState::Approved, &created_by, None);
+                }
+                config.deploy_requests.push(request.clone());
+
+                if config.commit().map_err(|err| error!("Error saving config: {err:?}")).is_err() {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to commit changes!").into_response();
+                }
+
+                debug!("Opened deploy request {} of {:?} into {:?}", request.id, request.branch, request.into);
+                (StatusCode::CREATED, Json(request)).into_response()
+            }),
+        )
+        .route(
+            "/deploy-requests/:id",
+            get(|State(state): State<Arc<AppState>>, Path(id): Path<u64>| async move {
+                match state.config.get().deploy_requests.iter().find(|r| r.id == id) {
+                    Some(request) => Json(request.clone()).into_response(),
+                    None => (StatusCode::NOT_FOUND, "Deploy request not found").into_response(),
+                }
+            }),
+        )
+        .route(
+            "/deploy-requests/:id/approve",
+            post(|State(state): State<Arc<AppState>>, cookies: Cookies, Path(id): Path<u64>| async move {
+                let admin = current_admin(&state, &cookies);
+
+                let mut config = state.config.edit();
+                if !config.admins.contains_key(&admin) {
+                    return (StatusCode::FORBIDDEN, "Only admins can approve deploy requests").into_response();
+                }
+                let required = config.deploy_request_approvals;
+                let Some(request) = config.deploy_requests.iter_mut().find(|r| r.id == id) else {
+                    return (StatusCode::NOT_FOUND, "Deploy request not found").into_response();
+                };
+                if request.created_by == admin {
+                    return (StatusCode::FORBIDDEN, "You can't approve your own deploy request").into_response();
+                }
+                if !matches!(request.state, DeployState::Open | DeployState::Approved) {
+                    return (StatusCode::CONFLICT, "The deploy request is no longer open").into_response();
+                }
+                if request.approvals.iter().any(|approval| approval.by == admin) {
+                    return (StatusCode::CONFLICT, "You have already approved this deploy request").into_response();
+                }
+
+                request.approvals.push(Approval { by: admin.clone(), at: now() });
+                if request.state == DeployState::Open && request.approvals.len() >= required {
+                    request.set_state(DeployState::Approved, &admin, None);
+                }
+                let request = request.clone();
+
+                if config.commit().map_err(|err| error!("Error saving config: {err:?}")).is_err() {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to commit changes!").into_response();
+                }
+
+                Json(request).into_response()
+            }),
+        )
+        .route(
+            "/deploy-requests/:id/deploy",
+            post(|State(state): State<Arc<AppState>>, cookies: Cookies, Path(id): Path<u64>| async move {
+                let admin = current_admin(&state, &cookies);
+
+                // Mark the request as deploying first so it can't be deployed twice at once
+                let request = {
+                    let mut config = state.config.edit();
+                    let admins = config.admins.clone();
+                    let required = config.deploy_request_approvals;
+                    let Some(request) = config.deploy_requests.iter_mut().find(|r| r.id == id) else {
+                        return (StatusCode::NOT_FOUND, "Deploy request not found").into_response();
+                    };
+                    if request.state != DeployState::Approved {
+                        return (StatusCode::CONFLICT, "Only approved deploy requests can be deployed").into_response();
+                    }
+                    // An approval doesn't count once the admin who gave it has been removed
+                    if request.approvals.iter().filter(|approval| admins.contains_key(&approval.by)).count() < required {
+                        return (StatusCode::CONFLICT, "The deploy request doesn't have enough approvals").into_response();
+                    }
+
+                    request.set_state(DeployState::Deploying, &admin, None);
+                    let request = request.clone();
+                    if config.commit().map_err(|err| error!("Error saving config: {err:?}")).is_err() {
+                        return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to commit changes!").into_response();
+                    }
+                    request
+                };
+
+                // The deploy runs in the background so it isn't interrupted if the client disconnects
+                debug!("Deploying request {id} of {:?} into {:?}", request.branch, request.into);
+                let response = (StatusCode::ACCEPTED, Json(&request)).into_response();
+                tokio::spawn(async move {
+                    let (status, message) = match deploy(&state, &request).await {
+                        Ok(()) => (DeployState::Deployed, None),
+                        Err(message) => {
+                            warn!("Deploy request {id} failed: {message}");
+                            (DeployState::Failed, Some(message))
+                        }
+                    };
+
+                    let mut config = state.config.edit();
+                    let Some(request) = config.deploy_requests.iter_mut().find(|r| r.id == id) else {
+                        return;
+                    };
+                    request.set_state(status, &admin, message);
+                    prune_finished(&mut config.deploy_requests);
+                    if let Err(err) = config.commit() {
+                        error!("Error saving config: {err:?}");
+                    }
+                });
+                response
+            }),
+        )
+        .route(
+            "/deploy-requests/:id/close",
+            post(|State(state): State<Arc<AppState>>, cookies: Cookies, Path(id): Path<u64>| async move {
+                let admin = current_admin(&state, &cookies);
+
+                let mut config = state.config.edit();
+                let Some(request) = config.deploy_requests.iter_mut().find(|r| r.id == id) else {
+                    return (StatusCode::NOT_FOUND, "Deploy request not found").into_response();
+                };
+                if !matches!(request.state, DeployState::Open | DeployState::Approved | DeployState::Failed) {
+                    return (StatusCode::CONFLICT, "The deploy request can't be closed").into_response();
+                }
+
+                request.set_state(DeployState::Closed, &admin, None);
+                let request = request.clone();
+                prune_finished(&mut config.deploy_requests);
+                if config.commit().map_err(|err| error!("Error saving config: {err:?}")).is_err() {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to commit changes!").into_response();
+                }
+
+                Json(request).into_response()
+            }),
+        )
+}
+
+#[derive(Deserialize)]
+struct CreateDeployRequest {
+    branch: String,
+}
+
+impl DeployRequest {
+    /// If the request can still be deployed. A branch can only have one active request at a time.
+    fn is_active(&self) -> bool {
+        matches!(
+            self.state,
+            DeployState::Open | DeployState::Approved | DeployState::Deploying
+        )
+    }
+
+    /// If the request is in a state it can't leave.
+    fn is_finished(&self) -> bool {
+        matches!(self.state, DeployState::Deployed | DeployState::Closed)
+    }
+
+    fn set_state(&mut self, state: DeployState, by: &str, message: Option<String>) {
+        self.state = state;
+        self.history.push(StateChange {
+            state,
+            by: by.to_string(),
+            at: now(),
+            message,
+        });
+    }
+}
+
+/// Forget the oldest deployed and closed requests once there are more than [`FINISHED_DEPLOY_REQUESTS`].
+fn prune_finished(requests: &mut Vec<DeployRequest>) {
+    let finished = requests.iter().filter(|r| r.is_finished()).count();
+    let mut excess = finished.saturating_sub(FINISHED_DEPLOY_REQUESTS);
+    // Requests are in the order they were opened
+    requests.retain(|r| {
+        if excess > 0 && r.is_finished() {
+            excess -= 1;
+            return false;
+        }
+        true
+    });
+}
+
+/// Mark requests which were deploying when Cityscale stopped as failed, as we can't know which statements were run.
+pub fn fail_interrupted_deploys(config: &ConfigManager) {
+    if !config
+        .get()
+        .deploy_requests
+        .iter()
+        .any(|r| r.state == DeployState::Deploying)
+    {
+        return;
+    }
+
+    let mut config = config.edit();
+    for request in config
+        .deploy_requests
+        .iter_mut()
+        .filter(|r| r.state == DeployState::Deploying)
+    {
+        warn!("Deploy request {} was interrupted", request.id);
+        request.set_state(
+            DeployState::Failed,
+            "cityscale",
+            Some("Cityscale stopped while the request was deploying".into()),
+        );
+    }
+    if let Err(err) = config.commit() {
+        error!("Error saving config: {err:?}");
+    }
+}
+
+fn current_admin(state: &AppState, cookies: &Cookies) -> String {
+    cookies
+        .private(&Key::from(state.config.get().secret.as_bytes()))
+        .get(USERNAME_HEADER)
+        .expect("checked in auth middleware")
+        .value()
+        .to_string()
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+/// The statements which apply the schema of `branch` to `into`, or `None` if either doesn't exist.
+async fn schema_changes(
+    conn: &mut Conn,
+    into: &str,
+    branch: &str,
+) -> Result<Option<Vec<String>>, mysql_async::Error> {
+    let (Some(from), Some(to)) = (
+        schema::load(conn, into).await?,
+        schema::load(conn, branch).await?,
+    ) else {
+        return Ok(None);
+    };
+    Ok(Some(schema::diff(&from, &to).statements))
+}
+
+/// Run the approved statements of a request against it's parent, returning why it failed.
+async fn deploy(state: &AppState, request: &DeployRequest) -> Result<(), String> {
+    // The `USE` mustn't leak into the pool, so the deploy gets it's own connection
+    let mut conn = Conn::new(state.db_opts.clone()).await.map_err(|err| {
+        error!("Error getting DB connection: {err}");
+        "Internal Server Error".to_string()
+    })?;
+    let result = apply(state, &mut conn, request).await;
+    if let Err(err) = conn.disconnect().await {
+        debug!("Error disconnecting deploy connection: {err}");
+    }
+    result
+}
+
+async fn apply(state: &AppState, conn: &mut Conn, request: &DeployRequest) -> Result<(), String> {
+    // What was approved must be what's run
+    match schema_changes(conn, &request.into, &request.branch).await {
+        Ok(Some(statements)) if statements == request.statements => {}
+        Ok(Some(_)) => {
+            return Err(format!(
+                "The schema of {:?} or {:?} changed after the request was opened",
+                request.branch, request.into
+            ))
+        }
+        Ok(None) => return Err("Database not found".into()),
+        Err(err) => return Err(format!("Error loading schemas: {err}")),
+    }
+
+    // The statements aren't qualified with the database
+    format!("USE `{}`", request.into)
+        .ignore(&mut *conn)
+        .await
+        .map_err(|err| format!("Error selecting {:?}: {err}", request.into))?;
+    let throttle = Throttle::from_config(&state.config.get());
+    for (i, statement) in request.statements.iter().enumerate() {
+        let result = run_statement(state, conn, &request.into, statement, throttle).await;
+        state.connections.invalidate_cache(&request.into, statement);
+        result.map_err(|err| {
+            format!(
+                "Error running statement {}, the earlier statements were applied: {err}",
+                i + 1
+            )
+        })?;
+    }
+    Ok(())
+}
+
+/// Run a statement of a deploy, using an online schema change for `ALTER TABLE` so the table isn't locked.
+async fn run_statement(
+    state: &AppState,
+    conn: &mut Conn,
+    database: &str,
+    statement: &str,
+    throttle: Throttle,
+) -> Result<(), String> {
+    if parse_alter(statement).is_some() {
+        match state
+            .migrations
+            .plan(&state.db, database, statement, throttle)
+            .await
+        {
+            Ok(migration) => match migration.run(&state.db).await {
+                // The original table is left as it was, so the statement can still be run directly
+                Err(MigrationError::Unsupported(reason)) => {
+                    warn!("Running {statement:?} on {database:?} directly as it can't be run online: {reason}");
+                }
+                result => return result.map_err(|err| err.to_string()),
+            },
+            Err(MigrationError::Unsupported(reason)) => {
+                warn!("Running {statement:?} on {database:?} directly as it can't be run online: {reason}");
+            }
+            Err(err) => return Err(err.to_string()),
+        }
+    }
+
+    statement
+        .ignore(&mut *conn)
+        .await
+        .map_err(|err| err.to_string())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn request(id: u64, state: DeployState) -> DeployRequest {
+        DeployRequest {
+            id,
+            branch: "branch".into(),
+            into: "parent".into(),
+            statements: Vec::new(),
+            created_by: "admin".into(),
+            created_at: 0,
+            approvals: Vec::new(),
+            state,
+            history: Vec::new(),
+        }
+    }
+
+    #[test]
+    fn prunes_the_oldest_finished_requests() {
+        let states = [
+            DeployState::Deployed,
+            DeployState::Closed,
+            DeployState::Failed,
+            DeployState::Open,
+        ];
+        let mut requests = (0..2 * FINISHED_DEPLOY_REQUESTS as u64 + 8)
+            .map(|id| request(id, states[id as usize % states.len()]))
+            .collect::<Vec<_>>();
+        let active = requests.iter().filter(|r| !r.is_finished()).count();
+
+        prune_finished(&mut requests);
+        assert_eq!(
+            requests.iter().filter(|r| r.is_finished()).count(),
+            FINISHED_DEPLOY_REQUESTS
+        );
+        assert_eq!(requests.iter().filter(|r| !r.is_finished()).count(), active);
+        // The oldest are gone and the newest request is kept so IDs aren't reused
+        assert!(!requests.iter().any(|r| [0, 1, 4, 5].contains(&r.id)));
+        assert_eq!(
+            requests.last().map(|r| r.id),
+            Some(2 * FINISHED_DEPLOY_REQUESTS as u64 + 7)
+        );
+
+        let before = requests.len();
+        prune_finished(&mut requests);
+        assert_eq!(requests.len(), before);
+    }
+}
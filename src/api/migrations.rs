@@ -0,0 +1,956 @@
+//! Online schema changes, which run an `ALTER TABLE` without locking the table for the length of the change.
+//!
+//! This works like `pt-online-schema-change`. The alteration is applied to an empty copy of the table,
+//! triggers keep the copy up to date as the original's rows are copied across in chunks and then the
+//! tables are swapped with an atomic `RENAME TABLE`.
+//!
+//! If Cityscale stops part way through the triggers and copy are left behind. They are named after the table (Eg. `_users_new` and `_users_ins`),
+//! and must be dropped manually before the table can be migrated online again.
+
+use std::{
+    collections::{BTreeMap, HashSet},
+    fmt, mem, panic,
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc, Mutex, MutexGuard, PoisonError,
+    },
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use axum::{
+    extract::{Path, State},
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    routing::{get, post, put},
+    Json, Router,
+};
+use mysql_async::{prelude::*, Conn, Params, Pool, Row, Value};
+use serde::{Deserialize, Serialize};
+use serde_json::json;
+use tokio::runtime::Handle;
+use tracing::{debug, error, info, warn};
+
+use super::{
+    branches::{is_valid_name, quote},
+    AppState,
+};
+use crate::config::Config;
+
+/// How many times the cut-over is attempted when it can't get a lock on the table.
+const CUT_OVER_ATTEMPTS: usize = 10;
+/// How long (in seconds) each cut-over attempt waits for a lock on the table. Queries on the table are blocked while it waits.
+const CUT_OVER_LOCK_TIMEOUT: u64 = 2;
+/// How many times copying a chunk is attempted when it deadlocks with a write to the table.
+const CHUNK_ATTEMPTS: usize = 3;
+
+pub fn mount() -> Router<Arc<AppState>> {
+    Router::new()
+        .route(
+            "/database/:db/migrations",
+            post(
+                |State(state): State<Arc<AppState>>,
+                 Path(db_name): Path<String>,
+                 Json(data): Json<StartMigrationRequest>| async move {
+                    // TODO: This is a crude way to prevent SQL injection, can we do something better here?
+                    if !is_valid_name(&db_name) {
+                        return (StatusCode::BAD_REQUEST, "Invalid database name").into_response();
+                    }
+
+                    let mut throttle = Throttle::from_config(&state.config.get());
+                    throttle.update(data.throttle);
+                    if throttle.chunk_size == 0 {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            "'chunk_size' must be greater than 0",
+                        )
+                            .into_response();
+                    }
+
+                    let migration = match state
+                        .migrations
+                        .plan(&state.db, &db_name, &data.statement, throttle)
+                        .await
+                    {
+                        Ok(migration) => migration,
+                        Err(err) => return err.into_response(),
+                    };
+
+                    let status = migration.status();
+                    tokio::spawn(async move {
+                        if migration.run(&state.db).await.is_ok() {
+                            state
+                                .connections
+                                .invalidate_cache(&migration.database, &migration.statement);
+                        }
+                    });
+                    (StatusCode::ACCEPTED, Json(status)).into_response()
+                },
+            ),
+        )
+        .route(
+            "/migrations",
+            get(|State(state): State<Arc<AppState>>| async move {
+                Json(
+                    state
+                        .migrations
+                        .all()
+                        .iter()
+                        .map(|migration| migration.status())
+                        .collect::<Vec<_>>(),
+                )
+            }),
+        )
+        .route(
+            "/migrations/:id",
+            get(
+                |State(state): State<Arc<AppState>>, Path(id): Path<u64>| async move {
+                    match state.migrations.get(id) {
+                        Some(migration) => Json(migration.status()).into_response(),
+                        None => (StatusCode::NOT_FOUND, "Migration not found").into_response(),
+                    }
+                },
+            ),
+        )
+        .route(
+            "/migrations/:id",
+            put(
+                |State(state): State<Arc<AppState>>,
+                 Path(id): Path<u64>,
+                 Json(data): Json<UpdateThrottle>| async move {
+                    let Some(migration) = state.migrations.get(id) else {
+                        return (StatusCode::NOT_FOUND, "Migration not found").into_response();
+                    };
+                    if data.chunk_size == Some(0) {
+                        return (
+                            StatusCode::BAD_REQUEST,
+                            "'chunk_size' must be greater than 0",
+                        )
+                            .into_response();
+                    }
+
+                    migration.lock().throttle.update(data);
+                    Json(migration.status()).into_response()
+                },
+            ),
+        )
+        .route(
+            "/migrations/:id/cancel",
+            post(
+                |State(state): State<Arc<AppState>>, Path(id): Path<u64>| async move {
+                    let Some(migration) = state.migrations.get(id) else {
+                        return (StatusCode::NOT_FOUND, "Migration not found").into_response();
+                    };
+                    if !migration.is_running() {
+                        return (StatusCode::CONFLICT, "The migration has already finished")
+                            .into_response();
+                    }
+
+                    // The migration stops before it's next chunk
+                    migration.cancelled.store(true, Ordering::Relaxed);
+                    (StatusCode::ACCEPTED, Json(migration.status())).into_response()
+                },
+            ),
+        )
+}
+
+#[derive(Deserialize)]
+struct StartMigrationRequest {
+    /// An `ALTER TABLE` statement, which isn't qualified with the database.
+    statement: String,
+    #[serde(flatten)]
+    throttle: UpdateThrottle,
+}
+
+#[derive(Deserialize)]
+struct UpdateThrottle {
+    chunk_size: Option<usize>,
+    chunk_delay: Option<u64>,
+}
+
+/// Limits on how quickly a migration copies rows, to limit the load it puts on MySQL.
+#[derive(Debug, Clone, Copy, Serialize)]
+pub struct Throttle {
+    /// The number of rows copied at a time.
+    pub chunk_size: usize,
+    /// How long (in milliseconds) to wait between chunks.
+    pub chunk_delay: u64,
+}
+
+impl Throttle {
+    pub fn from_config(config: &Config) -> Self {
+        Self {
+            chunk_size: config.migration_chunk_size,
+            chunk_delay: config.migration_chunk_delay,
+        }
+    }
+
+    fn update(&mut self, update: UpdateThrottle) {
+        self.chunk_size = update.chunk_size.unwrap_or(self.chunk_size);
+        self.chunk_delay = update.chunk_delay.unwrap_or(self.chunk_delay);
+    }
+}
+
+#[derive(Debug)]
+pub enum MigrationError {
+    /// The statement or table can't be migrated online, it must be run directly.
+    Unsupported(String),
+    NotFound,
+    /// The table is already being migrated.
+    InProgress,
+    Cancelled,
+    Mysql(mysql_async::Error),
+}
+
+impl From<mysql_async::Error> for MigrationError {
+    fn from(err: mysql_async::Error) -> Self {
+        Self::Mysql(err)
+    }
+}
+
+impl fmt::Display for MigrationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Unsupported(reason) => write!(f, "{reason}"),
+            Self::NotFound => write!(f, "Table not found"),
+            Self::InProgress => write!(f, "The table is already being migrated"),
+            Self::Cancelled => write!(f, "The migration was cancelled"),
+            Self::Mysql(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl IntoResponse for MigrationError {
+    fn into_response(self) -> Response {
+        match self {
+            Self::Unsupported(_) => (StatusCode::BAD_REQUEST, self.to_string()).into_response(),
+            Self::NotFound => (StatusCode::NOT_FOUND, self.to_string()).into_response(),
+            Self::InProgress | Self::Cancelled => {
+                (StatusCode::CONFLICT, self.to_string()).into_response()
+            }
+            Self::Mysql(err) => {
+                error!("Error planning migration: {err}");
+                (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+            }
+        }
+    }
+}
+
+/// The online schema changes which have been run since Cityscale started.
+#[derive(Default)]
+pub struct Migrations {
+    next_id: AtomicU64,
+    migrations: Mutex<BTreeMap<u64, Arc<Migration>>>,
+}
+
+impl Migrations {
+    fn lock(&self) -> MutexGuard<'_, BTreeMap<u64, Arc<Migration>>> {
+        self.migrations
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn get(&self, id: u64) -> Option<Arc<Migration>> {
+        self.lock().get(&id).cloned()
+    }
+
+    pub fn all(&self) -> Vec<Arc<Migration>> {
+        self.lock().values().cloned().collect()
+    }
+
+    /// Check `statement` can be run online and register it's migration, which must then be [run](Migration::run).
+    pub async fn plan(
+        &self,
+        pool: &Pool,
+        database: &str,
+        statement: &str,
+        throttle: Throttle,
+    ) -> Result<Arc<Migration>, MigrationError> {
+        let Some((table, alteration)) = parse_alter(statement) else {
+            return Err(MigrationError::Unsupported(
+                "Only 'ALTER TABLE' statements of a table in the database can be run online".into(),
+            ));
+        };
+        check_alteration(alteration)?;
+
+        let mut conn = pool.get_conn().await?;
+        let total_rows: Option<Option<u64>> = "SELECT TABLE_ROWS FROM information_schema.TABLES WHERE TABLE_SCHEMA = ? AND TABLE_NAME = ? AND TABLE_TYPE = 'BASE TABLE'"
+            .with((database, &table))
+            .first(&mut conn)
+            .await?;
+        let Some(total_rows) = total_rows else {
+            return Err(MigrationError::NotFound);
+        };
+
+        let primary_key = primary_key(&mut conn, database, &table).await?;
+        if primary_key.is_empty() {
+            return Err(MigrationError::Unsupported(
+                "The table must have a primary key to be migrated online".into(),
+            ));
+        }
+
+        // `CREATE TABLE ... LIKE` doesn't copy foreign keys, and the ones referencing the table would follow it when it's renamed
+        let foreign_key: Option<String> = "SELECT CONSTRAINT_NAME FROM information_schema.REFERENTIAL_CONSTRAINTS WHERE (CONSTRAINT_SCHEMA = ? AND TABLE_NAME = ?) OR (UNIQUE_CONSTRAINT_SCHEMA = ? AND REFERENCED_TABLE_NAME = ?) LIMIT 1"
+            .with((database, &table, database, &table))
+            .first(&mut conn)
+            .await?;
+        if let Some(foreign_key) = foreign_key {
+            return Err(MigrationError::Unsupported(format!(
+                "Tables with foreign keys can't be migrated online, found {foreign_key:?}"
+            )));
+        }
+
+        // The table's triggers would follow it when it's renamed
+        let trigger: Option<String> = "SELECT TRIGGER_NAME FROM information_schema.TRIGGERS WHERE EVENT_OBJECT_SCHEMA = ? AND EVENT_OBJECT_TABLE = ? LIMIT 1"
+            .with((database, &table))
+            .first(&mut conn)
+            .await?;
+        if let Some(trigger) = trigger {
+            return Err(MigrationError::Unsupported(format!(
+                "Tables with triggers can't be migrated online, found {trigger:?}"
+            )));
+        }
+
+        let names = Names::new(database, &table);
+        let existing: Option<String> = "SELECT TABLE_NAME FROM information_schema.TABLES WHERE TABLE_SCHEMA = ? AND TABLE_NAME IN (?, ?) LIMIT 1"
+            .with((database, &names.shadow_name, &names.old_name))
+            .first(&mut conn)
+            .await?;
+        if let Some(existing) = existing {
+            return Err(MigrationError::Unsupported(format!("The table {existing:?} already exists, it may be left over from an interrupted migration")));
+        }
+
+        let mut migrations = self.lock();
+        if migrations
+            .values()
+            .any(|m| m.database == database && m.table == table && m.is_running())
+        {
+            return Err(MigrationError::InProgress);
+        }
+
+        let id = self.next_id.fetch_add(1, Ordering::Relaxed) + 1;
+        let migration = Arc::new(Migration {
+            id,
+            database: database.to_string(),
+            table,
+            statement: statement.to_string(),
+            alteration: alteration.to_string(),
+            primary_key,
+            names,
+            cancelled: AtomicBool::new(false),
+            status: Mutex::new(Status {
+                state: MigrationState::Pending,
+                throttle,
+                copied_rows: 0,
+                total_rows: total_rows.unwrap_or_default(),
+                started_at: now(),
+                finished_at: None,
+                error: None,
+            }),
+        });
+        migrations.insert(id, migration.clone());
+        Ok(migration)
+    }
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum MigrationState {
+    Pending,
+    Copying,
+    CuttingOver,
+    Completed,
+    Failed,
+    Cancelled,
+}
+
+struct Status {
+    state: MigrationState,
+    throttle: Throttle,
+    copied_rows: u64,
+    /// MySQL's estimate of the number of rows in the table.
+    total_rows: u64,
+    started_at: u64,
+    finished_at: Option<u64>,
+    error: Option<String>,
+}
+
+/// An online schema change of a single table.
+pub struct Migration {
+    pub id: u64,
+    pub database: String,
+    pub table: String,
+    pub statement: String,
+    /// The statement without the `ALTER TABLE ...` prefix.
+    alteration: String,
+    primary_key: Vec<String>,
+    names: Names,
+    cancelled: AtomicBool,
+    status: Mutex<Status>,
+}
+
+impl Migration {
+    fn lock(&self) -> MutexGuard<'_, Status> {
+        self.status.lock().unwrap_or_else(PoisonError::into_inner)
+    }
+
+    pub fn is_running(&self) -> bool {
+        matches!(
+            self.lock().state,
+            MigrationState::Pending | MigrationState::Copying | MigrationState::CuttingOver
+        )
+    }
+
+    pub fn status(&self) -> serde_json::Value {
+        let status = self.lock();
+        json!({
+            "id": self.id,
+            "database": self.database,
+            "table": self.table,
+            "statement": self.statement,
+            "state": status.state,
+            "throttle": status.throttle,
+            "copied_rows": status.copied_rows,
+            "total_rows": status.total_rows,
+            "started_at": status.started_at,
+            "finished_at": status.finished_at,
+            "error": status.error,
+        })
+    }
+
+    /// Run the migration to completion.
+    ///
+    /// It runs in it's own task so the tables aren't left half migrated if the caller stops waiting for it.
+    pub async fn run(self: &Arc<Self>, pool: &Pool) -> Result<(), MigrationError> {
+        let (migration, pool) = (self.clone(), pool.clone());
+        match tokio::spawn(async move { migration.execute(pool).await }).await {
+            Ok(result) => result,
+            Err(err) if err.is_panic() => panic::resume_unwind(err.into_panic()),
+            Err(_) => Err(MigrationError::Cancelled),
+        }
+    }
+
+    async fn execute(self: Arc<Self>, pool: Pool) -> Result<(), MigrationError> {
+        let _interrupted = Interrupted {
+            migration: self.clone(),
+            pool: pool.clone(),
+        };
+        let result = async {
+            let mut conn = pool.get_conn().await?;
+            self.migrate(&mut conn).await
+        }
+        .await;
+        if result.is_err() {
+            self.clean_up(&pool).await;
+        }
+
+        let mut status = self.lock();
+        status.finished_at = Some(now());
+        match &result {
+            Ok(()) => {
+                info!(
+                    "Migration {} of {:?}.{:?} completed",
+                    self.id, self.database, self.table
+                );
+                status.state = MigrationState::Completed;
+            }
+            Err(MigrationError::Cancelled) => {
+                info!(
+                    "Migration {} of {:?}.{:?} was cancelled",
+                    self.id, self.database, self.table
+                );
+                status.state = MigrationState::Cancelled;
+            }
+            Err(err) => {
+                warn!(
+                    "Migration {} of {:?}.{:?} failed: {err}",
+                    self.id, self.database, self.table
+                );
+                status.state = MigrationState::Failed;
+                status.error = Some(err.to_string());
+            }
+        }
+        result
+    }
+
+    async fn migrate(&self, conn: &mut Conn) -> Result<(), MigrationError> {
+        let Names {
+            table,
+            shadow,
+            old,
+            triggers,
+            ..
+        } = &self.names;
+
+        self.lock().state = MigrationState::Copying;
+        format!("CREATE TABLE {shadow} LIKE {table}")
+            .ignore(&mut *conn)
+            .await?;
+        self.copy(conn).await?;
+
+        // The triggers were renamed with the original table
+        for trigger in triggers {
+            if let Err(err) = format!("DROP TRIGGER {trigger}").ignore(&mut *conn).await {
+                warn!(
+                    "Error dropping trigger {trigger} of migration {}: {err}",
+                    self.id
+                );
+            }
+        }
+        if let Err(err) = format!("DROP TABLE {old}").ignore(&mut *conn).await {
+            warn!("Error dropping table {old} of migration {}: {err}", self.id);
+        }
+        Ok(())
+    }
+
+    /// Drop the triggers and shadow table of a migration which didn't complete, leaving the original table as it was.
+    ///
+    /// This uses a new connection as the migration's may be what failed.
+    async fn clean_up(&self, pool: &Pool) {
+        let Names {
+            shadow, triggers, ..
+        } = &self.names;
+
+        let mut conn = match pool.get_conn().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!(
+                    "Error getting a connection to clean up migration {}, {shadow} and it's triggers must be dropped manually: {err}",
+                    self.id
+                );
+                return;
+            }
+        };
+        for trigger in triggers {
+            if let Err(err) = format!("DROP TRIGGER IF EXISTS {trigger}")
+                .ignore(&mut conn)
+                .await
+            {
+                warn!(
+                    "Error dropping trigger {trigger} of migration {}: {err}",
+                    self.id
+                );
+            }
+        }
+        if let Err(err) = format!("DROP TABLE IF EXISTS {shadow}")
+            .ignore(&mut conn)
+            .await
+        {
+            warn!(
+                "Error dropping table {shadow} of migration {}: {err}",
+                self.id
+            );
+        }
+    }
+
+    /// Alter the shadow table, copy the rows into it and swap it with the original table.
+    async fn copy(&self, conn: &mut Conn) -> Result<(), MigrationError> {
+        let Names {
+            table,
+            shadow,
+            old,
+            triggers,
+            shadow_name,
+            ..
+        } = &self.names;
+
+        format!("ALTER TABLE {shadow} {}", self.alteration)
+            .ignore(&mut *conn)
+            .await?;
+        if primary_key(conn, &self.database, shadow_name).await? != self.primary_key {
+            return Err(MigrationError::Unsupported(
+                "Changing the primary key can't be done online".into(),
+            ));
+        }
+
+        // The columns in both tables, generated columns can't be inserted into
+        let columns = "SELECT COLUMN_NAME FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = ? AND TABLE_NAME = ? AND GENERATION_EXPRESSION = '' ORDER BY ORDINAL_POSITION";
+        let new_columns = columns
+            .with((&self.database, shadow_name))
+            .map(&mut *conn, |column: String| column)
+            .await?
+            .into_iter()
+            .collect::<HashSet<_>>();
+        let columns = columns
+            .with((&self.database, &self.table))
+            .map(&mut *conn, |column: String| column)
+            .await?
+            .into_iter()
+            .filter(|column| new_columns.contains(column))
+            .collect::<Vec<_>>();
+
+        // Rows copied without a value for a new `NOT NULL` column would fail in strict mode, and so would writes to the table
+        let required: Option<String> = "SELECT COLUMN_NAME FROM information_schema.COLUMNS WHERE TABLE_SCHEMA = ? AND TABLE_NAME = ? AND IS_NULLABLE = 'NO' AND COLUMN_DEFAULT IS NULL AND GENERATION_EXPRESSION = '' AND EXTRA NOT LIKE '%auto_increment%'"
+            .with((&self.database, shadow_name))
+            .map(&mut *conn, |column: String| column)
+            .await?
+            .into_iter()
+            .find(|column| !columns.contains(column));
+        if let Some(column) = required {
+            return Err(MigrationError::Unsupported(format!(
+                "Adding the 'NOT NULL' column {column:?} without a default can't be done online"
+            )));
+        }
+
+        let column_list = columns
+            .iter()
+            .map(|c| quote(c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let new_values = columns
+            .iter()
+            .map(|c| format!("NEW.{}", quote(c)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let old_key = self
+            .primary_key
+            .iter()
+            .map(|c| format!("{c} = OLD.{c}", c = quote(c)))
+            .collect::<Vec<_>>()
+            .join(" AND ");
+
+        // Keep the shadow table up to date with writes made while it's copied
+        let [insert, update, delete] = triggers;
+        format!("CREATE TRIGGER {insert} AFTER INSERT ON {table} FOR EACH ROW REPLACE INTO {shadow} ({column_list}) VALUES ({new_values})")
+            .ignore(&mut *conn)
+            .await?;
+        format!("CREATE TRIGGER {update} AFTER UPDATE ON {table} FOR EACH ROW BEGIN DELETE IGNORE FROM {shadow} WHERE {old_key}; REPLACE INTO {shadow} ({column_list}) VALUES ({new_values}); END")
+            .ignore(&mut *conn)
+            .await?;
+        format!("CREATE TRIGGER {delete} AFTER DELETE ON {table} FOR EACH ROW DELETE IGNORE FROM {shadow} WHERE {old_key}")
+            .ignore(&mut *conn)
+            .await?;
+
+        let key = self
+            .primary_key
+            .iter()
+            .map(|c| quote(c))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let placeholders = vec!["?"; self.primary_key.len()].join(", ");
+        let unchanged = self
+            .primary_key
+            .iter()
+            .map(|c| format!("{shadow}.{c} = {shadow}.{c}", c = quote(c)))
+            .collect::<Vec<_>>()
+            .join(", ");
+        let mut last: Option<Vec<Value>> = None;
+        loop {
+            if self.cancelled.load(Ordering::Relaxed) {
+                return Err(MigrationError::Cancelled);
+            }
+            let throttle = self.lock().throttle;
+
+            // Find the primary key of the last row in the chunk, there is no end to the final chunk
+            let after = match last.is_some() {
+                true => format!("WHERE ({key}) > ({placeholders})"),
+                false => String::new(),
+            };
+            let end: Option<Row> = conn
+                .exec_first(
+                    format!(
+                        "SELECT {key} FROM {table} {after} ORDER BY {key} LIMIT 1 OFFSET {}",
+                        throttle.chunk_size.max(1) - 1
+                    ),
+                    params(last.iter().flatten()),
+                )
+                .await?;
+            let end = end.map(Row::unwrap);
+
+            let mut conditions = Vec::new();
+            if last.is_some() {
+                conditions.push(format!("({key}) > ({placeholders})"));
+            }
+            if end.is_some() {
+                conditions.push(format!("({key}) <= ({placeholders})"));
+            }
+            let filter = match conditions.is_empty() {
+                true => String::new(),
+                false => format!("WHERE {}", conditions.join(" AND ")),
+            };
+
+            // Rows the triggers have already written are newer, so they're kept.
+            // Any other error (Eg. a value which no longer fits it's column) fails the migration, like it would fail the `ALTER`.
+            let query = format!("INSERT INTO {shadow} ({column_list}) SELECT {column_list} FROM {table} {filter} LOCK IN SHARE MODE ON DUPLICATE KEY UPDATE {unchanged}");
+            let mut attempt = 1;
+            loop {
+                match conn
+                    .exec_drop(
+                        &query,
+                        params(last.iter().flatten().chain(end.iter().flatten())),
+                    )
+                    .await
+                {
+                    Ok(()) => break,
+                    Err(mysql_async::Error::Server(err))
+                        if err.code == 1213 && attempt < CHUNK_ATTEMPTS =>
+                    {
+                        debug!("Migration {} deadlocked copying a chunk, retrying", self.id);
+                        attempt += 1;
+                    }
+                    Err(err) => return Err(err.into()),
+                }
+            }
+            self.lock().copied_rows += conn.affected_rows();
+
+            let Some(end) = end else {
+                break;
+            };
+            last = Some(end);
+            if throttle.chunk_delay > 0 {
+                tokio::time::sleep(Duration::from_millis(throttle.chunk_delay)).await;
+            }
+        }
+
+        self.lock().state = MigrationState::CuttingOver;
+        format!("SET SESSION lock_wait_timeout = {CUT_OVER_LOCK_TIMEOUT}")
+            .ignore(&mut *conn)
+            .await?;
+        let mut attempt = 1;
+        let result = loop {
+            if self.cancelled.load(Ordering::Relaxed) {
+                break Err(MigrationError::Cancelled);
+            }
+
+            // Both renames happen at once so the table always exists
+            match format!("RENAME TABLE {table} TO {old}, {shadow} TO {table}")
+                .ignore(&mut *conn)
+                .await
+            {
+                Ok(()) => break Ok(()),
+                Err(mysql_async::Error::Server(err))
+                    if err.code == 1205 && attempt < CUT_OVER_ATTEMPTS =>
+                {
+                    debug!(
+                        "Migration {} timed out waiting for a lock to cut-over, retrying",
+                        self.id
+                    );
+                    attempt += 1;
+                }
+                Err(err) => break Err(err.into()),
+            }
+        };
+        if let Err(err) = "SET SESSION lock_wait_timeout = DEFAULT"
+            .ignore(&mut *conn)
+            .await
+        {
+            warn!("Error resetting lock wait timeout: {err}");
+        }
+        result
+    }
+}
+
+/// Cleans up a migration whose task is dropped before it finishes, which happens if it panics or the runtime shuts down.
+struct Interrupted {
+    migration: Arc<Migration>,
+    pool: Pool,
+}
+
+impl Drop for Interrupted {
+    fn drop(&mut self) {
+        {
+            let mut status = self.migration.lock();
+            if status.finished_at.is_some() {
+                return;
+            }
+            status.state = MigrationState::Failed;
+            status.finished_at = Some(now());
+            status.error = Some("The migration was interrupted".into());
+        }
+
+        warn!("Migration {} was interrupted", self.migration.id);
+        if let Ok(runtime) = Handle::try_current() {
+            let (migration, pool) = (self.migration.clone(), self.pool.clone());
+            runtime.spawn(async move { migration.clean_up(&pool).await });
+        }
+    }
+}
+
+/// The quoted names of the tables and triggers used by a migration.
+struct Names {
+    table: String,
+    shadow: String,
+    shadow_name: String,
+    old: String,
+    old_name: String,
+    /// The insert, update and delete triggers.
+    triggers: [String; 3],
+}
+
+impl Names {
+    fn new(database: &str, table: &str) -> Self {
+        let qualified = |name: &str| format!("{}.{}", quote(database), quote(name));
+        let shadow_name = format!("_{table}_new");
+        let old_name = format!("_{table}_old");
+        Self {
+            table: qualified(table),
+            shadow: qualified(&shadow_name),
+            shadow_name,
+            old: qualified(&old_name),
+            old_name,
+            triggers: ["ins", "upd", "del"].map(|kind| qualified(&format!("_{table}_{kind}"))),
+        }
+    }
+}
+
+fn params<'a>(values: impl Iterator<Item = &'a Value>) -> Params {
+    let values = values.cloned().collect::<Vec<_>>();
+    match values.is_empty() {
+        true => Params::Empty,
+        false => Params::Positional(values),
+    }
+}
+
+fn now() -> u64 {
+    SystemTime::now()
+        .duration_since(UNIX_EPOCH)
+        .map(|d| d.as_secs())
+        .unwrap_or_default()
+}
+
+async fn primary_key(
+    conn: &mut Conn,
+    database: &str,
+    table: &str,
+) -> Result<Vec<String>, mysql_async::Error> {
+    "SELECT COLUMN_NAME FROM information_schema.STATISTICS WHERE TABLE_SCHEMA = ? AND TABLE_NAME = ? AND INDEX_NAME = 'PRIMARY' ORDER BY SEQ_IN_INDEX"
+        .with((database, table))
+        .map(&mut *conn, |column: String| column)
+        .await
+}
+
+/// Split an `ALTER TABLE` statement into the table name and the alterations.
+/// Returns `None` for other statements, or if the table is qualified with a database.
+pub fn parse_alter(statement: &str) -> Option<(String, &str)> {
+    let statement = statement.trim().trim_end_matches(';').trim_end();
+    let rest = strip_keyword(statement, "ALTER")?;
+    let rest = strip_keyword(rest, "TABLE")?;
+
+    let (table, rest) = match rest.strip_prefix('`') {
+        Some(rest) => {
+            // A backtick in the name is escaped by doubling it
+            let mut name = String::new();
+            let mut chars = rest.char_indices().peekable();
+            loop {
+                let (i, c) = chars.next()?;
+                match c {
+                    '`' if matches!(chars.peek(), Some((_, '`'))) => {
+                        chars.next();
+                        name.push('`');
+                    }
+                    '`' => break (name, &rest[i + 1..]),
+                    c => name.push(c),
+                }
+            }
+        }
+        None => {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(rest.len());
+            (rest[..end].to_string(), &rest[end..])
+        }
+    };
+    if table.is_empty() || !rest.starts_with(char::is_whitespace) {
+        return None;
+    }
+
+    let rest = rest.trim();
+    (!rest.is_empty()).then_some((table, rest))
+}
+
+/// Reject alterations whose effect on the copied rows differs from running them directly.
+fn check_alteration(alteration: &str) -> Result<(), MigrationError> {
+    let keywords = keywords(alteration);
+    // MySQL would run anything after a semicolon as another statement
+    if keywords.iter().any(|word| word == ";") {
+        return Err(MigrationError::Unsupported(
+            "Only a single 'ALTER TABLE' statement can be run online".into(),
+        ));
+    }
+    // Columns are copied by name so renaming one would lose it's data
+    if let Some(keyword) = keywords
+        .iter()
+        .find(|word| *word == "RENAME" || *word == "CHANGE")
+    {
+        return Err(MigrationError::Unsupported(format!(
+            "'{keyword}' can't be run online"
+        )));
+    }
+    // The rows which break a new constraint would be lost when they're copied, instead of the alteration failing
+    if let Some(keyword) = keywords
+        .iter()
+        .find(|word| ["UNIQUE", "FOREIGN", "REFERENCES"].contains(&word.as_str()))
+    {
+        return Err(MigrationError::Unsupported(format!(
+            "Adding a constraint with '{keyword}' can't be done online"
+        )));
+    }
+    Ok(())
+}
+
+fn strip_keyword<'a>(s: &'a str, keyword: &str) -> Option<&'a str> {
+    let rest = s
+        .get(..keyword.len())
+        .filter(|prefix| prefix.eq_ignore_ascii_case(keyword))
+        .map(|_| &s[keyword.len()..])?;
+    rest.starts_with(char::is_whitespace)
+        .then(|| rest.trim_start())
+}
+
+/// The uppercased words and semicolons of a statement, skipping strings and quoted identifiers.
+fn keywords(statement: &str) -> Vec<String> {
+    let mut words = Vec::new();
+    let mut word = String::new();
+    let mut chars = statement.chars().peekable();
+    while let Some(c) = chars.next() {
+        if c.is_alphanumeric() || c == '_' {
+            word.push(c.to_ascii_uppercase());
+            continue;
+        }
+        if !word.is_empty() {
+            words.push(mem::take(&mut word));
+        }
+        if c == ';' {
+            words.push(";".into());
+        }
+
+        if matches!(c, '\'' | '"' | '`') {
+            while let Some(next) = chars.next() {
+                if next == '\\' && c != '`' {
+                    chars.next();
+                } else if next == c {
+                    // A doubled quote doesn't end the string
+                    if chars.peek() != Some(&c) {
+                        break;
+                    }
+                    chars.next();
+                }
+            }
+        }
+    }
+    if !word.is_empty() {
+        words.push(word);
+    }
+    words
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_alterations_which_lose_data() {
+        assert!(check_alteration("ADD COLUMN email VARCHAR(255), ADD INDEX name (name)").is_ok());
+        assert!(check_alteration("ADD COLUMN `unique` INT DEFAULT 'FOREIGN'").is_ok());
+        assert!(check_alteration("ADD COLUMN a VARCHAR(1) DEFAULT ';'").is_ok());
+        assert!(check_alteration("ADD COLUMN a INT; DROP TABLE other").is_err());
+        assert!(check_alteration("ADD COLUMN a INT /*!; DROP TABLE other */").is_err());
+        assert!(check_alteration("RENAME COLUMN a TO b").is_err());
+        assert!(check_alteration("CHANGE a b INT").is_err());
+        assert!(check_alteration("ADD UNIQUE INDEX email (email)").is_err());
+        assert!(check_alteration("MODIFY email VARCHAR(255) unique").is_err());
+        assert!(
+            check_alteration("ADD CONSTRAINT fk FOREIGN KEY (user_id) REFERENCES users (id)")
+                .is_err()
+        );
+    }
+}
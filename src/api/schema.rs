@@ -0,0 +1,882 @@
+use std::{
+    collections::{BTreeMap, BTreeSet},
+    sync::Arc,
+};
+
+use axum::{
+    extract::{self, Path, State},
+    http::StatusCode,
+    response::IntoResponse,
+    routing::get,
+    Json, Router,
+};
+use mysql_async::{prelude::*, Conn};
+use serde::{Deserialize, Serialize};
+use tracing::error;
+
+use super::{
+    branches::{is_valid_name, quote, strip_auto_increment},
+    AppState,
+};
+
+pub fn mount() -> Router<Arc<AppState>> {
+    Router::new().route(
+        "/database/:db/diff",
+        get(
+            |State(state): State<Arc<AppState>>,
+             Path(db_name): Path<String>,
+             extract::Query(query): extract::Query<DiffQuery>| async move {
+                // A branch is compared to it's parent by default
+                let base = query.base.or_else(|| {
+                    let config = state.config.get();
+                    config
+                        .databases
+                        .get(&db_name)
+                        .and_then(|db| db.branch.as_ref())
+                        .map(|branch| branch.parent.clone())
+                });
+                let Some(base) = base else {
+                    return (
+                        StatusCode::BAD_REQUEST,
+                        "The database isn't a branch so 'base' must be set",
+                    )
+                        .into_response();
+                };
+                if !is_valid_name(&base) || !is_valid_name(&db_name) {
+                    return (StatusCode::BAD_REQUEST, "Invalid database name").into_response();
+                }
+
+                let Ok(mut conn) = state
+                    .db
+                    .get_conn()
+                    .await
+                    .map_err(|err| error!("Error getting DB connection: {err}"))
+                else {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error")
+                        .into_response();
+                };
+
+                let schemas = async {
+                    Ok::<_, mysql_async::Error>((
+                        load(&mut conn, &base).await?,
+                        load(&mut conn, &db_name).await?,
+                    ))
+                };
+                match schemas.await {
+                    Ok((Some(from), Some(to))) => Json(diff(&from, &to)).into_response(),
+                    Ok(_) => (StatusCode::NOT_FOUND, "Database not found").into_response(),
+                    Err(err) => {
+                        error!("Error loading schemas of {base:?} and {db_name:?}: {err}");
+                        (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response()
+                    }
+                }
+            },
+        ),
+    )
+}
+
+#[derive(Deserialize)]
+struct DiffQuery {
+    /// The database to compare against. Defaults to the parent of a branch.
+    base: Option<String>,
+}
+
+/// The schema of a database.
+#[derive(Debug)]
+pub struct Schema {
+    tables: BTreeMap<String, Table>,
+    /// The `CREATE VIEW` statement of each view.
+    views: BTreeMap<String, String>,
+    /// Each trigger, with it's `CREATE TRIGGER` statement.
+    triggers: BTreeMap<String, Trigger>,
+}
+
+/// The table, timing and event of a trigger.
+type TriggerGroup = (String, String, String);
+
+/// A trigger, whose statement `FOLLOWS` the trigger before it on the same table and event so their order is kept.
+#[derive(Debug)]
+struct Trigger {
+    /// The table, timing and event the trigger is ordered within.
+    group: TriggerGroup,
+    /// The position of the trigger in it's group, from 1.
+    order: u64,
+    statement: String,
+}
+
+/// Build the statement of each trigger from it's name, group, order and body, which are sorted by group and order.
+fn ordered_triggers(rows: &[(String, TriggerGroup, u64, String)]) -> BTreeMap<String, Trigger> {
+    let mut triggers = BTreeMap::new();
+    let mut previous: Option<(&TriggerGroup, &String)> = None;
+    for (name, group, order, statement) in rows {
+        let (table, timing, event) = group;
+        let mut create = format!(
+            "CREATE TRIGGER {} {timing} {event} ON {} FOR EACH ROW",
+            quote(name),
+            quote(table)
+        );
+        if let Some((_, previous)) = previous.filter(|(g, _)| *g == group) {
+            create.push_str(&format!(" FOLLOWS {}", quote(previous)));
+        }
+        create.push_str(&format!(" {statement}"));
+
+        triggers.insert(
+            name.clone(),
+            Trigger {
+                group: group.clone(),
+                order: *order,
+                statement: create,
+            },
+        );
+        previous = Some((group, name));
+    }
+    triggers
+}
+
+/// A table, split up from it's `SHOW CREATE TABLE` output so each part uses MySQL's canonical definition.
+#[derive(Debug)]
+struct Table {
+    /// The definition of each column, in order.
+    columns: Vec<(String, String)>,
+    /// The definition of each index. The primary key is named `PRIMARY`.
+    indexes: BTreeMap<String, String>,
+    foreign_keys: BTreeMap<String, String>,
+    checks: BTreeMap<String, String>,
+    /// The table options (Eg. `ENGINE=InnoDB DEFAULT CHARSET=utf8mb4`), excluding the `AUTO_INCREMENT` counter.
+    options: String,
+}
+
+impl Table {
+    fn parse(create: &str) -> Self {
+        let create = strip_auto_increment(create);
+        let mut table = Self {
+            columns: Vec::new(),
+            indexes: BTreeMap::new(),
+            foreign_keys: BTreeMap::new(),
+            checks: BTreeMap::new(),
+            options: String::new(),
+        };
+
+        for line in create.lines().skip(1) {
+            if let Some(options) = line.strip_prefix(") ") {
+                table.options = options.to_string();
+                break;
+            }
+
+            let definition = line.trim().trim_end_matches(',').to_string();
+            if definition.starts_with('`') {
+                table.columns.push((parse_name(&definition), definition));
+            } else if definition.starts_with("PRIMARY KEY") {
+                table.indexes.insert("PRIMARY".into(), definition);
+            } else if let Some(rest) = ["KEY ", "UNIQUE KEY ", "FULLTEXT KEY ", "SPATIAL KEY "]
+                .iter()
+                .find_map(|prefix| definition.strip_prefix(prefix))
+            {
+                table.indexes.insert(parse_name(rest), definition.clone());
+            } else if let Some(rest) = definition.strip_prefix("CONSTRAINT ") {
+                let name = parse_name(rest);
+                match definition.contains(" FOREIGN KEY ") {
+                    true => table.foreign_keys.insert(name, definition),
+                    false => table.checks.insert(name, definition),
+                };
+            }
+        }
+
+        table
+    }
+
+    /// The `CREATE TABLE` statement without foreign keys, as they may reference tables which don't exist yet.
+    fn create_statement(&self, name: &str) -> String {
+        let definitions = self
+            .columns
+            .iter()
+            .map(|(_, definition)| definition)
+            .chain(self.indexes.values())
+            .chain(self.checks.values())
+            .map(|definition| format!("  {definition}"))
+            .collect::<Vec<_>>()
+            .join(",\n");
+
+        format!(
+            "CREATE TABLE {} (\n{definitions}\n) {}",
+            quote(name),
+            self.options
+        )
+    }
+}
+
+/// Parse the quoted name at the start of a definition (Eg. `` `id` int NOT NULL ``).
+fn parse_name(definition: &str) -> String {
+    let mut name = String::new();
+    let mut chars = definition
+        .strip_prefix('`')
+        .unwrap_or(definition)
+        .chars()
+        .peekable();
+    while let Some(c) = chars.next() {
+        if c == '`' {
+            // A backtick in a name is escaped by doubling it
+            if chars.peek() != Some(&'`') {
+                break;
+            }
+            chars.next();
+        }
+        name.push(c);
+    }
+    name
+}
+
+/// Load the schema of a database, or `None` if it doesn't exist.
+pub async fn load(conn: &mut Conn, database: &str) -> Result<Option<Schema>, mysql_async::Error> {
+    let exists: Option<String> =
+        "SELECT SCHEMA_NAME FROM information_schema.SCHEMATA WHERE SCHEMA_NAME = ?"
+            .with((database,))
+            .first(&mut *conn)
+            .await?;
+    if exists.is_none() {
+        return Ok(None);
+    }
+
+    let table_names = "SELECT TABLE_NAME FROM information_schema.TABLES WHERE TABLE_SCHEMA = ? AND TABLE_TYPE = 'BASE TABLE'"
+        .with((database,))
+        .map(&mut *conn, |table: String| table)
+        .await?;
+    let mut tables = BTreeMap::new();
+    for name in table_names {
+        let create: Option<(String, String)> =
+            format!("SHOW CREATE TABLE `{database}`.{}", quote(&name))
+                .first(&mut *conn)
+                .await?;
+        if let Some((_, create)) = create {
+            tables.insert(name, Table::parse(&create));
+        }
+    }
+
+    // Views and triggers reference tables qualified with the database, which is removed so they can be compared between databases
+    let qualifier = format!("`{database}`.");
+    let views = "SELECT TABLE_NAME, VIEW_DEFINITION, CHECK_OPTION, SECURITY_TYPE FROM information_schema.VIEWS WHERE TABLE_SCHEMA = ?"
+        .with((database,))
+        .map(&mut *conn, |(name, definition, check_option, security_type): (String, String, String, String)| {
+            let mut statement = format!(
+                "CREATE OR REPLACE SQL SECURITY {security_type} VIEW {} AS {}",
+                quote(&name),
+                definition.replace(&qualifier, "")
+            );
+            if check_option != "NONE" {
+                statement.push_str(&format!(" WITH {check_option} CHECK OPTION"));
+            }
+            (name, statement)
+        })
+        .await?
+        .into_iter()
+        .collect();
+
+    let rows = "SELECT TRIGGER_NAME, ACTION_TIMING, EVENT_MANIPULATION, EVENT_OBJECT_TABLE, ACTION_ORDER, ACTION_STATEMENT FROM information_schema.TRIGGERS WHERE TRIGGER_SCHEMA = ? ORDER BY EVENT_OBJECT_TABLE, ACTION_TIMING, EVENT_MANIPULATION, ACTION_ORDER"
+        .with((database,))
+        .map(&mut *conn, |(name, timing, event, table, order, statement): (String, String, String, String, u64, String)| {
+            (name, (table, timing, event), order, statement.replace(&qualifier, ""))
+        })
+        .await?;
+    let triggers = ordered_triggers(&rows);
+
+    Ok(Some(Schema {
+        tables,
+        views,
+        triggers,
+    }))
+}
+
+/// The difference between two schemas.
+#[derive(Debug, Serialize)]
+pub struct SchemaDiff {
+    pub tables: Vec<TableDiff>,
+    pub views: Vec<Change>,
+    pub triggers: Vec<Change>,
+    /// The statements which turn the first schema into the second, in the order they must be run.
+    /// They are not qualified with a database so they must be run with the first database selected.
+    pub statements: Vec<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct TableDiff {
+    pub name: String,
+    pub change: ChangeKind,
+    pub columns: Vec<Change>,
+    pub indexes: Vec<Change>,
+    pub foreign_keys: Vec<Change>,
+    pub checks: Vec<Change>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub options: Option<Change>,
+}
+
+/// A change to a part of the schema, with it's definition before and after.
+#[derive(Debug, Clone, Serialize)]
+pub struct Change {
+    pub name: String,
+    pub change: ChangeKind,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub from: Option<String>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub to: Option<String>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ChangeKind {
+    Added,
+    Removed,
+    Modified,
+}
+
+/// Compare two named collections, in the order of `to` followed by the removed items.
+fn diff_items<'a>(
+    from: impl IntoIterator<Item = (&'a String, &'a String)>,
+    to: impl IntoIterator<Item = (&'a String, &'a String)>,
+) -> Vec<Change> {
+    let from = from.into_iter().collect::<Vec<_>>();
+    let to = to.into_iter().collect::<Vec<_>>();
+    let find = |items: &[(&String, &'a String)], name: &String| {
+        items
+            .iter()
+            .find(|(n, _)| *n == name)
+            .map(|(_, d)| (*d).clone())
+    };
+
+    let mut changes = Vec::new();
+    for (name, definition) in &to {
+        let change = match find(&from, name) {
+            None => ChangeKind::Added,
+            Some(old) if old != **definition => ChangeKind::Modified,
+            Some(_) => continue,
+        };
+        changes.push(Change {
+            name: (*name).clone(),
+            change,
+            from: find(&from, name),
+            to: Some((*definition).clone()),
+        });
+    }
+    for (name, definition) in &from {
+        if find(&to, name).is_none() {
+            changes.push(Change {
+                name: (*name).clone(),
+                change: ChangeKind::Removed,
+                from: Some((*definition).clone()),
+                to: None,
+            });
+        }
+    }
+    changes
+}
+
+/// Compare the columns of a table, in the order of `to` followed by the removed columns.
+///
+/// A column which moved is modified, and the position is included in it's definitions (Eg. `` `name` text AFTER `id` ``).
+fn diff_columns(from: &[(String, String)], to: &[(String, String)]) -> Vec<Change> {
+    let mut changes = diff_items(
+        from.iter().map(|(n, d)| (n, d)),
+        to.iter().map(|(n, d)| (n, d)),
+    );
+
+    let positioned = |columns: &[(String, String)], name: &str| {
+        let i = columns.iter().position(|(n, _)| n == name)?;
+        let position = match i.checked_sub(1) {
+            Some(previous) => format!("AFTER {}", quote(&columns[previous].0)),
+            None => "FIRST".into(),
+        };
+        Some(format!("{} {position}", columns[i].1))
+    };
+    for name in moved_columns(from, to) {
+        let (old, new) = (positioned(from, &name), positioned(to, &name));
+        match changes.iter_mut().find(|change| change.name == name) {
+            Some(change) => (change.from, change.to) = (old, new),
+            None => changes.push(Change {
+                name,
+                change: ChangeKind::Modified,
+                from: old,
+                to: new,
+            }),
+        }
+    }
+
+    // The position of a column is relative to the ones before it, so they are changed first
+    changes.sort_by_key(|change| {
+        to.iter()
+            .position(|(n, _)| *n == change.name)
+            .unwrap_or(usize::MAX)
+    });
+    changes
+}
+
+/// The columns in both tables which must be moved to change the order of `from` to `to`.
+///
+/// The longest run of columns which are already in order stays put, so as few columns as possible are moved.
+fn moved_columns(from: &[(String, String)], to: &[(String, String)]) -> Vec<String> {
+    // The position in `from` of each column in both tables, in the order of `to`
+    let positions = to
+        .iter()
+        .filter_map(|(name, _)| from.iter().position(|(n, _)| n == name))
+        .collect::<Vec<_>>();
+
+    // The longest increasing subsequence, as the length ending at each position and the position before it
+    let mut lengths = vec![1; positions.len()];
+    let mut previous = vec![None; positions.len()];
+    for i in 0..positions.len() {
+        for j in 0..i {
+            if positions[j] < positions[i] && lengths[j] + 1 > lengths[i] {
+                lengths[i] = lengths[j] + 1;
+                previous[i] = Some(j);
+            }
+        }
+    }
+    let mut kept = BTreeSet::new();
+    let mut next = (0..positions.len()).max_by_key(|i| (lengths[*i], std::cmp::Reverse(*i)));
+    while let Some(i) = next {
+        kept.insert(positions[i]);
+        next = previous[i];
+    }
+
+    positions
+        .iter()
+        .filter(|position| !kept.contains(*position))
+        .map(|position| from[*position].0.clone())
+        .collect()
+}
+
+/// Compare two schemas, generating the DDL which turns `from` into `to`.
+///
+/// Renames can't be detected so they are a drop and an add. Partitioning isn't compared.
+pub fn diff(from: &Schema, to: &Schema) -> SchemaDiff {
+    let empty = Table::parse("");
+    let mut tables = Vec::new();
+    // Each phase is run in order so the objects a statement depends on exist
+    let mut drop_triggers = Vec::new();
+    let mut drop_views = Vec::new();
+    let mut drop_foreign_keys = Vec::new();
+    let mut create_tables = Vec::new();
+    let mut alter_tables = Vec::new();
+    let mut drop_tables = Vec::new();
+    let mut add_foreign_keys = Vec::new();
+    let mut create_views = Vec::new();
+    let mut create_triggers = Vec::new();
+
+    let names = from.tables.keys().chain(
+        to.tables
+            .keys()
+            .filter(|name| !from.tables.contains_key(*name)),
+    );
+    for name in names {
+        let (old, new) = (from.tables.get(name), to.tables.get(name));
+        let change = match (old, new) {
+            (None, _) => ChangeKind::Added,
+            (_, None) => ChangeKind::Removed,
+            (Some(_), Some(_)) => ChangeKind::Modified,
+        };
+        let (old, new) = (old.unwrap_or(&empty), new.unwrap_or(&empty));
+        let table = quote(name);
+
+        let columns = diff_columns(&old.columns, &new.columns);
+        let indexes = diff_items(&old.indexes, &new.indexes);
+        let foreign_keys = diff_items(&old.foreign_keys, &new.foreign_keys);
+        let checks = diff_items(&old.checks, &new.checks);
+        let options =
+            (change == ChangeKind::Modified && old.options != new.options).then(|| Change {
+                name: "options".into(),
+                change: ChangeKind::Modified,
+                from: Some(old.options.clone()),
+                to: Some(new.options.clone()),
+            });
+
+        // Foreign keys are dropped before anything else so the columns, indexes and tables they use can be changed
+        for fk in foreign_keys
+            .iter()
+            .filter(|fk| fk.change != ChangeKind::Added)
+        {
+            drop_foreign_keys.push(format!(
+                "ALTER TABLE {table} DROP FOREIGN KEY {}",
+                quote(&fk.name)
+            ));
+        }
+        for fk in foreign_keys
+            .iter()
+            .filter(|fk| fk.change != ChangeKind::Removed)
+        {
+            add_foreign_keys.push(format!(
+                "ALTER TABLE {table} ADD {}",
+                fk.to.as_deref().unwrap_or_default()
+            ));
+        }
+
+        match change {
+            ChangeKind::Added => create_tables.push(new.create_statement(name)),
+            ChangeKind::Removed => drop_tables.push(format!("DROP TABLE {table}")),
+            ChangeKind::Modified => {
+                let mut clauses = Vec::new();
+                for index in indexes.iter().filter(|i| i.change != ChangeKind::Added) {
+                    clauses.push(match index.name.as_str() {
+                        "PRIMARY" => "DROP PRIMARY KEY".to_string(),
+                        name => format!("DROP INDEX {}", quote(name)),
+                    });
+                }
+                for check in checks.iter().filter(|c| c.change != ChangeKind::Added) {
+                    clauses.push(format!("DROP CHECK {}", quote(&check.name)));
+                }
+                for column in &columns {
+                    let definition = column.to.as_deref().unwrap_or_default();
+                    clauses.push(match column.change {
+                        ChangeKind::Removed => format!("DROP COLUMN {}", quote(&column.name)),
+                        ChangeKind::Modified => format!("MODIFY COLUMN {definition}"),
+                        ChangeKind::Added => {
+                            let position = new
+                                .columns
+                                .iter()
+                                .position(|(n, _)| *n == column.name)
+                                .unwrap_or(0);
+                            match position.checked_sub(1).and_then(|i| new.columns.get(i)) {
+                                Some((previous, _)) => {
+                                    format!("ADD COLUMN {definition} AFTER {}", quote(previous))
+                                }
+                                None => format!("ADD COLUMN {definition} FIRST"),
+                            }
+                        }
+                    });
+                }
+                for item in indexes
+                    .iter()
+                    .chain(&checks)
+                    .filter(|i| i.change != ChangeKind::Removed)
+                {
+                    clauses.push(format!("ADD {}", item.to.as_deref().unwrap_or_default()));
+                }
+                if let Some(options) = &options {
+                    clauses.push(options.to.clone().unwrap_or_default());
+                }
+
+                if !clauses.is_empty() {
+                    alter_tables.push(format!("ALTER TABLE {table} {}", clauses.join(", ")));
+                }
+            }
+        }
+
+        let unchanged = columns.is_empty()
+            && indexes.is_empty()
+            && foreign_keys.is_empty()
+            && checks.is_empty()
+            && options.is_none();
+        if !unchanged || change != ChangeKind::Modified {
+            tables.push(TableDiff {
+                name: name.clone(),
+                change,
+                columns,
+                indexes,
+                foreign_keys,
+                checks,
+                options,
+            });
+        }
+    }
+
+    let views = diff_items(&from.views, &to.views);
+    for view in views
+        .iter()
+        .filter(|view| view.change == ChangeKind::Removed)
+    {
+        drop_views.push(format!("DROP VIEW {}", quote(&view.name)));
+    }
+    // `CREATE OR REPLACE` updates a modified view. A view is created after the views it selects from.
+    let mut pending = views
+        .iter()
+        .filter(|view| view.change != ChangeKind::Removed)
+        .collect::<Vec<_>>();
+    while !pending.is_empty() {
+        let references = |view: &Change, other: &Change| {
+            other.name != view.name
+                && view
+                    .to
+                    .as_deref()
+                    .unwrap_or_default()
+                    .contains(&quote(&other.name))
+        };
+        let ready = pending
+            .iter()
+            .position(|view| !pending.iter().any(|other| references(view, other)))
+            .unwrap_or(0);
+        create_views.push(pending.remove(ready).to.clone().unwrap_or_default());
+    }
+
+    let triggers = diff_items(
+        from.triggers.iter().map(|(n, t)| (n, &t.statement)),
+        to.triggers.iter().map(|(n, t)| (n, &t.statement)),
+    );
+    // A trigger is placed relative to the others on it's table and event, so every trigger in a changed group is recreated in order
+    let changed_groups = triggers
+        .iter()
+        .flat_map(|trigger| {
+            [
+                from.triggers.get(&trigger.name),
+                to.triggers.get(&trigger.name),
+            ]
+        })
+        .flatten()
+        .map(|trigger| &trigger.group)
+        .collect::<BTreeSet<_>>();
+    for (name, _) in from
+        .triggers
+        .iter()
+        .filter(|(_, t)| changed_groups.contains(&t.group))
+    {
+        drop_triggers.push(format!("DROP TRIGGER {}", quote(name)));
+    }
+    let mut recreated = to
+        .triggers
+        .values()
+        .filter(|t| changed_groups.contains(&t.group))
+        .collect::<Vec<_>>();
+    recreated.sort_by_key(|t| (&t.group, t.order));
+    create_triggers.extend(recreated.into_iter().map(|t| t.statement.clone()));
+
+    let statements = [
+        drop_triggers,
+        drop_views,
+        drop_foreign_keys,
+        create_tables,
+        alter_tables,
+        drop_tables,
+        add_foreign_keys,
+        create_views,
+        create_triggers,
+    ]
+    .concat();
+
+    SchemaDiff {
+        tables,
+        views,
+        triggers,
+        statements,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn table(columns: &[&str], keys: &[&str]) -> Table {
+        let definitions = columns
+            .iter()
+            .map(|column| format!("`{column}` int NOT NULL"))
+            .chain(keys.iter().map(|key| key.to_string()))
+            .map(|definition| format!("  {definition}"))
+            .collect::<Vec<_>>()
+            .join(",\n");
+        Table::parse(&format!(
+            "CREATE TABLE `t` (\n{definitions}\n) ENGINE=InnoDB AUTO_INCREMENT=5 DEFAULT CHARSET=utf8mb4"
+        ))
+    }
+
+    fn schema(tables: Vec<(&str, Table)>) -> Schema {
+        Schema {
+            tables: tables
+                .into_iter()
+                .map(|(name, table)| (name.to_string(), table))
+                .collect(),
+            views: BTreeMap::new(),
+            triggers: BTreeMap::new(),
+        }
+    }
+
+    fn views(views: &[(&str, &str)]) -> Schema {
+        let mut schema = schema(Vec::new());
+        schema.views = views
+            .iter()
+            .map(|(name, select)| {
+                let statement =
+                    format!("CREATE OR REPLACE SQL SECURITY DEFINER VIEW `{name}` AS {select}");
+                (name.to_string(), statement)
+            })
+            .collect();
+        schema
+    }
+
+    fn triggers(triggers: &[(&str, &str)]) -> Schema {
+        let rows = triggers
+            .iter()
+            .enumerate()
+            .map(|(i, (name, statement))| {
+                let group = ("t".into(), "BEFORE".into(), "INSERT".into());
+                (name.to_string(), group, i as u64 + 1, statement.to_string())
+            })
+            .collect::<Vec<_>>();
+        let mut schema = schema(vec![("t", table(&["id"], &[]))]);
+        schema.triggers = ordered_triggers(&rows);
+        schema
+    }
+
+    #[test]
+    fn parses_tables() {
+        let table = table(
+            &["id", "parent"],
+            &[
+                "PRIMARY KEY (`id`)",
+                "UNIQUE KEY `parent` (`parent`)",
+                "CONSTRAINT `fk` FOREIGN KEY (`parent`) REFERENCES `t` (`id`)",
+                "CONSTRAINT `positive` CHECK ((`id` > 0))",
+            ],
+        );
+        assert_eq!(
+            table.columns,
+            [
+                ("id".into(), "`id` int NOT NULL".into()),
+                ("parent".into(), "`parent` int NOT NULL".into())
+            ]
+        );
+        assert_eq!(
+            table.indexes.keys().collect::<Vec<_>>(),
+            ["PRIMARY", "parent"]
+        );
+        assert!(table.foreign_keys.contains_key("fk"));
+        assert!(table.checks.contains_key("positive"));
+        assert_eq!(table.options, "ENGINE=InnoDB DEFAULT CHARSET=utf8mb4");
+        assert_eq!(parse_name("`a``b` int"), "a`b");
+    }
+
+    #[test]
+    fn diffs_columns() {
+        let from = schema(vec![("t", table(&["id", "name", "old"], &[]))]);
+        let mut to = schema(vec![("t", table(&["id", "new", "name"], &[]))]);
+        to.tables.get_mut("t").unwrap().columns[2].1 = "`name` text".into();
+
+        let changes = diff(&from, &to);
+        assert_eq!(
+            changes.statements,
+            ["ALTER TABLE `t` ADD COLUMN `new` int NOT NULL AFTER `id`, MODIFY COLUMN `name` text, DROP COLUMN `old`"]
+        );
+        assert!(diff(&from, &from).statements.is_empty());
+    }
+
+    #[test]
+    fn diffs_column_order() {
+        let from = schema(vec![("t", table(&["a", "b", "c", "d"], &[]))]);
+        let to = schema(vec![("t", table(&["b", "c", "d", "a"], &[]))]);
+        assert_eq!(
+            diff(&from, &to).statements,
+            ["ALTER TABLE `t` MODIFY COLUMN `a` int NOT NULL AFTER `d`"]
+        );
+
+        let to = schema(vec![("t", table(&["d", "a", "b", "c"], &[]))]);
+        let changes = diff(&from, &to);
+        assert_eq!(
+            changes.statements,
+            ["ALTER TABLE `t` MODIFY COLUMN `d` int NOT NULL FIRST"]
+        );
+        assert_eq!(
+            changes.tables[0].columns[0].from.as_deref(),
+            Some("`d` int NOT NULL AFTER `c`")
+        );
+
+        let from = schema(vec![("t", table(&["a", "b"], &[]))]);
+        let to = schema(vec![("t", table(&["c", "b", "a"], &[]))]);
+        assert_eq!(
+            diff(&from, &to).statements,
+            ["ALTER TABLE `t` ADD COLUMN `c` int NOT NULL FIRST, MODIFY COLUMN `a` int NOT NULL AFTER `b`"]
+        );
+    }
+
+    #[test]
+    fn diffs_indexes() {
+        let from = schema(vec![(
+            "t",
+            table(&["id", "a"], &["PRIMARY KEY (`id`)", "KEY `a` (`a`)"]),
+        )]);
+        let to = schema(vec![(
+            "t",
+            table(
+                &["id", "a"],
+                &["PRIMARY KEY (`id`,`a`)", "UNIQUE KEY `b` (`a`)"],
+            ),
+        )]);
+        assert_eq!(
+            diff(&from, &to).statements,
+            ["ALTER TABLE `t` DROP PRIMARY KEY, DROP INDEX `a`, ADD PRIMARY KEY (`id`,`a`), ADD UNIQUE KEY `b` (`a`)"]
+        );
+    }
+
+    #[test]
+    fn diffs_foreign_keys_around_tables() {
+        let fk = "CONSTRAINT `fk` FOREIGN KEY (`parent`) REFERENCES `parents` (`id`)";
+        let from = schema(vec![
+            ("children", table(&["id", "parent"], &[fk])),
+            ("parents", table(&["id"], &["PRIMARY KEY (`id`)"])),
+        ]);
+        let to = schema(vec![
+            (
+                "children",
+                table(&["id", "parent"], &[&fk.replace("parents", "others")]),
+            ),
+            ("others", table(&["id"], &["PRIMARY KEY (`id`)"])),
+        ]);
+
+        let statements = diff(&from, &to).statements;
+        assert_eq!(statements.len(), 4);
+        assert_eq!(
+            statements[0],
+            "ALTER TABLE `children` DROP FOREIGN KEY `fk`"
+        );
+        assert!(statements[1]
+            .starts_with("CREATE TABLE `others` (\n  `id` int NOT NULL,\n  PRIMARY KEY (`id`)\n)"));
+        assert_eq!(statements[2], "DROP TABLE `parents`");
+        assert_eq!(
+            statements[3],
+            "ALTER TABLE `children` ADD CONSTRAINT `fk` FOREIGN KEY (`parent`) REFERENCES `others` (`id`)"
+        );
+    }
+
+    #[test]
+    fn creates_views_after_their_dependencies() {
+        let from = views(&[("old", "select 1")]);
+        let to = views(&[
+            ("a", "select * from `b`"),
+            ("b", "select * from `c`"),
+            ("c", "select 1"),
+        ]);
+
+        let statements = diff(&from, &to).statements;
+        assert_eq!(statements[0], "DROP VIEW `old`");
+        let created = statements[1..]
+            .iter()
+            .map(|statement| parse_name(statement.split(" VIEW ").nth(1).unwrap()))
+            .collect::<Vec<_>>();
+        assert_eq!(created, ["c", "b", "a"]);
+    }
+
+    #[test]
+    fn orders_triggers() {
+        let from = triggers(&[("a", "SET NEW.id = 1"), ("b", "SET NEW.id = 2")]);
+        assert_eq!(
+            from.triggers["b"].statement,
+            "CREATE TRIGGER `b` BEFORE INSERT ON `t` FOR EACH ROW FOLLOWS `a` SET NEW.id = 2"
+        );
+        assert!(diff(&from, &from).statements.is_empty());
+
+        // Swapping the order recreates every trigger in the group
+        let to = triggers(&[("b", "SET NEW.id = 2"), ("a", "SET NEW.id = 1")]);
+        assert_eq!(
+            diff(&from, &to).statements,
+            [
+                "DROP TRIGGER `a`",
+                "DROP TRIGGER `b`",
+                "CREATE TRIGGER `b` BEFORE INSERT ON `t` FOR EACH ROW SET NEW.id = 2",
+                "CREATE TRIGGER `a` BEFORE INSERT ON `t` FOR EACH ROW FOLLOWS `b` SET NEW.id = 1",
+            ]
+        );
+
+        let to = triggers(&[("a", "SET NEW.id = 1")]);
+        assert_eq!(
+            diff(&from, &to).statements,
+            [
+                "DROP TRIGGER `a`",
+                "DROP TRIGGER `b`",
+                "CREATE TRIGGER `a` BEFORE INSERT ON `t` FOR EACH ROW SET NEW.id = 1",
+            ]
+        );
+    }
+}
@@ -1,11 +1,14 @@
 use std::sync::Arc;
 
-use argon2::{password_hash::{SaltString, PasswordHasher}, Argon2};
+use argon2::{
+    password_hash::{PasswordHasher, SaltString},
+    Argon2,
+};
 use axum::{
     extract::{Path, State},
-    http::StatusCode,
+    http::{HeaderName, HeaderValue, Method, StatusCode},
     response::IntoResponse,
-    routing::{delete, get, post},
+    routing::{delete, get, post, put},
     Json, Router,
 };
 use rand::rngs::OsRng;
@@ -15,6 +18,7 @@ use tower_cookies::{Cookies, Key};
 use tracing::error;
 
 use super::{AppState, USERNAME_HEADER};
+use crate::config::{CorsConfig, UserConfig};
 
 pub fn mount() -> Router<Arc<AppState>> {
     Router::new()
@@ -29,7 +33,7 @@ pub fn mount() -> Router<Arc<AppState>> {
                     .to_string();
 
                 let config = state.config.get();
-                Json(config.admins.iter().map(|(username, _)| json!({
+                Json(config.admins.keys().map(|username| json!({
                     "username": username.clone(),
                     "is_self": *username == my_username
                 })).collect::<Vec<_>>())
@@ -37,12 +41,12 @@ pub fn mount() -> Router<Arc<AppState>> {
         )
         .route(
             "/admin",
-            post(|State(state): State<Arc<AppState>>, Json(data): Json<CreateUserRequest>| async move {               
+            post(|State(state): State<Arc<AppState>>, Json(data): Json<CreateUserRequest>| async move {
                 let salt = SaltString::generate(&mut OsRng);
                 let Ok(password_hash) = Argon2::default().hash_password(data.password.as_bytes(), &salt) else {
                     return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to hash password!").into_response();
                 };
-                
+
                 let mut config = state.config.edit();
                 config.admins.insert(data.username, password_hash.to_string());
 
@@ -64,7 +68,7 @@ pub fn mount() -> Router<Arc<AppState>> {
                 if my_username == username {
                     return (StatusCode::FORBIDDEN, "You cannot delete yourself!").into_response();
                 }
-                
+
                 let mut config = state.config.edit();
                 config.admins.remove(&username);
 
@@ -72,6 +76,65 @@ pub fn mount() -> Router<Arc<AppState>> {
                     return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to commit changes!").into_response();
                 }
 
+                StatusCode::NO_CONTENT.into_response()
+            }),
+        )
+        .route(
+            "/cors",
+            get(|State(state): State<Arc<AppState>>| async move {
+                Json(state.config.get().cors.clone())
+            }),
+        )
+        .route(
+            "/cors",
+            put(|State(state): State<Arc<AppState>>, Json(data): Json<CorsConfig>| async move {
+                if let Some(origin) = data.allowed_origins.iter().find(|o| HeaderValue::from_str(o).is_err()) {
+                    return (StatusCode::BAD_REQUEST, format!("Invalid origin {origin:?}")).into_response();
+                }
+                if let Some(method) = data.allowed_methods.iter().find(|m| *m != "*" && m.parse::<Method>().is_err()) {
+                    return (StatusCode::BAD_REQUEST, format!("Invalid method {method:?}")).into_response();
+                }
+                if let Some(header) = data.allowed_headers.iter().find(|h| *h != "*" && h.parse::<HeaderName>().is_err()) {
+                    return (StatusCode::BAD_REQUEST, format!("Invalid header {header:?}")).into_response();
+                }
+
+                let mut config = state.config.edit();
+                config.cors = data;
+
+                if config.commit().map_err(|err| error!("Error saving config: {err:?}")).is_err() {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to commit changes!").into_response();
+                }
+
+                StatusCode::NO_CONTENT.into_response()
+            }),
+        )
+        .route(
+            "/users",
+            get(|State(state): State<Arc<AppState>>| async move {
+                Json(state.config.get().users.clone())
+            }),
+        )
+        .route(
+            "/users/:username",
+            get(|State(state): State<Arc<AppState>>, Path(username): Path<String>| async move {
+                Json(state.config.get().users.get(&username).cloned().unwrap_or_default())
+            }),
+        )
+        .route(
+            "/users/:username",
+            put(|State(state): State<Arc<AppState>>, Path(username): Path<String>, Json(data): Json<UserConfig>| async move {
+                let mut config = state.config.edit();
+                // Users without any settings are removed to keep the config tidy
+                if data == UserConfig::default() {
+                    config.users.remove(&username);
+                } else {
+                    config.users.insert(username, data);
+                }
+
+                if config.commit().map_err(|err| error!("Error saving config: {err:?}")).is_err() {
+                    return (StatusCode::INTERNAL_SERVER_ERROR, "Failed to commit changes!").into_response();
+                }
+
                 StatusCode::NO_CONTENT.into_response()
             }),
         )
@@ -1,13 +1,17 @@
 use std::{
     collections::HashMap,
-    sync::{Arc, PoisonError, RwLock},
+    fmt,
+    sync::{Arc, Mutex, PoisonError, RwLock},
+    time::{Duration, Instant},
 };
 
 use axum::{
-    extract::State,
-    http::StatusCode,
+    body::Bytes,
+    extract::{State, WebSocketUpgrade},
+    http::HeaderMap,
+    middleware,
     response::{IntoResponse, Response},
-    routing::post,
+    routing::{get, post},
     Json, Router,
 };
 use axum_extra::{
@@ -17,346 +21,1641 @@ use axum_extra::{
 use base64::{engine::general_purpose::STANDARD, Engine};
 use mysql_async::{
     consts::{ColumnFlags, ColumnType},
-    prelude::Queryable,
-    Column, Conn, OptsBuilder, Pool, Row, Transaction, TxOpts, Value,
+    prelude::{Protocol, Queryable},
+    ChangeUserOpts, Column, Conn, OptsBuilder, Params, Pool, QueryResult, Row, TxOpts, Value,
 };
 use secstr::SecStr;
-use serde::{Deserialize, Serialize};
 use serde_json::json;
-use tracing::{debug, error};
+use tracing::{debug, error, warn};
 use uuid::Uuid;
 
-use super::AppState;
+use super::{
+    cancel::{self, Interrupted, QueryKiller},
+    AppState,
+};
+use crate::config::Config;
+use cache::{Analysis, CacheStats, Invalidation, ResultCache};
+use cityscale_client::types::{
+    BatchQuery, BatchRequest, CreateSessionRequest, Field, QueryResult as VitessResult,
+    Row as VitessRow, SessionMode, SqlRequest, TransactionSession,
+};
+use credentials::CredentialCache;
+pub use credentials::PoolStats;
+use database::SessionConn;
+use error::{Code, VitessError};
+use limits::RateLimiter;
+
+mod bind_vars;
+mod cache;
+mod cors;
+mod credentials;
+mod database;
+mod error;
+mod limits;
+mod proto;
+mod socket;
+mod stream;
+
+/// How often the reaper checks for expired sessions.
+const REAPER_INTERVAL: Duration = Duration::from_secs(5);
+/// How long we remember a reaped session so the client gets a useful error instead of "non-existent".
+const EXPIRED_SESSION_RETENTION: Duration = Duration::from_secs(15 * 60);
 
+#[derive(Default)]
 pub struct ConnectionPool {
-    /// A map of username + database combo's to database connection & password
-    connections: RwLock<HashMap<(String, String), (SecStr, mysql_async::Pool)>>,
-    /// Active database transactions
-    sessions: tokio::sync::RwLock<HashMap<Uuid, Transaction<'static>>>,
+    /// The credentials we have verified and the connection pool for each of them.
+    credentials: CredentialCache,
+    /// Active sessions, which are either a transaction or a pinned connection.
+    /// The map lock is only held to lookup a session, each session is locked individually while it's in use.
+    sessions: RwLock<HashMap<Uuid, SessionHandle>>,
+    /// Sessions which have been rolled back without the client asking and when that happened.
+    expired: Mutex<HashMap<Uuid, (Instant, Expiry)>>,
+    /// The request rate of each MySQL user with a limit.
+    rate_limiter: RateLimiter,
+    /// Results of `Execute` queries for users or queries with a cache TTL.
+    cache: ResultCache,
+    /// How many native MySQL connections each user has open through the proxy, which count as sessions.
+    native_connections: Mutex<HashMap<String, usize>>,
+    /// The connection credentials missing from the cache are checked with, see [`ConnectionPool::verify_credentials`].
+    verifier: tokio::sync::Mutex<Option<Conn>>,
 }
 
-// `@planetscale/database-js`` compatible SQL API
-pub fn mount() -> Router<Arc<AppState>> {
-    let pool = Arc::new(ConnectionPool {
-        connections: RwLock::new(HashMap::with_capacity(250)),
-        sessions: Default::default(),
-    });
+struct SessionHandle {
+    /// The username + database combo of the credentials which created the session.
+    owner: (String, String),
+    mode: SessionMode,
+    session: Arc<tokio::sync::Mutex<Session>>,
+}
 
-    Router::new()
-    .route(
-        "/Execute",
-post({
-    let pool = pool.clone();
-    move |State(state): State<Arc<AppState>>, TypedHeader(Authorization(auth)): TypedHeader<Authorization<Basic>>, Json(data): Json<SqlRequest>|
-        {
-            let pool = pool.clone();
-            async move {
-                // TODO: Check the credentials against the transaction session to avoid needing to create another connection that will just be dropped
-                let (mut conn, db) = authentication_and_get_db_conn(&pool, &state, auth).await?;
+struct Session {
+    /// The session's connection. For a transaction session the transaction is open on it.
+    /// This is `None` once the session has been committed, rolled back or expired.
+    conn: Option<Conn>,
+    /// The database the session's connection was opened against.
+    default_database: String,
+    /// The database the session has been switched to with `USE`, if it's not the default.
+    database: Option<String>,
+    /// The cached results the session's writes invalidate once they are committed.
+    invalidation: Invalidation,
+    /// The queries in the connection's statement cache, least recently used first.
+    prepared: Vec<String>,
+    created_at: Instant,
+    last_used: Instant,
+}
 
-                let start = std::time::Instant::now();
-                let mut session = None;
+impl Session {
+    fn new(conn: Conn, default_database: String, database: Option<String>) -> Self {
+        let now = Instant::now();
+        Self {
+            conn: Some(conn),
+            default_database,
+            database,
+            invalidation: Invalidation::None,
+            prepared: Vec::new(),
+            created_at: now,
+            last_used: now,
+        }
+    }
 
-                if data.query == "BEGIN" {
-                    let tx = db.start_transaction(TxOpts::default()).await.map_err(|err| {
-                        error!("Error starting DB transaction: {err}");
-                        error(format!("error starting DB transaction: {err:?}"))
-                    })?;
+    fn expiry(&self, now: Instant, timeouts: SessionTimeouts) -> Option<Expiry> {
+        if now.duration_since(self.created_at) >= timeouts.max_lifetime {
+            Some(Expiry::MaxLifetime(timeouts.max_lifetime))
+        } else if now.duration_since(self.last_used) >= timeouts.idle {
+            Some(Expiry::Idle(timeouts.idle))
+        } else {
+            None
+        }
+    }
+}
 
-                    let id = Uuid::new_v4();
-                    debug!("Creating new DB session {id:?}");
+#[derive(Debug, Clone, Copy)]
+struct SessionTimeouts {
+    idle: Duration,
+    max_lifetime: Duration,
+}
 
-                    {
-                        pool.sessions.write().await.insert(id, tx);
-                    }
+impl SessionTimeouts {
+    fn from_config(config: &Config) -> Self {
+        Self {
+            idle: Duration::from_secs(config.session_idle_timeout),
+            max_lifetime: Duration::from_secs(config.session_max_lifetime),
+        }
+    }
+}
 
-                    session = Some(TransactionSession {
-                        id
-                    });
+#[derive(Debug, Clone, Copy)]
+enum Expiry {
+    Idle(Duration),
+    MaxLifetime(Duration),
+    /// MySQL rolled back the transaction as it deadlocked with another transaction.
+    Deadlock,
+}
 
-                    // `BEGIN` is run by `db.start_transaction` so we don't actually wanna execute it
-                    return Ok(Json(json!({
-                        "session": session,
-                        "result": json!({}),
-                        "timing": start.elapsed().as_secs_f64(),
-                    })).into_response());
+impl fmt::Display for Expiry {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Idle(d) => write!(f, "idle for more than {}s", d.as_secs()),
+            Self::MaxLifetime(d) => write!(f, "open for more than {}s", d.as_secs()),
+            Self::Deadlock => write!(f, "rolled back due to a deadlock"),
+        }
+    }
+}
+
+/// A native MySQL connection which counts towards it's user's session limit while it's open.
+pub struct NativeConnection {
+    pool: Arc<ConnectionPool>,
+    username: String,
+}
+
+impl Drop for NativeConnection {
+    fn drop(&mut self) {
+        let mut connections = self
+            .pool
+            .native_connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner);
+        if let Some(count) = connections.get_mut(&self.username) {
+            *count -= 1;
+            if *count == 0 {
+                connections.remove(&self.username);
+            }
+        }
+    }
+}
+
+enum SessionError {
+    NotFound,
+    /// The session was created by a different username + database combo.
+    Forbidden,
+    Expired(Expiry),
+}
+
+impl ConnectionPool {
+    /// Forget the cached credentials of a MySQL user, so they are verified again on the next request.
+    /// This must be called when a user is dropped or their password is changed.
+    pub fn invalidate_user(&self, username: &str) {
+        self.credentials.invalidate(|u, _| u == username);
+    }
+
+    /// Forget the cached credentials of every user of a database.
+    pub fn invalidate_database(&self, database: &str) {
+        self.credentials.invalidate(|_, db| db == database);
+    }
+
+    /// Information about the connection pool of each username + database combo.
+    pub fn pool_stats(&self) -> Vec<PoolStats> {
+        self.credentials.stats()
+    }
+
+    /// The hit rate and size of the result cache.
+    pub fn cache_stats(&self) -> CacheStats {
+        self.cache.stats()
+    }
+
+    /// Invalidate the cached results a query run outside of the `psdb` API may have changed.
+    pub fn invalidate_cache(&self, database: &str, query: &str) {
+        self.cache
+            .invalidate(&Analysis::new(query, database).invalidation());
+    }
+
+    /// Remove every cached result.
+    pub fn clear_cache(&self) {
+        self.cache.invalidate(&Invalidation::All);
+    }
+
+    /// Check a set of credentials against MySQL by switching a connection to them with `COM_CHANGE_USER`.
+    ///
+    /// The same connection is reused so a request with new credentials doesn't open one just to check them.
+    /// It's closed if the switch fails as the server may have dropped it, and is reopened by the next check.
+    async fn verify_credentials(
+        &self,
+        opts: &mysql_async::Opts,
+        username: &str,
+        password: &str,
+        database: &str,
+    ) -> Result<(), mysql_async::Error> {
+        let change = ChangeUserOpts::new()
+            .with_user(Some(username.to_string()))
+            .with_pass(Some(password.to_string()))
+            .with_db_name(Some(database.to_string()));
+
+        let mut verifier = self.verifier.lock().await;
+        let mut reconnected = false;
+        loop {
+            let conn = match &mut *verifier {
+                Some(conn) => conn,
+                None => {
+                    reconnected = true;
+                    verifier.insert(Conn::new(opts.clone()).await?)
+                }
+            };
+            match conn.change_user(change.clone()).await {
+                Ok(()) => return Ok(()),
+                Err(err) => {
+                    *verifier = None;
+                    // The connection may have timed out while it was idle
+                    if reconnected || matches!(err, mysql_async::Error::Server(_)) {
+                        return Err(err);
+                    }
                 }
+            }
+        }
+    }
 
-                let (columns, values, rows_affected, last_insert_id) = if let Some(session) = data.session {
-                    // TODO: Can we only lock the specific session, not all of them while the DB query is running
-                    let mut sessions = pool.sessions.write().await;
+    /// Enforce the request rate limit of a MySQL user.
+    fn check_rate_limit(&self, config: &Config, username: &str) -> Result<(), VitessError> {
+        let Some(limit) = config
+            .users
+            .get(username)
+            .and_then(|user| user.max_requests_per_second)
+        else {
+            return Ok(());
+        };
 
-                    if data.query == "COMMIT" {
-                        let tx = sessions.remove(&session.id).ok_or_else(|| {
-                            debug!("Attempted to commit non-existent transaction {:?}", session.id);
-                            error(format!("error committing non-existent transaction {:?}", session.id))
-                        })?;
+        match self.rate_limiter.check(username, limit) {
+            true => Ok(()),
+            false => Err(VitessError::new(
+                Code::ResourceExhausted,
+                format!("user {username:?} exceeded it's limit of {limit} requests per second"),
+            )),
+        }
+    }
 
-                        tx.commit().await.map_err(|err| {
-                            error!("Error committing transaction: {err}");
-                            error(format!("error committing transaction {:?}: {err:?}", session.id))
-                        })?;
-                        debug!("COMMIT transaction {:?}", session.id);
+    /// Enforce the concurrent session limit of a MySQL user before opening a new session.
+    fn check_session_limit(&self, config: &Config, username: &str) -> Result<(), VitessError> {
+        let Some(limit) = config
+            .users
+            .get(username)
+            .and_then(|user| user.max_sessions)
+        else {
+            return Ok(());
+        };
 
-                        return Ok(Json(json!({
-                            "session": session,
-                            "result": json!({}),
-                            "timing": start.elapsed().as_secs_f64(),
-                        })).into_response());
-                    } else if data.query == "ROLLBACK" {
-                        let tx = sessions.remove(&session.id).ok_or_else(|| {
-                            debug!("Attempted to rollback non-existent transaction {:?}", session.id);
-                            error(format!("error rolling back non-existent transaction {:?}", session.id))
-                        })?;
+        let open = self
+            .sessions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .values()
+            .filter(|handle| handle.owner.0 == username)
+            .count()
+            + self
+                .native_connections
+                .lock()
+                .unwrap_or_else(PoisonError::into_inner)
+                .get(username)
+                .copied()
+                .unwrap_or_default();
+        match open < limit {
+            true => Ok(()),
+            false => Err(VitessError::new(
+                Code::ResourceExhausted,
+                format!("user {username:?} exceeded it's limit of {limit} open sessions"),
+            )),
+        }
+    }
 
-                        tx.rollback().await.map_err(|err| {
-                            error!("Error rolling back transaction: {err}");
-                            error(format!("error rolling back transaction {:?}: {err:?}", session.id))
-                        })?;
-                        debug!("ROLLBACK transaction {:?}", session.id);
+    /// Enforce the limits of a MySQL user on a native connection through the proxy.
+    ///
+    /// The connection is one request and counts as a session until the returned guard is dropped.
+    pub fn open_native_connection(
+        self: &Arc<Self>,
+        config: &Config,
+        username: &str,
+    ) -> Result<NativeConnection, String> {
+        self.check_rate_limit(config, username)
+            .and_then(|()| self.check_session_limit(config, username))
+            .map_err(|err| err.message)?;
 
-                        return Ok(Json(json!({
-                            "session": session,
-                            "result": json!({}),
-                            "timing": start.elapsed().as_secs_f64(),
-                        })).into_response());
+        *self
+            .native_connections
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .entry(username.to_string())
+            .or_default() += 1;
+        Ok(NativeConnection {
+            pool: self.clone(),
+            username: username.to_string(),
+        })
+    }
+
+    fn insert_session(
+        &self,
+        id: Uuid,
+        owner: (String, String),
+        mode: SessionMode,
+        conn: Conn,
+        database: Option<String>,
+    ) {
+        let session = Session::new(conn, owner.1.clone(), database);
+        self.sessions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(
+                id,
+                SessionHandle {
+                    owner,
+                    mode,
+                    session: Arc::new(tokio::sync::Mutex::new(session)),
+                },
+            );
+    }
+
+    /// Lock a session for exclusive use by the current request.
+    ///
+    /// Other sessions are unaffected so unrelated transactions can run in parallel.
+    /// If the session expired since the reaper last ran it will be rolled back.
+    async fn lock_session(
+        &self,
+        id: Uuid,
+        owner: &(String, String),
+        timeouts: SessionTimeouts,
+    ) -> Result<tokio::sync::OwnedMutexGuard<Session>, SessionError> {
+        let session = self
+            .sessions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&id)
+            .map(|handle| (handle.owner == *owner).then(|| handle.session.clone()));
+        let session = match session {
+            Some(Some(session)) => session,
+            Some(None) => return Err(SessionError::Forbidden),
+            None => {
+                return Err(self
+                    .expired_reason(&id)
+                    .map_or(SessionError::NotFound, SessionError::Expired))
+            }
+        };
+
+        let mut session = session.lock_owned().await;
+        if session.conn.is_none() {
+            // The session ended while we were waiting for the lock
+            return Err(self
+                .expired_reason(&id)
+                .map_or(SessionError::NotFound, SessionError::Expired));
+        }
+
+        if let Some(expiry) = session.expiry(Instant::now(), timeouts) {
+            self.expire_session(id, &mut session, expiry).await;
+            return Err(SessionError::Expired(expiry));
+        }
+
+        Ok(session)
+    }
+
+    /// Get the mode of a session, if it exists.
+    fn session_mode(&self, id: Uuid) -> Option<SessionMode> {
+        self.sessions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(&id)
+            .map(|handle| handle.mode)
+    }
+
+    /// End a session, removing it from the active set and returning it's connection so it's transaction can be committed or rolled back.
+    ///
+    /// If the session switched databases it's switched back, as the connection is returned to the pool once it ends.
+    /// The pool resets the connection's session variables and temporary tables itself.
+    async fn end_session(&self, id: Uuid, session: &mut Session) -> Option<Conn> {
+        self.sessions
+            .write()
+            .unwrap_or_else(PoisonError::into_inner)
+            .remove(&id);
+        let mut conn = session.conn.take()?;
+
+        if session.database.take().is_some() {
+            if let Err(err) = conn
+                .query_drop(database::use_statement(&session.default_database))
+                .await
+            {
+                warn!(
+                    "Error switching session {id:?} back to {:?}: {err}",
+                    session.default_database
+                );
+            }
+        }
+        Some(conn)
+    }
+
+    async fn expire_session(&self, id: Uuid, session: &mut Session, expiry: Expiry) {
+        debug!("Expiring DB session {id:?} as it was {expiry}");
+        self.expired
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .insert(id, (Instant::now(), expiry));
+
+        if let Some(mut conn) = self.end_session(id, session).await {
+            // A pinned connection may also be in a transaction the client started
+            if let Err(err) = conn.query_drop("ROLLBACK").await {
+                warn!("Error rolling back expired session {id:?}: {err}");
+            }
+        }
+    }
+
+    /// Get the reason a session which no longer exists was expired, if it was.
+    fn expired_reason(&self, id: &Uuid) -> Option<Expiry> {
+        self.expired
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(id)
+            .map(|(_, expiry)| *expiry)
+    }
+}
+
+/// Periodically rollback sessions which have outlived their timeouts.
+///
+/// This ensures a client which crashes mid-transaction doesn't hold it's locks and connection forever.
+async fn reap_sessions(state: Arc<AppState>) {
+    let pool = state.connections.clone();
+    let mut interval = tokio::time::interval(REAPER_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+
+    loop {
+        interval.tick().await;
+        let timeouts = SessionTimeouts::from_config(&state.config.get());
+        let now = Instant::now();
+
+        pool.credentials
+            .evict_expired(Duration::from_secs(state.config.get().credential_cache_ttl));
+        pool.rate_limiter.evict_idle();
+        pool.cache.evict_expired();
+
+        let sessions = pool
+            .sessions
+            .read()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|(id, handle)| (*id, handle.session.clone()))
+            .collect::<Vec<_>>();
+
+        for (id, session) in sessions {
+            // If the session is locked a query is running on it so it's not idle.
+            // We will check it again on the next tick.
+            let Ok(mut session) = session.try_lock_owned() else {
+                continue;
+            };
+
+            if let Some(expiry) = session.expiry(now, timeouts) {
+                pool.expire_session(id, &mut session, expiry).await;
+            }
+        }
+
+        pool.expired
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, (at, _)| now.duration_since(*at) < EXPIRED_SESSION_RETENTION);
+    }
+}
+
+// `@planetscale/database-js`` compatible SQL API
+pub fn mount(state: Arc<AppState>) -> Router<Arc<AppState>> {
+    let pool = state.connections.clone();
+
+    tokio::spawn(reap_sessions(state.clone()));
+
+    Router::new()
+    .route(
+        "/Execute",
+        post({
+            let pool = pool.clone();
+            move |State(state): State<Arc<AppState>>, TypedHeader(Authorization(auth)): TypedHeader<Authorization<Basic>>, headers: HeaderMap, Json(data): Json<SqlRequest>| {
+                execute(state, pool.clone(), auth, headers, data)
+            }
+        }),
+    )
+    .route(
+        "/ExecuteBatch",
+        post({
+            let pool = pool.clone();
+            move |State(state): State<Arc<AppState>>, TypedHeader(Authorization(auth)): TypedHeader<Authorization<Basic>>, headers: HeaderMap, Json(data): Json<BatchRequest>| {
+                let pool = pool.clone();
+                let killer = QueryKiller::new(state.db.clone());
+                killer.clone().detach(async move {
+                    let auth = authenticate(&pool, &state, auth).await?;
+                    let start = std::time::Instant::now();
+                    let preserve_fractional_seconds = state.config.get().preserve_fractional_seconds;
+                    let opts = QueryOptions {
+                        limits: ResultLimits::from_config(&state.config.get(), &auth.key.0),
+                        timeout: query_timeout(&state, &headers, &auth.key.0).map_err(IntoResponse::into_response)?,
+                        killer: &killer,
+                    };
+
+                    if let Some(query) = data.queries.iter().find(|q| matches!(q.query.as_str(), "BEGIN" | "COMMIT" | "ROLLBACK")) {
+                        return Err(error(Code::InvalidArgument, format!("{:?} is not supported in a batch, use 'asTransaction' or a session instead", query.query)));
+                    }
+                    if let Some(query) = data.queries.iter().find(|q| database::parse_use(&q.query).is_some()) {
+                        return Err(error(Code::InvalidArgument, format!("{:?} is not supported in a batch, use Execute instead", query.query)));
+                    }
+
+                    let invalidation = {
+                        let database = data.session.as_ref().and_then(|session| session.database.as_deref()).unwrap_or(&auth.key.1);
+                        let mut invalidation = Invalidation::None;
+                        for query in &data.queries {
+                            let analysis = Analysis::new(&query.query, database);
+                            check_protected(&state.config.get(), &analysis).map_err(IntoResponse::into_response)?;
+                            invalidation.merge(analysis.invalidation());
+                        }
+                        invalidation
+                    };
+
+                    let output = if let Some(id) = data.session.as_ref().and_then(|session| session.id) {
+                        if data.as_transaction {
+                            return Err(error(Code::InvalidArgument, "'asTransaction' can't be used with a session"));
+                        }
+
+                        let timeouts = SessionTimeouts::from_config(&state.config.get());
+                        let mut locked = pool
+                            .lock_session(id, &auth.key, timeouts)
+                            .await
+                            .map_err(|err| session_error(err, id, &auth.key))?;
+
+                        debug!("Executing batch of {} queries on session {id:?}", data.queries.len());
+                        let Session { conn, last_used, invalidation: writes, .. } = &mut *locked;
+                        let conn = conn.as_mut().expect("checked by 'lock_session'");
+                        let conn_id = conn.id();
+                        *last_used = Instant::now();
+                        let output = run_batch(conn, conn_id, data.queries, false, &auth.key, preserve_fractional_seconds, opts).await;
+                        *last_used = Instant::now();
+                        pool.cache.invalidate(&invalidation);
+                        writes.merge(invalidation);
+
+                        if output.deadlock && pool.session_mode(id) == Some(SessionMode::Transaction) {
+                            // MySQL has already rolled back the transaction so any further queries would run outside of it
+                            pool.expire_session(id, &mut locked, Expiry::Deadlock).await;
+                        }
+
+                        output
                     } else {
-                        debug!("Executing query {:?} on session {:?}", data.query, session.id);
-                        let tx = sessions.get_mut(&session.id).ok_or_else(|| {
-                            debug!("Attempted to getting non-existent transaction {:?}", session.id);
-                            error(format!("error getting non-existent transaction {:?}", session.id))
+                        debug!("Executing batch of {} queries", data.queries.len());
+                        let database = data.session.as_ref().and_then(|session| session.database.as_deref());
+                        let mut conn = SessionConn::new(auth.conn().await?, &auth.key.1);
+                        conn.switch(database).await.map_err(|err| {
+                            debug!("Error switching to database {database:?}: {err}");
+                            query_error(data.session.as_ref(), &err.into(), &auth.key, start)
                         })?;
+                        let conn_id = conn.id();
 
-                        let result = tx
-                            .exec_iter(&data.query, ())
-                            .await
-                            .map_err(|err| {
-                                error!("Error executing query against transaction {:?}: {err}", session.id);
-                                error(format!("error executing query: {err:?}"))
+                        let output = if data.as_transaction {
+                            let mut tx = conn.start_transaction(TxOpts::default()).await.map_err(|err| {
+                                error!("Error starting DB transaction: {err}");
+                                VitessError::from_mysql(&err, &auth.key).into_response()
                             })?;
 
-                        (result.columns(), result.collect_and_drop::<Row>().await, tx.affected_rows().to_string(), tx.last_insert_id().map(|v| v.to_string()))
+                            let output = run_batch(&mut tx, conn_id, data.queries, true, &auth.key, preserve_fractional_seconds, opts).await;
+                            if output.failed {
+                                if let Err(err) = tx.rollback().await {
+                                    warn!("Error rolling back batch transaction: {err}");
+                                }
+                            } else {
+                                let result = tx.commit().await;
+                                // Invalidated once the writes are visible so the old results can't be cached again
+                                pool.cache.invalidate(&invalidation);
+                                result.map_err(|err| {
+                                    error!("Error committing batch transaction: {err}");
+                                    query_error(None, &err.into(), &auth.key, start)
+                                })?;
+                            }
+
+                            output
+                        } else {
+                            let output = run_batch(&mut *conn, conn_id, data.queries, false, &auth.key, preserve_fractional_seconds, opts).await;
+                            pool.cache.invalidate(&invalidation);
+                            output
+                        };
+                        conn.release().await;
+                        output
+                    };
+
+                    Ok::<Response, Response>(Json(json!({
+                        "session": data.session,
+                        "results": output.results,
+                        "timing": start.elapsed().as_secs_f64(),
+                    })).into_response())
+                })
+            }
+        }),
+    )
+    .route(
+        "/StreamExecute",
+        post({
+            let pool = pool.clone();
+            // This is a Connect server-streaming RPC so the request is enveloped like the response.
+            // Errors before the query starts are returned like `Execute` does, everything after is in the end of the stream.
+            move |State(state): State<Arc<AppState>>, TypedHeader(Authorization(auth)): TypedHeader<Authorization<Basic>>, headers: HeaderMap, body: Bytes| {
+                let pool = pool.clone();
+                async move {
+                    let data = stream::decode_request::<SqlRequest>(&body).map_err(IntoResponse::into_response)?;
+                    let auth = authenticate(&pool, &state, auth).await?;
+
+                    if matches!(data.query.as_str(), "BEGIN" | "COMMIT" | "ROLLBACK") || database::parse_use(&data.query).is_some() {
+                        return Err(error(Code::InvalidArgument, format!("{:?} is not supported by StreamExecute, use Execute instead", data.query)));
                     }
-                } else {
-                    debug!("Executing query {:?}", data.query);
-                    let result =  conn
-                        .exec_iter(&data.query, ())
-                        .await
-                        .map_err(|err| {
-                            error!("Error executing query {:?}: {err}", session.as_ref().map(|s| s.id));
-                            error(format!("error executing query: {err:?}"))
-                        })?;
 
-                    (result.columns(), result.collect_and_drop::<Row>().await, conn.affected_rows().to_string(), conn.last_insert_id().map(|v| v.to_string()))
-                };
+                    let params = bind_vars::to_params(data.bind_vars).map_err(IntoResponse::into_response)?;
+                    let preserve_fractional_seconds = state.config.get().preserve_fractional_seconds;
+                    let timeout = query_timeout(&state, &headers, &auth.key.0).map_err(IntoResponse::into_response)?;
+                    let killer = QueryKiller::new(state.db.clone());
+                    let database = data.session.as_ref().and_then(|session| session.database.as_deref()).unwrap_or(&auth.key.1);
+                    let analysis = Analysis::new(&data.query, database);
+                    check_protected(&state.config.get(), &analysis).map_err(IntoResponse::into_response)?;
+                    let invalidation = analysis.invalidation();
 
-                let values = values.map_err(|err| {
-                    error!("Error getting values: {err}");
-                    error(format!("error decoding values: {err:?}"))
-                })?;
+                    if let Some(id) = data.session.as_ref().and_then(|session| session.id) {
+                        let timeouts = SessionTimeouts::from_config(&state.config.get());
+                        let mut locked = pool
+                            .lock_session(id, &auth.key, timeouts)
+                            .await
+                            .map_err(|err| session_error(err, id, &auth.key))?;
 
-                let fields = columns.as_deref()
-                    .unwrap_or(&[])
-                    .iter()
-                    .map(|col|
-                        json!({
-                            "name": col.name_str().to_string(),
-                            "type": column_type_to_str(&col),
-                            "charset": col.character_set(),
-                            "flags": col.flags().bits()
-                        })
-                    )
-                    .collect::<Vec<_>>();
-
-                let rows = values
-                    .into_iter()
-                    .map(|mut row| {
-                        let mut lengths = Vec::new();
-                        let mut values = Vec::new();
-
-                        for i in 0..row.len() {
-                            let Some(value) = row.take(i) else {
-                                continue;
-                            };
-
-                            let result = match value {
-                                Value::NULL => {
-                                    lengths.push(-1i64);
-                                    continue;
-                                },
-                                Value::Bytes(v) => {
-                                    lengths.push(v.len().try_into().expect("unable to cast usize to i64. How big are your damn pointers?"));
-                                    values.extend(v);
-                                    continue;
-                                },
-                                Value::Int(i) => i.to_string(),
-                                Value::UInt(i) => i.to_string(),
-                                Value::Float(i) => i.to_string(),
-                                Value::Double(i) => i.to_string(),
-                                // TODO: Planetscale seems to wipe out the fractional seconds, idk why but we are gonna copy for now.
-                                Value::Date(year, month, day, hour, minute, second, _) => {
-                                    if row.columns_ref()[i].column_type() == ColumnType::MYSQL_TYPE_DATE {
-                                        format!("{:04}-{:02}-{:02}", year, month, day)
-                                    } else {
-                                        format!("{:04}-{:02}-{:02} {:02}:{:02}:{:02}", year, month, day, hour, minute, second)
-                                    }
-                                },
-                                // TODO: Planetscale seems to wipe out the fractional seconds, idk why but we are gonna copy for now.
-                                Value::Time(neg, d, h, i, s, _) => {
-                                    if neg {
-                                        format!("-{:02}:{:02}:{:02}", d * 24 + u32::from(h), i, s)
-                                    } else {
-                                        format!("{:02}:{:02}:{:02}", d * 24 + u32::from(h), i, s)
-                                    }
-                                }
-                            };
+                        debug!("Streaming query {:?} on session {id:?}", data.query);
+                        let (sender, response) = stream::Sender::new(data.session, preserve_fractional_seconds);
+                        // The session stays locked until the whole result has been sent
+                        tokio::spawn(async move {
+                            let Session { conn, last_used, invalidation: writes, .. } = &mut *locked;
+                            let conn = conn.as_mut().expect("checked by 'lock_session'");
+                            let conn_id = conn.id();
+                            *last_used = Instant::now();
+                            let result = killer
+                                .run(conn_id, timeout, stream::run_query(conn, &data.query, params, &sender, &killer))
+                                .await;
+                            *last_used = Instant::now();
+                            pool.cache.invalidate(&invalidation);
+                            writes.merge(invalidation);
 
-                            lengths.push(result.len().try_into().expect("unable to cast usize to i64. How big are your damn pointers?"));
-                            values.extend(result.as_bytes());
-                        }
+                            let err = result.err().map(|err| {
+                                debug!("Error streaming query against session {id:?}: {err}");
+                                err
+                            });
+                            if err.as_ref().is_some_and(QueryError::is_deadlock) && pool.session_mode(id) == Some(SessionMode::Transaction) {
+                                // MySQL has already rolled back the transaction so any further queries would run outside of it
+                                pool.expire_session(id, &mut locked, Expiry::Deadlock).await;
+                            }
+                            sender.end(err.map(|err| err.to_vitess(&auth.key))).await;
+                        });
 
-                    json!({
-                        "lengths": lengths,
-                        "values": STANDARD.encode(values),
-                    })
-                })
-                .collect::<Vec<_>>();
-
-                Ok::<Response, Response>(Json(json!({
-                    "session": session,
-                    "result": json!({
-                        "rowsAffected": rows_affected,
-                        "insertId": last_insert_id,
-                        "fields": fields,
-                        "rows": rows,
-                    }),
-                    "timing": start.elapsed().as_secs_f64(),
-                })).into_response())
-        }}})
+                        Ok::<Response, Response>(response)
+                    } else {
+                        debug!("Streaming query {:?}", data.query);
+                        let database = data.session.as_ref().and_then(|session| session.database.as_deref());
+                        let mut conn = SessionConn::new(auth.conn().await?, &auth.key.1);
+                        conn.switch(database).await.map_err(|err| {
+                            debug!("Error switching to database {database:?}: {err}");
+                            VitessError::from_mysql(&err, &auth.key).into_response()
+                        })?;
+                        let (sender, response) = stream::Sender::new(data.session, preserve_fractional_seconds);
+                        tokio::spawn(async move {
+                            let conn_id = conn.id();
+                            let result = killer
+                                .run(conn_id, timeout, stream::run_query(&mut *conn, &data.query, params, &sender, &killer))
+                                .await;
+                            pool.cache.invalidate(&invalidation);
+                            let err = result.err().map(|err| {
+                                debug!("Error streaming query: {err}");
+                                err.to_vitess(&auth.key)
+                            });
+                            if err.is_none() {
+                                conn.release().await;
+                            }
+                            sender.end(err).await;
+                        });
+
+                        Ok(response)
+                    }
+                }
+            }
+        }),
+    )
+    .route(
+        "/Socket",
+        get({
+            let pool = pool.clone();
+            // Browsers can't set the `Authorization` header of a WebSocket so the credentials can also be sent in the first frame.
+            move |State(state): State<Arc<AppState>>, auth: Option<TypedHeader<Authorization<Basic>>>, headers: HeaderMap, ws: WebSocketUpgrade| {
+                let pool = pool.clone();
+                async move {
+                    let auth = auth.map(|TypedHeader(Authorization(auth))| auth);
+                    if let Some(auth) = &auth {
+                        authenticate(&pool, &state, auth.clone()).await?;
+                    }
+
+                    Ok::<Response, Response>(ws.on_upgrade(move |socket| socket::serve(socket, state, pool, auth, headers)))
+                }
+            }
+        }),
     )
     .route(
         "/CreateSession",
-        post(move |State(state): State<Arc<AppState>>, TypedHeader(Authorization(auth)): TypedHeader<Authorization<Basic>>| {
+        // The body is optional as `database-js` sends an empty object, so the mode defaults to a transaction like PlanetScale.
+        post(move |State(state): State<Arc<AppState>>, TypedHeader(Authorization(auth)): TypedHeader<Authorization<Basic>>, body: Bytes| {
             let pool = pool.clone();
             async move {
-                let (conn, db) = match authentication_and_get_db_conn(&pool, &state, auth).await {
-                    Ok(conn) => conn,
+                let data = match body.is_empty() {
+                    true => CreateSessionRequest::default(),
+                    false => match serde_json::from_slice::<CreateSessionRequest>(&body) {
+                        Ok(data) => data,
+                        Err(err) => return error(Code::InvalidArgument, format!("invalid request: {err}")),
+                    },
+                };
+                let auth = match authenticate(&pool, &state, auth).await {
+                    Ok(auth) => auth,
                     Err(res) => return res,
                 };
-                drop(conn); // TODO: As we use connections for auth creating this is kinda required to cache the credentials (and reducing load on the DB). We can probs workaround this to make this endpoint *wayy* faster in the future.
+                if let Err(err) = pool.check_session_limit(&state.config.get(), &auth.key.0) {
+                    return err.into_response();
+                }
 
-                let Ok(tx) = db.start_transaction(TxOpts::default()).await.map_err(|err| error!("Error starting DB transaction: {err}")) else {
-                    return (StatusCode::INTERNAL_SERVER_ERROR, "Internal Server Error").into_response();
+                let mut conn = match auth.conn().await {
+                    Ok(conn) => conn,
+                    Err(res) => return res,
                 };
+                if data.mode == SessionMode::Transaction {
+                    if let Err(err) = conn.query_drop("START TRANSACTION").await {
+                        error!("Error starting DB transaction: {err}");
+                        return VitessError::from_mysql(&err, &auth.key).into_response();
+                    }
+                }
 
                 let id = Uuid::new_v4();
-                debug!("Creating new DB session {id:?}");
+                debug!("Creating new DB session {id:?} ({:?})", data.mode);
 
-                {
-                    pool.sessions.write().await.insert(id, tx);
-                }
+                pool.insert_session(id, auth.key, data.mode, conn, None);
 
                 Json(json!({
                     "session": TransactionSession {
-                        id,
+                        id: Some(id),
+                        database: None,
                     }
                 })).into_response()
             }
         }),
     )
+    .layer(middleware::from_fn(proto::transcode))
+    // Outside of `transcode` so preflight requests are answered before it
+    .layer(middleware::from_fn_with_state(state, cors::cors))
 }
 
-async fn authentication_and_get_db_conn(
+/// Execute a query from `/Execute` or a WebSocket frame.
+async fn execute(
+    state: Arc<AppState>,
+    pool: Arc<ConnectionPool>,
+    auth: Basic,
+    headers: HeaderMap,
+    data: SqlRequest,
+) -> Result<Response, Response> {
+    let killer = QueryKiller::new(state.db.clone());
+    killer
+        .clone()
+        .detach(async move {
+            let auth = authenticate(&pool, &state, auth).await?;
+
+            let start = std::time::Instant::now();
+
+            let mut session = data.session.unwrap_or_default();
+            let use_database = database::parse_use(&data.query);
+            // Transaction control statements are passed through to a pinned connection
+            let mode = session.id.and_then(|id| pool.session_mode(id));
+            let is_transaction = mode != Some(SessionMode::Connection);
+
+            if data.query == "BEGIN" && is_transaction {
+                pool.check_session_limit(&state.config.get(), &auth.key.0)
+                    .map_err(IntoResponse::into_response)?;
+                let mut conn = auth.conn().await?;
+                conn.query_drop("START TRANSACTION").await.map_err(|err| {
+                    error!("Error starting DB transaction: {err}");
+                    VitessError::from_mysql(&err, &auth.key).into_response()
+                })?;
+                if let Some(database) = &session.database {
+                    conn.query_drop(database::use_statement(database))
+                        .await
+                        .map_err(|err| {
+                            debug!("Error switching new transaction to {database:?}: {err}");
+                            query_error(Some(&session), &err.into(), &auth.key, start)
+                        })?;
+                }
+
+                let id = Uuid::new_v4();
+                debug!("Creating new DB session {id:?}");
+
+                pool.insert_session(
+                    id,
+                    auth.key,
+                    SessionMode::Transaction,
+                    conn,
+                    session.database.clone(),
+                );
+
+                session.id = Some(id);
+                return Ok(Json(json!({
+                    "session": session.non_empty(),
+                    "result": json!({}),
+                    "timing": start.elapsed().as_secs_f64(),
+                }))
+                .into_response());
+            }
+
+            let analysis = Analysis::new(
+                &data.query,
+                session.database.as_deref().unwrap_or(&auth.key.1),
+            );
+            check_protected(&state.config.get(), &analysis).map_err(IntoResponse::into_response)?;
+            // Only results outside of a session are cached as a session can see it's own uncommitted writes
+            let cache = match analysis.ttl(state.config.get().result_cache_ttl(&auth.key.0)) {
+                Some(ttl) if session.id.is_none() => {
+                    let key = cache::Key::new(
+                        &auth.key.0,
+                        session.database.as_deref().unwrap_or(&auth.key.1),
+                        &data.query,
+                        &data.bind_vars,
+                    );
+                    if let Some(result) = pool.cache.get(&key) {
+                        debug!("Returning cached result of query {:?}", data.query);
+                        return Ok(Json(json!({
+                            "session": session.non_empty(),
+                            "result": result,
+                            "timing": start.elapsed().as_secs_f64(),
+                        }))
+                        .into_response());
+                    }
+                    Some((key, ttl, pool.cache.generation()))
+                }
+                _ => None,
+            };
+
+            let params =
+                bind_vars::to_params(data.bind_vars).map_err(IntoResponse::into_response)?;
+            let opts = QueryOptions {
+                limits: ResultLimits::from_config(&state.config.get(), &auth.key.0),
+                timeout: query_timeout(&state, &headers, &auth.key.0)
+                    .map_err(IntoResponse::into_response)?,
+                killer: &killer,
+            };
+            let result = if let Some(id) = session.id {
+                let timeouts = SessionTimeouts::from_config(&state.config.get());
+                let mut locked = match pool.lock_session(id, &auth.key, timeouts).await {
+                    Ok(locked) => locked,
+                    Err(SessionError::Expired(expiry)) if data.query == "ROLLBACK" => {
+                        // The transaction was already rolled back so we are done
+                        debug!("ROLLBACK expired transaction {id:?} ({expiry})");
+                        session.id = None;
+                        return Ok(Json(json!({
+                            "session": session.non_empty(),
+                            "result": json!({}),
+                            "timing": start.elapsed().as_secs_f64(),
+                        }))
+                        .into_response());
+                    }
+                    Err(err) => return Err(session_error(err, id, &auth.key)),
+                };
+
+                if data.query == "COMMIT" && is_transaction {
+                    let mut conn = pool
+                        .end_session(id, &mut locked)
+                        .await
+                        .expect("checked by 'lock_session'");
+                    conn.query_drop("COMMIT").await.map_err(|err| {
+                        error!("Error committing transaction {id:?}: {err}");
+                        query_error(Some(&session), &err.into(), &auth.key, start)
+                    })?;
+                    debug!("COMMIT transaction {id:?}");
+                    pool.cache
+                        .invalidate(&std::mem::take(&mut locked.invalidation));
+
+                    session.id = None;
+                    return Ok(Json(json!({
+                        "session": session.non_empty(),
+                        "result": json!({}),
+                        "timing": start.elapsed().as_secs_f64(),
+                    }))
+                    .into_response());
+                } else if data.query == "ROLLBACK" && is_transaction {
+                    let mut conn = pool
+                        .end_session(id, &mut locked)
+                        .await
+                        .expect("checked by 'lock_session'");
+                    conn.query_drop("ROLLBACK").await.map_err(|err| {
+                        error!("Error rolling back transaction {id:?}: {err}");
+                        query_error(Some(&session), &err.into(), &auth.key, start)
+                    })?;
+                    debug!("ROLLBACK transaction {id:?}");
+
+                    session.id = None;
+                    return Ok(Json(json!({
+                        "session": session.non_empty(),
+                        "result": json!({}),
+                        "timing": start.elapsed().as_secs_f64(),
+                    }))
+                    .into_response());
+                } else {
+                    debug!("Executing query {:?} on session {id:?}", data.query);
+                    let Session {
+                        conn,
+                        last_used,
+                        default_database,
+                        database,
+                        invalidation,
+                        prepared,
+                        ..
+                    } = &mut *locked;
+                    let conn = conn.as_mut().expect("checked by 'lock_session'");
+                    let conn_id = conn.id();
+                    *last_used = Instant::now();
+
+                    if !matches!(params, Params::Empty) {
+                        // This mirrors the statement cache, which evicts the least recently used statement
+                        prepared.retain(|query| *query != data.query);
+                        prepared.push(data.query.clone());
+                        let evicted = prepared
+                            .len()
+                            .saturating_sub(state.config.get().statement_cache_size);
+                        prepared.drain(..evicted);
+                    }
+                    let result = run_query(conn, conn_id, &data.query, params, opts).await;
+                    *last_used = Instant::now();
+
+                    let writes = analysis.invalidation();
+                    pool.cache.invalidate(&writes);
+                    invalidation.merge(writes);
+                    // A pinned connection's transactions are committed by an ordinary query
+                    if !is_transaction && data.query == "COMMIT" {
+                        pool.cache.invalidate(&std::mem::take(invalidation));
+                    }
+
+                    match result {
+                        Ok(result) => {
+                            if let Some(db) = use_database {
+                                // A statement keeps using the database it was prepared in
+                                close_statements(conn, std::mem::take(prepared)).await;
+                                debug!("Switched session {id:?} to database {db:?}");
+                                *database = (db != *default_database).then_some(db);
+                            }
+                            // The session's current database is the source of truth
+                            session.database = database.clone();
+                            result
+                        }
+                        Err(err) => {
+                            debug!("Error executing query against session {id:?}: {err}");
+                            if err.is_deadlock() && is_transaction {
+                                // MySQL has already rolled back the transaction so any further queries would run outside of it
+                                pool.expire_session(id, &mut locked, Expiry::Deadlock).await;
+                            }
+                            return Err(query_error(Some(&session), &err, &auth.key, start));
+                        }
+                    }
+                }
+            } else if let Some(db) = use_database {
+                // Outside of a transaction the client holds the current database and we switch to it for each request
+                let mut conn = SessionConn::new(auth.conn().await?, &auth.key.1);
+                conn.switch(Some(&db)).await.map_err(|err| {
+                    debug!("Error switching to database {db:?}: {err}");
+                    query_error(Some(&session), &err.into(), &auth.key, start)
+                })?;
+                conn.release().await;
+                debug!("Switched session to database {db:?}");
+
+                session.database = (db != auth.key.1).then_some(db);
+                return Ok(Json(json!({
+                    "session": session.non_empty(),
+                    "result": json!({}),
+                    "timing": start.elapsed().as_secs_f64(),
+                }))
+                .into_response());
+            } else {
+                debug!("Executing query {:?}", data.query);
+                let mut conn = SessionConn::new(auth.conn().await?, &auth.key.1);
+                conn.switch(session.database.as_deref())
+                    .await
+                    .map_err(|err| {
+                        debug!("Error switching to database {:?}: {err}", session.database);
+                        query_error(Some(&session), &err.into(), &auth.key, start)
+                    })?;
+                let conn_id = conn.id();
+                let result = run_query(&mut *conn, conn_id, &data.query, params, opts).await;
+                pool.cache.invalidate(&analysis.invalidation());
+                let result = result.map_err(|err| {
+                    debug!("Error executing query: {err}");
+                    query_error(Some(&session), &err, &auth.key, start)
+                })?;
+                conn.release().await;
+                result
+            };
+
+            let preserve_fractional_seconds = state.config.get().preserve_fractional_seconds;
+            let result = query_result(result, preserve_fractional_seconds);
+            if let Some((key, ttl, generation)) = cache {
+                let max_size = state.config.get().result_cache_size;
+                pool.cache
+                    .insert(key, &analysis, result.clone(), ttl, generation, max_size);
+            }
+            Ok::<Response, Response>(
+                Json(json!({
+                    "session": session.non_empty(),
+                    "result": result,
+                    "timing": start.elapsed().as_secs_f64(),
+                }))
+                .into_response(),
+            )
+        })
+        .await
+}
+
+/// The result of successfully authenticating a request.
+struct Authenticated {
+    /// The username + database combo the credentials resolved to.
+    key: (String, String),
+    db: Pool,
+}
+
+impl Authenticated {
+    /// Get a connection to the user's database.
+    async fn conn(&self) -> Result<Conn, Response> {
+        self.db.get_conn().await.map_err(|err| {
+            error!("Error getting DB connection: {err}");
+            error(Code::Unavailable, "error retrieving database connection")
+        })
+    }
+}
+
+/// Reject DDL against a protected database.
+fn check_protected(config: &Config, analysis: &Analysis) -> Result<(), VitessError> {
+    match protected_error(config, analysis) {
+        Some(message) => Err(VitessError::new(Code::PermissionDenied, message)),
+        None => Ok(()),
+    }
+}
+
+/// Reject DDL against a protected database from the dashboard, returning the error message.
+pub fn check_protected_query(config: &Config, query: &str, database: &str) -> Result<(), String> {
+    match protected_error(config, &Analysis::new(query, database)) {
+        Some(message) => Err(message),
+        None => Ok(()),
+    }
+}
+
+/// The error for a query which changes the schema of a protected database.
+fn protected_error(config: &Config, analysis: &Analysis) -> Option<String> {
+    let database = config
+        .protected_databases()
+        .find(|database| analysis.changes_schema(database))?;
+    Some(format!("Database {database:?} is protected, schema changes must be made on a branch and deployed with a deploy request"))
+}
+
+/// Authenticate a request and enforce the user's rate limit.
+async fn authenticate(
     pool: &ConnectionPool,
     state: &AppState,
     auth: Basic,
-) -> Result<(Conn, Pool), Response> {
+) -> Result<Authenticated, Response> {
     let Some((username, database)) = auth.username().split_once("%3B") else {
-        return Err((
-            StatusCode::BAD_REQUEST,
-            "Invalid username. Must be in form 'username;db'",
-        )
-            .into_response());
+        return Err(error(
+            Code::InvalidArgument,
+            "invalid username. Must be in form 'username;db'",
+        ));
     };
     let password = SecStr::from(auth.password());
 
     let key = (username.to_string(), database.to_string());
-    let result = pool
-        .connections
-        .read()
-        .unwrap_or_else(PoisonError::into_inner)
-        .get(&key)
-        .cloned();
-
-    Ok(if let Some((actual_password, db)) = result {
-        // TODO: If the user were to change password this would fail.
-        // TODO: Can we list to the biglog and invalidate the cache when users are modified???
-        if actual_password != password {
-            return Err(StatusCode::UNAUTHORIZED.into_response());
+    let (ttl, capacity) = {
+        let config = state.config.get();
+        (
+            Duration::from_secs(config.credential_cache_ttl),
+            config.credential_cache_size,
+        )
+    };
+
+    // If the password doesn't match it may have been changed so we check it against MySQL
+    if let Some((_, db)) = pool
+        .credentials
+        .get(&key, ttl)
+        .filter(|(actual_password, _)| *actual_password == password)
+    {
+        // This is after the credentials are verified so other clients can't use up a user's limit
+        pool.check_rate_limit(&state.config.get(), username)
+            .map_err(IntoResponse::into_response)?;
+        return Ok(Authenticated { key, db });
+    }
+
+    // Checking against MySQL is expensive, so attempts with a wrong password count towards the limit too
+    pool.check_rate_limit(&state.config.get(), username)
+        .map_err(IntoResponse::into_response)?;
+    if let Some(errno) = pool.credentials.rejected(&key, &password) {
+        return Err(rejected(errno, database));
+    }
+
+    match pool
+        .verify_credentials(&state.db_opts, username, auth.password(), database)
+        .await
+    {
+        Ok(()) => {
+            let db = mysql_async::Pool::new(
+                OptsBuilder::from_opts(state.db_opts.clone())
+                    .user(Some(username))
+                    .pass(Some(auth.password()))
+                    .db_name(Some(database)) // TODO: This will cause issues - https://github.com/oscartbeaumont/cityscale/issues/23
+                    // Only statements with bind variables are prepared, see `run_query`.
+                    // Connections are reset when they are returned to the pool which closes their statements,
+                    // so one prepared after switching database isn't reused. Sessions close them on `USE`.
+                    .stmt_cache_size(state.config.get().statement_cache_size),
+            );
+            pool.credentials
+                .insert(key.clone(), password, db.clone(), capacity);
+            Ok(Authenticated { key, db })
+        }
+        Err(mysql_async::Error::Server(err)) if matches!(err.code, 1045 | 1049) => {
+            pool.credentials.reject(key, password, err.code, capacity);
+            Err(rejected(err.code, database))
+        }
+        Err(err) => {
+            error!("Error verifying credentials: {err}");
+            Err(error(
+                Code::Unavailable,
+                format!("error retrieving database connection: {err}"),
+            ))
         }
+    }
+}
 
-        let conn = db.get_conn().await.map_err(|err| {
-            error!("Error getting DB connection: {err}");
-            error(format!("error retrieving database connection"))
-        })?;
+/// The error for credentials MySQL rejected with `errno`.
+fn rejected(errno: u16, database: &str) -> Response {
+    match errno {
+        1049 => error(Code::NotFound, format!("unknown database {database:?}. Ensure your connection URI contains a valid database name.")),
+        _ => error(Code::Unauthenticated, "invalid auth credentials"),
+    }
+}
 
-        (conn, db)
-    } else {
-        let db = mysql_async::Pool::new(
-            OptsBuilder::from_opts(state.db_opts.clone())
-                .user(Some(username))
-                .pass(Some(auth.password()))
-                .db_name(Some(database)) // TODO: This will cause issues - https://github.com/oscartbeaumont/cityscale/issues/23
-                .stmt_cache_size(0), // TODO: Should we renable this? It breaks transactions
-        );
+/// The maximum size of a result returned by `Execute`, to avoid running out of memory buffering it.
+#[derive(Debug, Clone, Copy)]
+struct ResultLimits {
+    max_rows: usize,
+    max_bytes: usize,
+}
 
-        match db.get_conn().await {
-            Ok(conn) => {
-                pool.connections
-                    .write()
-                    .unwrap_or_else(PoisonError::into_inner)
-                    .insert(key, (password, db.clone()));
-                (conn, db)
-            }
-            Err(mysql_async::Error::Server(err)) if err.code == 1045 => {
-                return Err(StatusCode::UNAUTHORIZED.into_response());
-            }
-            Err(mysql_async::Error::Server(err)) if err.code == 1049 => {
-                return Err(error(format!("unknown database {database:?}. Ensure your connection URI contains a valid database name.")));
+impl ResultLimits {
+    fn from_config(config: &Config, username: &str) -> Self {
+        Self {
+            max_rows: config.max_result_rows(username),
+            max_bytes: config.max_result_bytes,
+        }
+    }
+}
+
+/// An upper bound on the size of a row once it's encoded.
+fn row_size(row: &Row) -> usize {
+    (0..row.len())
+        .map(|i| match row.as_ref(i) {
+            None | Some(Value::NULL) => 0,
+            Some(Value::Bytes(v)) => v.len(),
+            // `-18446744073709551615` or `-1.7976931348623157e308`
+            Some(Value::Int(_) | Value::UInt(_) | Value::Float(_) | Value::Double(_)) => 24,
+            // `YYYY-MM-DD HH:MM:SS.ffffff` or `-DDDD:MM:SS.ffffff`
+            Some(Value::Date(..) | Value::Time(..)) => 26,
+        })
+        .sum()
+}
+
+/// An error from running a query.
+#[derive(Debug)]
+enum QueryError {
+    Mysql(mysql_async::Error),
+    /// The result exceeded the configured [`ResultLimits`].
+    TooLarge(String),
+    /// The query was killed as it timed out or the request was canceled.
+    Interrupted(Interrupted),
+}
+
+impl From<mysql_async::Error> for QueryError {
+    fn from(err: mysql_async::Error) -> Self {
+        Self::Mysql(err)
+    }
+}
+
+impl From<Interrupted> for QueryError {
+    fn from(err: Interrupted) -> Self {
+        Self::Interrupted(err)
+    }
+}
+
+impl fmt::Display for QueryError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Self::Mysql(err) => write!(f, "{err}"),
+            Self::TooLarge(msg) => write!(f, "{msg}"),
+            Self::Interrupted(err) => write!(f, "{err}"),
+        }
+    }
+}
+
+impl QueryError {
+    /// MySQL rolls back the entire transaction when it's picked as the victim of a deadlock.
+    fn is_deadlock(&self) -> bool {
+        matches!(self, Self::Mysql(mysql_async::Error::Server(err)) if err.code == 1213)
+    }
+
+    /// Is this an error from the query itself rather than the connection?
+    fn is_query_error(&self) -> bool {
+        match self {
+            Self::Mysql(err) => VitessError::is_query_error(err),
+            Self::TooLarge(_) | Self::Interrupted(_) => true,
+        }
+    }
+
+    fn to_vitess(&self, key: &(String, String)) -> VitessError {
+        match self {
+            Self::Mysql(err) => VitessError::from_mysql(err, key),
+            Self::TooLarge(msg) => VitessError::new(Code::ResourceExhausted, msg.clone()),
+            Self::Interrupted(err @ Interrupted::TimedOut(_)) => {
+                VitessError::new(Code::DeadlineExceeded, err.to_string())
             }
-            Err(err) => {
-                error!("Error getting DB connection to new pool: {err}");
-                return Err(error(format!(
-                    "error retrieving database connection: {err}"
-                )));
+            Self::Interrupted(err @ Interrupted::Canceled) => {
+                VitessError::new(Code::Canceled, err.to_string())
             }
         }
+    }
+}
+
+/// How the queries of a request are run.
+#[derive(Clone, Copy)]
+struct QueryOptions<'a> {
+    limits: ResultLimits,
+    timeout: Option<Duration>,
+    killer: &'a QueryKiller,
+}
+
+/// The result of running a single query.
+struct QueryOutput {
+    columns: Option<Arc<[Column]>>,
+    rows: Vec<Row>,
+    rows_affected: u64,
+    last_insert_id: Option<u64>,
+}
+
+/// Close the cached statements of queries so they are prepared again when they are next run.
+async fn close_statements(conn: &mut Conn, queries: Vec<String>) {
+    for query in queries {
+        let result = async {
+            let statement = conn.prep(&query).await?;
+            conn.close(statement).await
+        };
+        if let Err(err) = result.await {
+            warn!("Error closing statement {query:?}: {err}");
+        }
+    }
+}
+
+/// Run a query on a connection or transaction.
+///
+/// Queries are only prepared when they have bind variables.
+/// Everything else uses the text protocol as `database-js` interpolates values on the client, so the query text is rarely reused,
+/// and some statements can't be prepared at all (Eg. `SAVEPOINT`).
+///
+/// Prepared statements are only reused within a session, which holds onto it's connection.
+/// Other requests get a connection from the pool, which closes it's statements when it's reset on being returned.
+///
+/// The query is killed if it runs for longer than the timeout or the request is canceled, `conn_id` must be the ID of `conn`.
+async fn run_query(
+    conn: &mut impl Queryable,
+    conn_id: u32,
+    query: &str,
+    params: Params,
+    opts: QueryOptions<'_>,
+) -> Result<QueryOutput, QueryError> {
+    let result = async {
+        if let Params::Empty = params {
+            collect_result(conn.query_iter(query).await?, opts.limits).await
+        } else {
+            collect_result(conn.exec_iter(query, params).await?, opts.limits).await
+        }
+    };
+
+    opts.killer.run(conn_id, opts.timeout, result).await
+}
+
+async fn collect_result<P: Protocol>(
+    mut result: QueryResult<'_, 'static, P>,
+    limits: ResultLimits,
+) -> Result<QueryOutput, QueryError> {
+    let columns = result.columns();
+
+    let mut rows = Vec::new();
+    let mut size = 0;
+    while let Some(row) = result.next().await? {
+        size += row_size(&row);
+
+        let exceeded = if rows.len() >= limits.max_rows {
+            Some(format!("Row count exceeded {}", limits.max_rows))
+        } else if size > limits.max_bytes {
+            Some(format!("Result size exceeded {} bytes", limits.max_bytes))
+        } else {
+            None
+        };
+        if let Some(msg) = exceeded {
+            // Read the rest of the result so the connection can be used again
+            result.drop_result().await?;
+            return Err(QueryError::TooLarge(format!(
+                "{msg}, use StreamExecute for large results"
+            )));
+        }
+
+        rows.push(row);
+    }
+
+    let rows_affected = result.affected_rows();
+    let last_insert_id = result.last_insert_id();
+    result.drop_result().await?;
+
+    Ok(QueryOutput {
+        columns,
+        rows,
+        rows_affected,
+        last_insert_id,
     })
 }
 
-fn error(msg: String) -> Response {
-    (
-        StatusCode::INTERNAL_SERVER_ERROR,
-        Json(json!({
-            "error": {
-                "message": msg,
+/// The outcome of running a batch of queries.
+struct BatchOutput {
+    /// The result or error of each query, in the order they were given.
+    results: Vec<serde_json::Value>,
+    /// Did any of the queries fail?
+    failed: bool,
+    /// Did a query deadlock, causing MySQL to rollback the transaction it was in?
+    deadlock: bool,
+}
+
+/// Run each query in a batch in order.
+///
+/// When `stop_on_error` is set (Eg. the batch is a transaction) the queries after a failure are not run.
+/// The batch is always stopped if the connection fails or deadlocks as the remaining queries can't run correctly.
+async fn run_batch(
+    conn: &mut impl Queryable,
+    conn_id: u32,
+    queries: Vec<BatchQuery>,
+    stop_on_error: bool,
+    key: &(String, String),
+    preserve_fractional_seconds: bool,
+    opts: QueryOptions<'_>,
+) -> BatchOutput {
+    let mut output = BatchOutput {
+        results: Vec::with_capacity(queries.len()),
+        failed: false,
+        deadlock: false,
+    };
+
+    let mut stopped = false;
+    for query in queries {
+        if stopped {
+            output.results.push(json!({
+                "error": VitessError::new(Code::Aborted, "query was not executed as an earlier query in the batch failed"),
+            }));
+            continue;
+        }
+
+        let result = match bind_vars::to_params(query.bind_vars) {
+            Ok(params) => run_query(conn, conn_id, &query.query, params, opts)
+                .await
+                .map_err(|err| {
+                    output.deadlock |= err.is_deadlock();
+                    let fatal = !err.is_query_error() || err.is_deadlock();
+                    (err.to_vitess(key), fatal)
+                }),
+            Err(err) => Err((err, false)),
+        };
+
+        match result {
+            Ok(result) => output.results.push(json!({
+                "result": query_result(result, preserve_fractional_seconds),
+            })),
+            Err((err, fatal)) => {
+                debug!("Error executing query in batch: {}", err.message);
+                output.failed = true;
+                stopped = fatal || stop_on_error;
+                output.results.push(json!({ "error": err }));
             }
-        })),
+        }
+    }
+
+    output
+}
+
+/// Convert the output of a query into a Vitess `QueryResult`.
+fn query_result(output: QueryOutput, preserve_fractional_seconds: bool) -> VitessResult {
+    let fields = output
+        .columns
+        .as_deref()
+        .unwrap_or(&[])
+        .iter()
+        .map(field)
+        .collect::<Vec<_>>();
+
+    let rows = output
+        .rows
+        .into_iter()
+        .map(|row| encode_row(row, preserve_fractional_seconds))
+        .collect::<Vec<_>>();
+
+    VitessResult {
+        rows_affected: output.rows_affected,
+        insert_id: output.last_insert_id,
+        fields,
+        rows,
+    }
+}
+
+/// The query timeout of a request, see [`cancel::timeout`].
+fn query_timeout(
+    state: &AppState,
+    headers: &HeaderMap,
+    username: &str,
+) -> Result<Option<Duration>, VitessError> {
+    cancel::timeout(headers, state.config.get().query_timeout(username))
+        .map_err(|message| VitessError::new(Code::InvalidArgument, message))
+}
+
+fn error(code: Code, msg: impl Into<String>) -> Response {
+    VitessError::new(code, msg).into_response()
+}
+
+/// Respond with an error from executing a query.
+///
+/// Like Vitess, errors from the query itself are returned in the body of a successful response so the client keeps it's session.
+fn query_error(
+    session: Option<&TransactionSession>,
+    err: &QueryError,
+    key: &(String, String),
+    start: Instant,
+) -> Response {
+    let error = err.to_vitess(key);
+    if !err.is_query_error() {
+        return error.into_response();
+    }
+
+    Json(json!({
+        "session": session.and_then(TransactionSession::non_empty),
+        "error": error,
+        "timing": start.elapsed().as_secs_f64(),
+    }))
+    .into_response()
+}
+
+/// The error returned when a session can't be used by the current request.
+fn session_error(err: SessionError, id: Uuid, key: &(String, String)) -> Response {
+    match err {
+        SessionError::Expired(expiry) => session_expired(id, expiry),
+        SessionError::Forbidden => {
+            warn!("User {key:?} attempted to use transaction {id:?} which belongs to another user");
+            error(
+                Code::PermissionDenied,
+                format!("transaction {id} does not belong to the current user and database"),
+            )
+        }
+        SessionError::NotFound => {
+            debug!("Attempted to use non-existent transaction {id:?}");
+            error(Code::NotFound, format!("transaction {id} not found"))
+        }
+    }
+}
+
+/// The error returned when a client uses a session which was rolled back without it asking.
+fn session_expired(id: Uuid, expiry: Expiry) -> Response {
+    error(
+        Code::Aborted,
+        format!("transaction {id}: ended as it was {expiry}, the transaction has been rolled back"),
     )
-        .into_response()
 }
 
-#[derive(Debug, Clone, Deserialize, Serialize)]
-struct TransactionSession {
-    id: Uuid,
+/// Get the Vitess metadata of a column.
+fn field(col: &Column) -> Field {
+    Field {
+        name: col.name_str().to_string(),
+        ty: column_type_to_str(col).to_string(),
+        table: col.table_str().to_string(),
+        org_table: col.org_table_str().to_string(),
+        database: col.schema_str().to_string(),
+        org_name: col.org_name_str().to_string(),
+        column_length: col.column_length(),
+        charset: col.character_set().into(),
+        decimals: col.decimals().into(),
+        flags: col.flags().bits().into(),
+    }
+}
+
+/// Encode a row into the Vitess format of the concatenated values and the length of each of them.
+///
+/// A length of `-1` represents `NULL`.
+fn encode_row(mut row: Row, preserve_fractional_seconds: bool) -> VitessRow {
+    let mut lengths = Vec::new();
+    let mut values = Vec::new();
+
+    for i in 0..row.len() {
+        let Some(value) = row.take(i) else {
+            continue;
+        };
+
+        let col = &row.columns_ref()[i];
+        // Planetscale wipes out the fractional seconds so by default we copy them.
+        let fraction = |micros: u32| {
+            if preserve_fractional_seconds {
+                fractional_seconds(col, micros)
+            } else {
+                String::new()
+            }
+        };
+
+        let result = match value {
+            Value::NULL => {
+                lengths.push(-1i64);
+                continue;
+            }
+            Value::Bytes(mut v) => {
+                // Temporal values from the text protocol are already formatted by MySQL
+                if !preserve_fractional_seconds && is_temporal_with_time(col) {
+                    if let Some(i) = v.iter().position(|b| *b == b'.') {
+                        v.truncate(i);
+                    }
+                }
+
+                lengths.push(
+                    v.len()
+                        .try_into()
+                        .expect("unable to cast usize to i64. How big are your damn pointers?"),
+                );
+                values.extend(v);
+                continue;
+            }
+            Value::Int(i) => i.to_string(),
+            Value::UInt(i) => i.to_string(),
+            Value::Float(i) => i.to_string(),
+            Value::Double(i) => i.to_string(),
+            Value::Date(year, month, day, hour, minute, second, micros) => {
+                if matches!(
+                    col.column_type(),
+                    ColumnType::MYSQL_TYPE_DATE | ColumnType::MYSQL_TYPE_NEWDATE
+                ) {
+                    format!("{:04}-{:02}-{:02}", year, month, day)
+                } else {
+                    format!(
+                        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}{}",
+                        year,
+                        month,
+                        day,
+                        hour,
+                        minute,
+                        second,
+                        fraction(micros)
+                    )
+                }
+            }
+            Value::Time(neg, d, h, i, s, micros) => format!(
+                "{}{:02}:{:02}:{:02}{}",
+                if neg { "-" } else { "" },
+                d * 24 + u32::from(h),
+                i,
+                s,
+                fraction(micros)
+            ),
+        };
+
+        lengths.push(
+            result
+                .len()
+                .try_into()
+                .expect("unable to cast usize to i64. How big are your damn pointers?"),
+        );
+        values.extend(result.as_bytes());
+    }
+
+    VitessRow {
+        lengths,
+        values: STANDARD.encode(values),
+    }
+}
+
+fn is_temporal_with_time(col: &Column) -> bool {
+    matches!(
+        col.column_type(),
+        ColumnType::MYSQL_TYPE_DATETIME
+            | ColumnType::MYSQL_TYPE_DATETIME2
+            | ColumnType::MYSQL_TYPE_TIMESTAMP
+            | ColumnType::MYSQL_TYPE_TIMESTAMP2
+            | ColumnType::MYSQL_TYPE_TIME
+            | ColumnType::MYSQL_TYPE_TIME2
+    )
 }
 
-#[derive(Deserialize)]
-struct SqlRequest {
-    query: String,
-    session: Option<TransactionSession>,
+/// Format the fractional part of a temporal value, truncated to the precision of it's column (Eg. `DATETIME(3)`).
+fn fractional_seconds(col: &Column, micros: u32) -> String {
+    // MySQL supports at most microsecond precision
+    let decimals = usize::from(col.decimals()).min(6);
+    if decimals == 0 {
+        return String::new();
+    }
+
+    let mut result = format!(".{micros:06}");
+    result.truncate(decimals + 1);
+    result
 }
 
+/// The MySQL character set id of the `binary` character set.
+/// This is used to distinguish binary strings from strings with a `_bin` collation (which also set the `BINARY_FLAG`).
+const BINARY_CHARSET: u16 = 63;
+
 // Convert MySQL column types to Vitess column types
 //
 // Ref:
@@ -364,7 +1663,7 @@ struct SqlRequest {
 // - https://vitess.io/files/version-pdfs/Vitess-Docs-6.0-04-29-2020.pdf
 fn column_type_to_str(col: &Column) -> &'static str {
     let is_signed = !col.flags().contains(ColumnFlags::UNSIGNED_FLAG);
-    let is_binary = col.flags().contains(ColumnFlags::BINARY_FLAG);
+    let is_binary = col.character_set() == BINARY_CHARSET;
 
     if col.flags().contains(ColumnFlags::ENUM_FLAG) {
         return "ENUM";
@@ -373,39 +1672,35 @@ fn column_type_to_str(col: &Column) -> &'static str {
     }
 
     match col.column_type() {
-        ColumnType::MYSQL_TYPE_DECIMAL => "DECIMAL",
+        ColumnType::MYSQL_TYPE_DECIMAL | ColumnType::MYSQL_TYPE_NEWDECIMAL => "DECIMAL",
         ColumnType::MYSQL_TYPE_TINY => t(is_signed, "INT8", "UINT8"),
         ColumnType::MYSQL_TYPE_SHORT => t(is_signed, "INT16", "UINT16"),
         ColumnType::MYSQL_TYPE_LONG => t(is_signed, "INT32", "UINT32"),
         ColumnType::MYSQL_TYPE_FLOAT => "FLOAT32",
         ColumnType::MYSQL_TYPE_DOUBLE => "FLOAT64",
-        ColumnType::MYSQL_TYPE_NULL => "NULL",
-        ColumnType::MYSQL_TYPE_TIMESTAMP => "TIMESTAMP",
+        ColumnType::MYSQL_TYPE_NULL => "NULL_TYPE",
+        ColumnType::MYSQL_TYPE_TIMESTAMP | ColumnType::MYSQL_TYPE_TIMESTAMP2 => "TIMESTAMP",
         ColumnType::MYSQL_TYPE_LONGLONG => t(is_signed, "INT64", "UINT64"),
         ColumnType::MYSQL_TYPE_INT24 => t(is_signed, "INT24", "UINT24"),
-        ColumnType::MYSQL_TYPE_DATE => "DATE",
-        ColumnType::MYSQL_TYPE_TIME => "TIME",
-        ColumnType::MYSQL_TYPE_DATETIME => "DATETIME",
+        ColumnType::MYSQL_TYPE_DATE | ColumnType::MYSQL_TYPE_NEWDATE => "DATE",
+        ColumnType::MYSQL_TYPE_TIME | ColumnType::MYSQL_TYPE_TIME2 => "TIME",
+        ColumnType::MYSQL_TYPE_DATETIME | ColumnType::MYSQL_TYPE_DATETIME2 => "DATETIME",
         ColumnType::MYSQL_TYPE_YEAR => "YEAR",
-        ColumnType::MYSQL_TYPE_NEWDATE => unreachable!("Internal to MySQL."),
-        ColumnType::MYSQL_TYPE_VARCHAR => "VARCHAR",
+        ColumnType::MYSQL_TYPE_VARCHAR | ColumnType::MYSQL_TYPE_VAR_STRING => {
+            t(is_binary, "VARBINARY", "VARCHAR")
+        }
         ColumnType::MYSQL_TYPE_BIT => "BIT",
-        ColumnType::MYSQL_TYPE_TIMESTAMP2 => todo!(),
-        ColumnType::MYSQL_TYPE_DATETIME2 => todo!(),
-        ColumnType::MYSQL_TYPE_TIME2 => todo!(),
-        ColumnType::MYSQL_TYPE_TYPED_ARRAY => unreachable!("Used for replication only."),
-        ColumnType::MYSQL_TYPE_UNKNOWN => unreachable!(),
         ColumnType::MYSQL_TYPE_JSON => "JSON",
-        ColumnType::MYSQL_TYPE_NEWDECIMAL => todo!(),
         ColumnType::MYSQL_TYPE_ENUM => "ENUM",
         ColumnType::MYSQL_TYPE_SET => "SET",
         ColumnType::MYSQL_TYPE_TINY_BLOB
         | ColumnType::MYSQL_TYPE_MEDIUM_BLOB
         | ColumnType::MYSQL_TYPE_LONG_BLOB
         | ColumnType::MYSQL_TYPE_BLOB => t(is_binary, "BLOB", "TEXT"),
-        ColumnType::MYSQL_TYPE_VAR_STRING => t(is_binary, "VARBINARY", "VARCHAR"),
         ColumnType::MYSQL_TYPE_STRING => t(is_binary, "BINARY", "CHAR"),
         ColumnType::MYSQL_TYPE_GEOMETRY => "GEOMETRY",
+        // These are never sent to clients by the server but we pass the raw bytes through just in case.
+        ColumnType::MYSQL_TYPE_TYPED_ARRAY | ColumnType::MYSQL_TYPE_UNKNOWN => "VARBINARY",
     }
 }
 
@@ -416,3 +1711,47 @@ fn t<T>(a_or_b: bool, a: T, b: T) -> T {
         b
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rejects_schema_changes_to_protected_databases() {
+        let mut config = Config::default();
+        config.databases.entry("prod".into()).or_default().protected = true;
+        let rejected = |query: &str, database: &str| {
+            let execute = check_protected(&config, &Analysis::new(query, database)).is_err();
+            let dashboard = check_protected_query(&config, query, database).is_err();
+            assert_eq!(execute, dashboard, "{query}");
+            execute
+        };
+
+        assert!(rejected("ALTER TABLE users ADD x INT", "prod"));
+        assert!(rejected("DROP TABLE Prod.users", "other"));
+        assert!(!rejected("ALTER TABLE users ADD x INT", "other"));
+        assert!(!rejected("INSERT INTO users VALUES (1)", "prod"));
+        assert!(!rejected("CREATE TEMPORARY TABLE t (id INT)", "prod"));
+
+        // Every statement of a query is run
+        assert!(rejected("SELECT 1; DROP TABLE users", "prod"));
+        assert!(rejected("SELECT 1;\nDROP TABLE users;", "prod"));
+        assert!(rejected("USE prod; DROP TABLE users", "other"));
+        assert!(!rejected("USE other; SELECT 1", "prod"));
+        // The SQL in an executable comment is run
+        assert!(rejected("/*!50000 DROP TABLE users */", "prod"));
+        assert!(rejected("/*! DROP TABLE users */", "prod"));
+        assert!(rejected(
+            "SELECT 1 /*!; ALTER TABLE users ADD x INT*/",
+            "prod"
+        ));
+        assert!(!rejected("SELECT /*+ MAX_EXECUTION_TIME(1) */ 1", "prod"));
+        // We don't know what a prepared statement runs
+        assert!(rejected(
+            "PREPARE s FROM 'ALTER TABLE users ADD x INT'",
+            "prod"
+        ));
+        assert!(rejected("PREPARE s FROM @sql", "other"));
+        assert!(rejected("EXECUTE s", "other"));
+    }
+}
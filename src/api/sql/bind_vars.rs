@@ -0,0 +1,130 @@
+use std::collections::HashMap;
+
+use base64::{engine::general_purpose::STANDARD, Engine};
+use cityscale_client::types::BindVariable;
+use mysql_async::{Params, Value};
+
+use super::error::{Code, VitessError};
+
+/// Convert the bind variables from a request into parameters for `mysql_async`.
+pub fn to_params(bind_vars: HashMap<String, BindVariable>) -> Result<Params, VitessError> {
+    if bind_vars.is_empty() {
+        return Ok(Params::Empty);
+    }
+
+    bind_vars
+        .into_iter()
+        .map(|(name, var)| {
+            let value = to_value(&name, var)?;
+            Ok((name.into_bytes(), value))
+        })
+        .collect::<Result<HashMap<_, _>, _>>()
+        .map(Params::Named)
+}
+
+fn to_value(name: &str, var: BindVariable) -> Result<Value, VitessError> {
+    let ty = var.ty.as_deref().unwrap_or("NULL_TYPE");
+    if ty == "TUPLE" || !var.values.is_empty() {
+        return Err(VitessError::new(
+            Code::Unimplemented,
+            format!("bind variable {name:?}: tuple bind variables are not supported"),
+        ));
+    }
+    if ty == "NULL_TYPE" {
+        return Ok(Value::NULL);
+    }
+
+    let value = STANDARD
+        .decode(var.value.unwrap_or_default())
+        .map_err(|err| {
+            VitessError::new(
+                Code::InvalidArgument,
+                format!("bind variable {name:?}: value is not valid base64: {err}"),
+            )
+        })?;
+
+    let invalid = |err: &dyn std::fmt::Display| {
+        VitessError::new(
+            Code::InvalidArgument,
+            format!("bind variable {name:?}: invalid {ty} value: {err}"),
+        )
+    };
+    let text = || std::str::from_utf8(&value).map_err(|err| invalid(&err));
+
+    Ok(match ty {
+        "INT8" | "INT16" | "INT24" | "INT32" | "INT64" => {
+            Value::Int(text()?.parse().map_err(|err| invalid(&err))?)
+        }
+        "UINT8" | "UINT16" | "UINT24" | "UINT32" | "UINT64" | "YEAR" => {
+            Value::UInt(text()?.parse().map_err(|err| invalid(&err))?)
+        }
+        "FLOAT32" | "FLOAT64" => Value::Double(text()?.parse().map_err(|err| invalid(&err))?),
+        // Everything else is sent in it's text representation and MySQL will convert it to the column's type
+        _ => Value::Bytes(value),
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn var(ty: &str, value: &str) -> BindVariable {
+        BindVariable::new(ty, value)
+    }
+
+    fn convert(var: BindVariable) -> Result<Value, Code> {
+        match to_params(HashMap::from([("v".to_string(), var)])) {
+            Ok(Params::Named(params)) => Ok(params[b"v".as_slice()].clone()),
+            Ok(params) => panic!("expected named params, got {params:?}"),
+            Err(err) => Err(err.code),
+        }
+    }
+
+    #[test]
+    fn converts_bind_vars() {
+        assert!(matches!(to_params(HashMap::new()), Ok(Params::Empty)));
+
+        let cases = [
+            (var("INT8", "-128"), Ok(Value::Int(-128))),
+            (
+                var("INT64", "-9223372036854775808"),
+                Ok(Value::Int(i64::MIN)),
+            ),
+            (
+                var("UINT64", "18446744073709551615"),
+                Ok(Value::UInt(u64::MAX)),
+            ),
+            (var("YEAR", "2024"), Ok(Value::UInt(2024))),
+            (var("FLOAT64", "1.5"), Ok(Value::Double(1.5))),
+            (var("VARCHAR", "Oscar"), Ok(Value::Bytes(b"Oscar".to_vec()))),
+            (var("DECIMAL", "1.10"), Ok(Value::Bytes(b"1.10".to_vec()))),
+            (
+                var("DATETIME", "2024-01-02 03:04:05"),
+                Ok(Value::Bytes(b"2024-01-02 03:04:05".to_vec())),
+            ),
+            (var("NULL_TYPE", ""), Ok(Value::NULL)),
+            (BindVariable::default(), Ok(Value::NULL)),
+            (var("INT64", "abc"), Err(Code::InvalidArgument)),
+            (var("UINT8", "-1"), Err(Code::InvalidArgument)),
+            (
+                BindVariable {
+                    ty: Some("INT64".into()),
+                    value: Some("not base64!".into()),
+                    values: Vec::new(),
+                },
+                Err(Code::InvalidArgument),
+            ),
+            (
+                BindVariable {
+                    ty: Some("TUPLE".into()),
+                    value: None,
+                    values: vec![serde_json::json!({ "type": "INT64", "value": "MQ==" })],
+                },
+                Err(Code::Unimplemented),
+            ),
+        ];
+        for (var, expected) in cases {
+            assert_eq!(convert(var.clone()), expected, "{var:?}");
+        }
+    }
+}
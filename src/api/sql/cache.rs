@@ -0,0 +1,697 @@
+use std::{
+    collections::{BTreeMap, HashMap, HashSet},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex, PoisonError,
+    },
+    time::{Duration, Instant},
+};
+
+use cityscale_client::types::{BindVariable, QueryResult as VitessResult};
+use serde::Serialize;
+
+/// The comment hint which sets the TTL (in seconds) of a query's cached result (Eg. `SELECT * FROM posts /* cityscale:cache_ttl=60 */`).
+const TTL_HINT: &str = "cityscale:cache_ttl=";
+
+/// A database + table name, lowercased as comparing them is only used to find cached results to invalidate.
+type Table = (String, String);
+
+/// A cache of the results of read-only queries run outside of a session.
+///
+/// Entries are removed when their TTL passes or when a query run through Cityscale writes to a table they read.
+/// Writes made directly in MySQL (Eg. through the MySQL proxy) are only picked up once the TTL passes.
+#[derive(Default)]
+pub struct ResultCache {
+    inner: Mutex<Inner>,
+    hits: AtomicU64,
+    misses: AtomicU64,
+}
+
+#[derive(Default)]
+struct Inner {
+    entries: HashMap<Key, Entry>,
+    /// The size of all entries in bytes.
+    size: usize,
+    /// Incremented on each invalidation, so results read before a write completed aren't cached after it.
+    generation: u64,
+}
+
+/// A query's result is only shared between requests with the same credentials, database and bind variables.
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+pub struct Key {
+    pub username: String,
+    pub database: String,
+    pub query: String,
+    /// The bind variables serialized in a stable order.
+    pub bind_vars: String,
+}
+
+impl Key {
+    pub fn new(
+        username: &str,
+        database: &str,
+        query: &str,
+        bind_vars: &HashMap<String, BindVariable>,
+    ) -> Self {
+        Self {
+            username: username.to_string(),
+            database: database.to_string(),
+            query: query.to_string(),
+            bind_vars: serde_json::to_string(&bind_vars.iter().collect::<BTreeMap<_, _>>())
+                .expect("bind variables are serializable"),
+        }
+    }
+}
+
+struct Entry {
+    result: VitessResult,
+    tables: HashSet<Table>,
+    size: usize,
+    expires_at: Instant,
+    last_used: Instant,
+}
+
+/// Information about the result cache for operators.
+#[derive(Debug, Serialize)]
+pub struct CacheStats {
+    pub hits: u64,
+    pub misses: u64,
+    pub entries: usize,
+    /// The size of all entries in bytes.
+    pub size: usize,
+}
+
+impl ResultCache {
+    /// Get a cached result, recording a hit or miss.
+    pub fn get(&self, key: &Key) -> Option<VitessResult> {
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        let result = inner
+            .entries
+            .get_mut(key)
+            .filter(|entry| entry.expires_at > now)
+            .map(|entry| {
+                entry.last_used = now;
+                entry.result.clone()
+            });
+
+        match result.is_some() {
+            true => self.hits.fetch_add(1, Ordering::Relaxed),
+            false => self.misses.fetch_add(1, Ordering::Relaxed),
+        };
+        result
+    }
+
+    /// The current generation, which must be read before running a query whose result will be inserted.
+    pub fn generation(&self) -> u64 {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .generation
+    }
+
+    /// Cache the result of a query, unless the cache was invalidated since `generation` as the result may be stale.
+    ///
+    /// The least recently used entries are removed to keep the cache under `max_size` bytes.
+    pub fn insert(
+        &self,
+        key: Key,
+        query: &Analysis,
+        result: VitessResult,
+        ttl: Duration,
+        generation: u64,
+        max_size: usize,
+    ) {
+        let size = key.query.len()
+            + result
+                .rows
+                .iter()
+                .map(|row| row.values.len())
+                .sum::<usize>();
+        if size > max_size {
+            return;
+        }
+
+        let now = Instant::now();
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        if inner.generation != generation {
+            return;
+        }
+
+        if let Some(old) = inner.entries.remove(&key) {
+            inner.size -= old.size;
+        }
+        inner.evict_expired(now);
+        while inner.size + size > max_size {
+            let Some(oldest) = inner
+                .entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+            else {
+                break;
+            };
+            let entry = inner.entries.remove(&oldest).expect("key was just found");
+            inner.size -= entry.size;
+        }
+
+        inner.size += size;
+        inner.entries.insert(
+            key,
+            Entry {
+                result,
+                tables: query.tables.clone(),
+                size,
+                expires_at: now + ttl,
+                last_used: now,
+            },
+        );
+    }
+
+    /// Remove the cached results which may have been changed by a write.
+    pub fn invalidate(&self, invalidation: &Invalidation) {
+        if matches!(invalidation, Invalidation::None) {
+            return;
+        }
+
+        let mut inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        inner.generation += 1;
+        let mut removed = 0;
+        inner.entries.retain(|_, entry| {
+            let keep = match invalidation {
+                Invalidation::None => true,
+                Invalidation::Tables(tables) => entry.tables.is_disjoint(tables),
+                Invalidation::All => false,
+            };
+            if !keep {
+                removed += entry.size;
+            }
+            keep
+        });
+        inner.size -= removed;
+    }
+
+    pub fn evict_expired(&self) {
+        self.inner
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .evict_expired(Instant::now());
+    }
+
+    pub fn stats(&self) -> CacheStats {
+        let inner = self.inner.lock().unwrap_or_else(PoisonError::into_inner);
+        CacheStats {
+            hits: self.hits.load(Ordering::Relaxed),
+            misses: self.misses.load(Ordering::Relaxed),
+            entries: inner.entries.len(),
+            size: inner.size,
+        }
+    }
+}
+
+impl Inner {
+    fn evict_expired(&mut self, now: Instant) {
+        let mut removed = 0;
+        self.entries.retain(|_, entry| {
+            let keep = entry.expires_at > now;
+            if !keep {
+                removed += entry.size;
+            }
+            keep
+        });
+        self.size -= removed;
+    }
+}
+
+/// The cached results a write needs to invalidate.
+#[derive(Debug, Default)]
+pub enum Invalidation {
+    #[default]
+    None,
+    Tables(HashSet<Table>),
+    /// We don't know what the query changed so everything is invalidated.
+    All,
+}
+
+impl Invalidation {
+    /// Combine the invalidations of multiple queries (Eg. the writes of a transaction).
+    pub fn merge(&mut self, other: Invalidation) {
+        match (&mut *self, other) {
+            (_, Invalidation::None) | (Invalidation::All, _) => {}
+            (Invalidation::Tables(tables), Invalidation::Tables(other)) => tables.extend(other),
+            (this, other) => *this = other,
+        }
+    }
+}
+
+/// What a query reads or writes, from a rough parse of it's SQL.
+#[derive(Debug)]
+pub struct Analysis {
+    kind: Kind,
+    /// The tables the query references, qualified with the current database.
+    tables: HashSet<Table>,
+    /// The TTL set with a comment hint.
+    ttl_hint: Option<u64>,
+    /// The databases whose schema the query changes, if it's DDL.
+    schema_changes: SchemaChanges,
+}
+
+#[derive(Debug, Default)]
+enum SchemaChanges {
+    #[default]
+    None,
+    Databases(HashSet<String>),
+    /// We don't know what the query runs so it could change any database (Eg. `EXECUTE`).
+    Any,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum Kind {
+    /// A `SELECT` which doesn't lock rows or write to variables or files.
+    Cacheable,
+    /// A statement which doesn't change any data (Eg. `SHOW` or `SET`).
+    ReadOnly,
+    Write,
+    /// A statement which could change anything (Eg. `CALL`).
+    Unknown,
+}
+
+impl Analysis {
+    pub fn new(query: &str, database: &str) -> Self {
+        let (tokens, comments) = tokenize(query);
+        let ttl_hint = comments.iter().find_map(|comment| {
+            let (_, ttl) = comment.split_once(TTL_HINT)?;
+            let digits = ttl
+                .trim_start()
+                .split(|c: char| !c.is_ascii_digit())
+                .next()?;
+            digits.parse().ok()
+        });
+
+        // MySQL runs every statement of a query with multiple statements, each in the database a `USE` before it switched to
+        let statements = tokens
+            .split(|token| *token == Token::Semicolon)
+            .filter(|statement| !statement.is_empty())
+            .collect::<Vec<_>>();
+        let mut analysis =
+            Self::statement(statements.first().copied().unwrap_or_default(), database);
+        let mut database = database.to_string();
+        for (statement, next) in statements.iter().zip(statements.iter().skip(1)) {
+            if let [Token::Word(keyword), Token::Word(name), ..] = statement {
+                if keyword.eq_ignore_ascii_case("USE") {
+                    database = name.clone();
+                }
+            }
+            let next = Self::statement(next, &database);
+            analysis.tables.extend(next.tables);
+            analysis.schema_changes = match (analysis.schema_changes, next.schema_changes) {
+                (SchemaChanges::Any, _) | (_, SchemaChanges::Any) => SchemaChanges::Any,
+                (SchemaChanges::Databases(mut databases), SchemaChanges::Databases(other)) => {
+                    databases.extend(other);
+                    SchemaChanges::Databases(databases)
+                }
+                (changes, SchemaChanges::None) | (SchemaChanges::None, changes) => changes,
+            };
+            // We don't know which statement's result is returned, or if a later statement reads an earlier one's writes
+            analysis.kind = Kind::Unknown;
+        }
+        analysis.ttl_hint = ttl_hint;
+        analysis
+    }
+
+    /// Analyse a single statement of a query.
+    fn statement(tokens: &[Token], database: &str) -> Self {
+        let keyword = |i: usize, word: &str| matches!(tokens.get(i), Some(Token::Word(w)) if w.eq_ignore_ascii_case(word));
+        let contains = |word: &str| (0..tokens.len()).any(|i| keyword(i, word));
+        // A query may start with parentheses (Eg. `(SELECT ...) UNION (SELECT ...)`)
+        let first = tokens
+            .iter()
+            .find_map(|token| match token {
+                Token::Word(word) => Some(word.to_ascii_uppercase()),
+                _ => None,
+            })
+            .unwrap_or_default();
+        let locks = contains("LOCK")
+            || (0..tokens.len()).any(|i| {
+                keyword(i, "FOR") && (keyword(i + 1, "UPDATE") || keyword(i + 1, "SHARE"))
+            });
+        // MySQL allows a CTE before an `UPDATE` or `DELETE`
+        let writes = (0..tokens.len()).any(|i| {
+            (keyword(i, "UPDATE") && !(i > 0 && keyword(i - 1, "FOR")))
+                || keyword(i, "DELETE")
+                || keyword(i, "INSERT")
+        });
+
+        let kind = match first.as_str() {
+            "WITH" if writes => Kind::Write,
+            "SELECT" | "WITH" if locks || contains("INTO") => Kind::ReadOnly,
+            "SELECT" | "WITH" => Kind::Cacheable,
+            "SHOW" | "DESCRIBE" | "DESC" | "EXPLAIN" | "USE" | "SET" | "BEGIN" | "START"
+            | "COMMIT" | "ROLLBACK" | "SAVEPOINT" | "RELEASE" => Kind::ReadOnly,
+            "INSERT" | "REPLACE" | "UPDATE" | "DELETE" | "TRUNCATE" | "ALTER" | "DROP"
+            | "RENAME" | "CREATE" => Kind::Write,
+            _ => Kind::Unknown,
+        };
+
+        let mut tables = HashSet::new();
+        let mut i = 0;
+        while i < tokens.len() {
+            let starts_list = ["FROM", "JOIN", "INTO", "UPDATE", "TABLE", "TABLES"]
+                .iter()
+                .any(|word| keyword(i, word));
+            i += 1;
+            if !starts_list {
+                continue;
+            }
+
+            loop {
+                while [
+                    "IF",
+                    "NOT",
+                    "EXISTS",
+                    "IGNORE",
+                    "LOW_PRIORITY",
+                    "QUICK",
+                    "ONLY",
+                ]
+                .iter()
+                .any(|word| keyword(i, word))
+                {
+                    i += 1;
+                }
+                let Some(Token::Word(name)) = tokens.get(i) else {
+                    break;
+                };
+                let table = match (tokens.get(i + 1), tokens.get(i + 2)) {
+                    (Some(Token::Dot), Some(Token::Word(table))) => {
+                        i += 3;
+                        (name.to_lowercase(), table.to_lowercase())
+                    }
+                    _ => {
+                        i += 1;
+                        (database.to_lowercase(), name.to_lowercase())
+                    }
+                };
+                tables.insert(table);
+
+                // Skip an alias in a list of tables (Eg. `FROM a AS x, b y`)
+                if keyword(i, "AS") {
+                    i += 2;
+                } else if matches!(tokens.get(i), Some(Token::Word(_)))
+                    && tokens.get(i + 1) == Some(&Token::Comma)
+                {
+                    i += 1;
+                }
+                if tokens.get(i) != Some(&Token::Comma) {
+                    break;
+                }
+                i += 1;
+            }
+        }
+
+        // Temporary tables only exist for the session so they don't change the schema
+        let start = tokens.iter().position(
+            |token| matches!(token, Token::Word(word) if word.eq_ignore_ascii_case(&first)),
+        );
+        let schema_changes = match start {
+            // A prepared statement's SQL may come from a variable, or a previous request
+            Some(_) if matches!(first.as_str(), "PREPARE" | "EXECUTE") => SchemaChanges::Any,
+            Some(i)
+                if matches!(first.as_str(), "CREATE" | "ALTER" | "DROP" | "RENAME")
+                    && !keyword(i + 1, "TEMPORARY") =>
+            {
+                if keyword(i + 1, "DATABASE") || keyword(i + 1, "SCHEMA") {
+                    // A statement on a database only changes that database
+                    let mut i = i + 2;
+                    while ["IF", "NOT", "EXISTS"].iter().any(|word| keyword(i, word)) {
+                        i += 1;
+                    }
+                    SchemaChanges::Databases(match tokens.get(i) {
+                        Some(Token::Word(name)) => HashSet::from([name.to_lowercase()]),
+                        _ => HashSet::from([database.to_lowercase()]),
+                    })
+                } else {
+                    // Not every object a statement creates is found as a table (Eg. `CREATE VIEW`) so the current database is always included
+                    SchemaChanges::Databases(
+                        tables
+                            .iter()
+                            .map(|(database, _)| database.clone())
+                            .chain([database.to_lowercase()])
+                            .collect(),
+                    )
+                }
+            }
+            _ => SchemaChanges::None,
+        };
+
+        Self {
+            kind,
+            tables,
+            ttl_hint: None,
+            schema_changes,
+        }
+    }
+
+    /// If the query changes the schema of a database (Eg. `ALTER TABLE`).
+    /// This errs on the side of including too many, Eg. the database of a table a new view selects from.
+    pub fn changes_schema(&self, database: &str) -> bool {
+        match &self.schema_changes {
+            SchemaChanges::None => false,
+            SchemaChanges::Databases(databases) => databases.contains(&database.to_lowercase()),
+            SchemaChanges::Any => true,
+        }
+    }
+
+    /// The TTL to cache the query's result for, if it can be cached.
+    pub fn ttl(&self, default: Option<u64>) -> Option<Duration> {
+        let ttl = self.ttl_hint.or(default)?;
+        (self.kind == Kind::Cacheable && ttl != 0).then(|| Duration::from_secs(ttl))
+    }
+
+    /// The cached results running the query would invalidate.
+    pub fn invalidation(&self) -> Invalidation {
+        match self.kind {
+            Kind::Cacheable | Kind::ReadOnly => Invalidation::None,
+            Kind::Write if !self.tables.is_empty() => Invalidation::Tables(self.tables.clone()),
+            Kind::Write | Kind::Unknown => Invalidation::All,
+        }
+    }
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Token {
+    /// A keyword or identifier, with the quotes removed if it was quoted with backticks.
+    Word(String),
+    Dot,
+    Comma,
+    Semicolon,
+    /// Any other punctuation or literal, which we don't care about.
+    Other,
+}
+
+/// Split a query into tokens, returning the text of it's comments separately.
+fn tokenize(query: &str) -> (Vec<Token>, Vec<&str>) {
+    let mut tokens = Vec::new();
+    let mut comments = Vec::new();
+    let bytes = query.as_bytes();
+    let mut i = 0;
+
+    while i < bytes.len() {
+        let c = bytes[i];
+        let rest = &query[i..];
+        if c.is_ascii_whitespace() {
+            i += 1;
+        } else if let Some(body) = rest
+            .strip_prefix("/*!")
+            .or_else(|| rest.strip_prefix("/*M!"))
+        {
+            // MySQL runs the body of an executable comment (Eg. `/*!50000 DROP TABLE users */`) as part of the query
+            let end = body.find("*/").unwrap_or(body.len());
+            let (body_tokens, body_comments) =
+                tokenize(body[..end].trim_start_matches(|c: char| c.is_ascii_digit()));
+            tokens.extend(body_tokens);
+            comments.extend(body_comments);
+            i += rest.len() - body.len() + (end + 2).min(body.len());
+        } else if let Some(body) = rest.strip_prefix("/*") {
+            let (comment, end) = match body.find("*/") {
+                Some(end) => (&body[..end], end + 4),
+                None => (body, rest.len()),
+            };
+            comments.push(comment);
+            i += end;
+        } else if rest.starts_with("--") || c == b'#' {
+            let end = rest.find('\n').unwrap_or(rest.len());
+            comments.push(&rest[..end]);
+            i += end;
+        } else if c == b'`' {
+            let (word, end) = match rest[1..].find('`') {
+                Some(end) => (&rest[1..1 + end], end + 2),
+                None => (&rest[1..], rest.len()),
+            };
+            tokens.push(Token::Word(word.to_string()));
+            i += end;
+        } else if c == b'\'' || c == b'"' {
+            // Skip the string, including escaped quotes
+            let mut j = i + 1;
+            while j < bytes.len() && bytes[j] != c {
+                j += if bytes[j] == b'\\' { 2 } else { 1 };
+            }
+            tokens.push(Token::Other);
+            i = j + 1;
+        } else if c.is_ascii_alphanumeric() || c == b'_' || c == b'$' || !c.is_ascii() {
+            let end = rest
+                .find(|c: char| !(c.is_alphanumeric() || c == '_' || c == '$'))
+                .unwrap_or(rest.len())
+                // Other unicode characters (Eg. punctuation) are a word on their own
+                .max(rest.chars().next().map_or(1, char::len_utf8));
+            tokens.push(Token::Word(rest[..end].to_string()));
+            i += end;
+        } else {
+            tokens.push(match c {
+                b'.' => Token::Dot,
+                b',' => Token::Comma,
+                b';' => Token::Semicolon,
+                _ => Token::Other,
+            });
+            i += 1;
+        }
+    }
+
+    (tokens, comments)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const TTL: Duration = Duration::from_secs(60);
+    const MAX_SIZE: usize = 1024 * 1024;
+
+    fn key(query: &str) -> Key {
+        Key::new("user", "db", query, &HashMap::new())
+    }
+
+    /// Cache the result of a query, returning it's key.
+    fn insert(cache: &ResultCache, query: &str) -> Key {
+        let key = key(query);
+        let analysis = Analysis::new(query, "db");
+        cache.insert(
+            key.clone(),
+            &analysis,
+            VitessResult::default(),
+            TTL,
+            cache.generation(),
+            MAX_SIZE,
+        );
+        key
+    }
+
+    fn cached(cache: &ResultCache, key: &Key) -> bool {
+        cache.get(key).is_some()
+    }
+
+    #[test]
+    fn keys_are_stable() {
+        let vars = |names: &[&str]| {
+            names
+                .iter()
+                .map(|name| (name.to_string(), BindVariable::new("INT64", name)))
+                .collect::<HashMap<_, _>>()
+        };
+        let query = "SELECT * FROM t WHERE a = :a AND b = :b";
+        let key = Key::new("user", "db", query, &vars(&["a", "b"]));
+
+        assert_eq!(key, Key::new("user", "db", query, &vars(&["b", "a"])));
+        assert_ne!(key, Key::new("other", "db", query, &vars(&["a", "b"])));
+        assert_ne!(key, Key::new("user", "other", query, &vars(&["a", "b"])));
+        assert_ne!(key, Key::new("user", "db", query, &vars(&["a"])));
+    }
+
+    #[test]
+    fn writes_invalidate_the_tables_they_change() {
+        let cache = ResultCache::default();
+        let users = insert(&cache, "SELECT * FROM users");
+        let joined = insert(
+            &cache,
+            "SELECT * FROM posts JOIN users ON users.id = posts.user_id",
+        );
+        let other = insert(&cache, "SELECT * FROM other.users");
+        let comments = insert(&cache, "SELECT * FROM comments");
+
+        // A read doesn't invalidate anything
+        cache.invalidate(&Analysis::new("SELECT * FROM users", "db").invalidation());
+        assert!(cached(&cache, &users));
+
+        cache.invalidate(&Analysis::new("UPDATE users SET name = 'a'", "db").invalidation());
+        assert!(!cached(&cache, &users));
+        assert!(!cached(&cache, &joined));
+        assert!(cached(&cache, &other));
+        assert!(cached(&cache, &comments));
+
+        // Like a deploy, which changes the schema
+        cache.invalidate(&Analysis::new("ALTER TABLE other.users ADD x INT", "db").invalidation());
+        assert!(!cached(&cache, &other));
+        assert!(cached(&cache, &comments));
+
+        // A statement we can't analyse invalidates everything
+        cache.invalidate(&Analysis::new("CALL cleanup()", "db").invalidation());
+        assert!(!cached(&cache, &comments));
+        assert_eq!(cache.stats().size, 0);
+    }
+
+    #[test]
+    fn stale_results_are_not_cached() {
+        let cache = ResultCache::default();
+        let query = "SELECT * FROM users";
+        let generation = cache.generation();
+        // A write completed while the query was running
+        cache.invalidate(&Analysis::new("DELETE FROM users", "db").invalidation());
+        cache.insert(
+            key(query),
+            &Analysis::new(query, "db"),
+            VitessResult::default(),
+            TTL,
+            generation,
+            MAX_SIZE,
+        );
+        assert!(!cached(&cache, &key(query)));
+    }
+
+    #[test]
+    fn analyses_queries() {
+        let analysis = Analysis::new("SELECT * FROM a /* cityscale:cache_ttl=5 */", "db");
+        assert_eq!(analysis.ttl(None), Some(Duration::from_secs(5)));
+        assert_eq!(
+            Analysis::new("SELECT * FROM a", "db").ttl(Some(10)),
+            Some(Duration::from_secs(10))
+        );
+        assert_eq!(Analysis::new("SELECT * FROM a", "db").ttl(None), None);
+        assert_eq!(
+            Analysis::new("SELECT * FROM a FOR UPDATE", "db").ttl(Some(10)),
+            None
+        );
+
+        // The statements of a query may read each other's writes, and we don't know which result is returned
+        let multiple = Analysis::new("SELECT * FROM a; DELETE FROM a", "db");
+        assert_eq!(multiple.ttl(Some(10)), None);
+        assert!(matches!(multiple.invalidation(), Invalidation::All));
+        assert!(matches!(
+            Analysis::new("SELECT * FROM a /*!; DELETE FROM a */", "db").invalidation(),
+            Invalidation::All
+        ));
+        assert_eq!(
+            Analysis::new("SELECT * FROM a;", "db").ttl(Some(10)),
+            Some(Duration::from_secs(10))
+        );
+
+        let Invalidation::Tables(tables) =
+            Analysis::new("INSERT INTO `Db`.`A` SELECT * FROM b", "db").invalidation()
+        else {
+            panic!("expected the tables to be found");
+        };
+        assert_eq!(
+            tables,
+            HashSet::from([("db".into(), "a".into()), ("db".into(), "b".into())])
+        );
+    }
+}
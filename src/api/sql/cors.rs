@@ -0,0 +1,118 @@
+use std::sync::Arc;
+
+use axum::{
+    extract::{Request, State},
+    http::{header, HeaderMap, HeaderValue, Method, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+
+use super::AppState;
+use crate::config::CorsConfig;
+
+/// Add CORS headers for the origins in [`CorsConfig`] so the API can be called from a browser.
+///
+/// The config is read on each request so changes from the settings API apply immediately.
+/// Listed origins are echoed back with credentials allowed. Any other origin is allowed by `*` with a literal `*`
+/// and without credentials, so a browser never sends it's cookies to an origin which wasn't listed.
+pub async fn cors(State(state): State<Arc<AppState>>, request: Request, next: Next) -> Response {
+    let cors = state.config.get().cors.clone();
+    let Some(origin) = request
+        .headers()
+        .get(header::ORIGIN)
+        .and_then(|origin| allowed(&cors, origin))
+    else {
+        return next.run(request).await;
+    };
+
+    if request.method() == Method::OPTIONS
+        && request
+            .headers()
+            .contains_key(header::ACCESS_CONTROL_REQUEST_METHOD)
+    {
+        let mut response = StatusCode::NO_CONTENT.into_response();
+        let headers = response.headers_mut();
+        insert_origin(headers, &origin);
+        insert_list(
+            headers,
+            header::ACCESS_CONTROL_ALLOW_METHODS,
+            &cors.allowed_methods,
+            request.headers().get(header::ACCESS_CONTROL_REQUEST_METHOD),
+        );
+        insert_list(
+            headers,
+            header::ACCESS_CONTROL_ALLOW_HEADERS,
+            &cors.allowed_headers,
+            request
+                .headers()
+                .get(header::ACCESS_CONTROL_REQUEST_HEADERS),
+        );
+        headers.insert(
+            header::ACCESS_CONTROL_MAX_AGE,
+            HeaderValue::from_static("600"),
+        );
+        return response;
+    }
+
+    let mut response = next.run(request).await;
+    insert_origin(response.headers_mut(), &origin);
+    response
+}
+
+/// How a request's origin is allowed.
+enum Allowed {
+    /// The origin is listed, so it's allowed to send credentials.
+    Origin(HeaderValue),
+    /// The origin is allowed by `*`.
+    Any,
+}
+
+fn allowed(cors: &CorsConfig, origin: &HeaderValue) -> Option<Allowed> {
+    if cors
+        .allowed_origins
+        .iter()
+        .any(|allowed| allowed.as_bytes() == origin.as_bytes())
+    {
+        Some(Allowed::Origin(origin.clone()))
+    } else if cors.allowed_origins.iter().any(|allowed| allowed == "*") {
+        Some(Allowed::Any)
+    } else {
+        None
+    }
+}
+
+fn insert_origin(headers: &mut HeaderMap, origin: &Allowed) {
+    match origin {
+        Allowed::Origin(origin) => {
+            headers.insert(header::ACCESS_CONTROL_ALLOW_ORIGIN, origin.clone());
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_CREDENTIALS,
+                HeaderValue::from_static("true"),
+            );
+            headers.append(header::VARY, HeaderValue::from_static("Origin"));
+        }
+        Allowed::Any => {
+            headers.insert(
+                header::ACCESS_CONTROL_ALLOW_ORIGIN,
+                HeaderValue::from_static("*"),
+            );
+        }
+    }
+}
+
+/// Insert the allowed methods or headers, mirroring what the browser requested if `*` is allowed
+/// as the wildcard isn't supported for requests with credentials and never covers `Authorization`.
+fn insert_list(
+    headers: &mut HeaderMap,
+    name: header::HeaderName,
+    allowed: &[String],
+    requested: Option<&HeaderValue>,
+) {
+    let value = match allowed.iter().any(|v| v == "*") {
+        true => requested.cloned(),
+        false => HeaderValue::from_str(&allowed.join(", ")).ok(),
+    };
+    if let Some(value) = value.filter(|v| !v.is_empty()) {
+        headers.insert(name, value);
+    }
+}
@@ -0,0 +1,180 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::{Duration, Instant},
+};
+
+use secstr::SecStr;
+use serde::Serialize;
+use tracing::debug;
+
+/// How long credentials MySQL rejected are remembered, so clients retrying them don't each reach MySQL.
+const REJECTED_TTL: Duration = Duration::from_secs(10);
+
+/// A cache of the credentials we have verified against MySQL and the connection pool for each of them.
+///
+/// It's bounded by the number of entries, evicting the least recently used, and each entry
+/// expires a while after it's verified so credentials changed outside of Cityscale are eventually picked up.
+#[derive(Default)]
+pub struct CredentialCache {
+    /// A map of username + database combo's to database connection & password
+    entries: Mutex<HashMap<(String, String), Entry>>,
+    /// Credentials MySQL recently rejected and the error number it rejected them with.
+    rejected: Mutex<HashMap<(String, String), Rejection>>,
+}
+
+struct Rejection {
+    password: SecStr,
+    errno: u16,
+    at: Instant,
+}
+
+struct Entry {
+    password: SecStr,
+    db: mysql_async::Pool,
+    verified_at: Instant,
+    last_used: Instant,
+}
+
+/// Information about a cached connection pool.
+#[derive(Debug, Serialize)]
+pub struct PoolStats {
+    pub username: String,
+    pub database: String,
+    /// Seconds since the credentials were verified.
+    pub age: u64,
+    /// Seconds since the pool was last used.
+    pub idle: u64,
+}
+
+impl CredentialCache {
+    /// Get the password and pool for a username + database combo if they haven't expired.
+    pub fn get(
+        &self,
+        key: &(String, String),
+        ttl: Duration,
+    ) -> Option<(SecStr, mysql_async::Pool)> {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        let entry = entries.get_mut(key)?;
+
+        let now = Instant::now();
+        if now.duration_since(entry.verified_at) >= ttl {
+            debug!("Credentials for {key:?} expired from the cache");
+            entries.remove(key);
+            return None;
+        }
+
+        entry.last_used = now;
+        Some((entry.password.clone(), entry.db.clone()))
+    }
+
+    /// Cache a set of credentials which were just verified, evicting the least recently used entries if the cache is full.
+    pub fn insert(
+        &self,
+        key: (String, String),
+        password: SecStr,
+        db: mysql_async::Pool,
+        capacity: usize,
+    ) {
+        let mut entries = self.entries.lock().unwrap_or_else(PoisonError::into_inner);
+        entries.remove(&key);
+
+        while !entries.is_empty() && entries.len() >= capacity {
+            let lru = entries
+                .iter()
+                .min_by_key(|(_, entry)| entry.last_used)
+                .map(|(key, _)| key.clone())
+                .expect("checked not empty");
+            debug!("Evicting credentials for {lru:?} from the cache as it's full");
+            entries.remove(&lru);
+        }
+
+        let now = Instant::now();
+        entries.insert(
+            key,
+            Entry {
+                password,
+                db,
+                verified_at: now,
+                last_used: now,
+            },
+        );
+    }
+
+    /// The MySQL error number a username + database combo was rejected with, if it was recently with the same password.
+    pub fn rejected(&self, key: &(String, String), password: &SecStr) -> Option<u16> {
+        self.rejected
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .get(key)
+            .filter(|rejection| {
+                rejection.at.elapsed() < REJECTED_TTL && rejection.password == *password
+            })
+            .map(|rejection| rejection.errno)
+    }
+
+    /// Remember credentials MySQL rejected.
+    ///
+    /// Once `capacity` are remembered no more are until they expire, so random usernames can't grow it forever.
+    pub fn reject(&self, key: (String, String), password: SecStr, errno: u16, capacity: usize) {
+        let mut rejected = self.rejected.lock().unwrap_or_else(PoisonError::into_inner);
+        rejected.retain(|_, rejection| rejection.at.elapsed() < REJECTED_TTL);
+        if rejected.len() < capacity || rejected.contains_key(&key) {
+            rejected.insert(
+                key,
+                Rejection {
+                    password,
+                    errno,
+                    at: Instant::now(),
+                },
+            );
+        }
+    }
+
+    /// Remove every entry matching `predicate`. Their pools are closed once the connections in use are returned.
+    pub fn invalidate(&self, mut predicate: impl FnMut(&str, &str) -> bool) {
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(username, database), _| {
+                let remove = predicate(username, database);
+                if remove {
+                    debug!("Invalidating cached credentials for {username:?} on {database:?}");
+                }
+                !remove
+            });
+        // A rejected password may be valid now
+        self.rejected
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|(username, database), _| !predicate(username, database));
+    }
+
+    /// Remove all entries which have outlived `ttl`, and rejections which have expired.
+    pub fn evict_expired(&self, ttl: Duration) {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, entry| now.duration_since(entry.verified_at) < ttl);
+        self.rejected
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, rejection| now.duration_since(rejection.at) < REJECTED_TTL);
+    }
+
+    pub fn stats(&self) -> Vec<PoolStats> {
+        let now = Instant::now();
+        self.entries
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .iter()
+            .map(|((username, database), entry)| PoolStats {
+                username: username.clone(),
+                database: database.clone(),
+                age: now.duration_since(entry.verified_at).as_secs(),
+                idle: now.duration_since(entry.last_used).as_secs(),
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,97 @@
+use std::ops::{Deref, DerefMut};
+
+use mysql_async::{prelude::Queryable, Conn};
+use tracing::warn;
+
+/// Get the database of a `USE` statement.
+pub fn parse_use(query: &str) -> Option<String> {
+    let query = query.trim().trim_end_matches(';').trim_end();
+    let (keyword, database) = query.split_once(char::is_whitespace)?;
+    if !keyword.eq_ignore_ascii_case("USE") {
+        return None;
+    }
+
+    let database = database.trim();
+    let database = match database.strip_prefix('`') {
+        Some(quoted) => quoted.strip_suffix('`')?.replace("``", "`"),
+        None if database.contains(|c: char| c.is_whitespace() || c == '`') => return None,
+        None => database.to_string(),
+    };
+    (!database.is_empty()).then_some(database)
+}
+
+/// The statement to switch a connection to a database.
+pub fn use_statement(database: &str) -> String {
+    format!("USE `{}`", database.replace('`', "``"))
+}
+
+/// A connection for queries outside of a transaction, switched to the session's current database.
+///
+/// Connections are returned to the pool when they are dropped, so one which has been switched must be
+/// switched back with [`SessionConn::release`] first. If it's not (Eg. an error), it's closed instead.
+pub struct SessionConn {
+    conn: Option<Conn>,
+    /// The database the connection's pool connects to.
+    default_database: String,
+    switched: bool,
+}
+
+impl SessionConn {
+    pub fn new(conn: Conn, default_database: &str) -> Self {
+        Self {
+            conn: Some(conn),
+            default_database: default_database.to_string(),
+            switched: false,
+        }
+    }
+
+    /// Switch the connection to a database other than the default.
+    pub async fn switch(&mut self, database: Option<&str>) -> Result<(), mysql_async::Error> {
+        let Some(database) = database.filter(|db| *db != self.default_database) else {
+            return Ok(());
+        };
+
+        self.switched = true;
+        self.query_drop(use_statement(database)).await
+    }
+
+    /// Return the connection to the pool.
+    pub async fn release(mut self) {
+        let mut conn = self.conn.take().expect("only taken on release or drop");
+        if !self.switched {
+            return;
+        }
+
+        if let Err(err) = conn.query_drop(use_statement(&self.default_database)).await {
+            warn!(
+                "Error switching connection back to {:?}: {err}",
+                self.default_database
+            );
+            let _ = conn.disconnect().await;
+        }
+    }
+}
+
+impl Deref for SessionConn {
+    type Target = Conn;
+
+    fn deref(&self) -> &Self::Target {
+        self.conn.as_ref().expect("only taken on release or drop")
+    }
+}
+
+impl DerefMut for SessionConn {
+    fn deref_mut(&mut self) -> &mut Self::Target {
+        self.conn.as_mut().expect("only taken on release or drop")
+    }
+}
+
+impl Drop for SessionConn {
+    fn drop(&mut self) {
+        if let Some(conn) = self.conn.take().filter(|_| self.switched) {
+            tokio::spawn(async move {
+                let _ = conn.disconnect().await;
+            });
+        }
+    }
+}
@@ -0,0 +1,272 @@
+use axum::{
+    http::StatusCode,
+    response::{IntoResponse, Response},
+    Json,
+};
+use mysql_async::DriverError;
+use serde::Serialize;
+use serde_json::json;
+
+/// Vitess RPC error codes.
+///
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/vtrpc.proto#L55
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "SCREAMING_SNAKE_CASE")]
+pub enum Code {
+    Canceled,
+    Unknown,
+    InvalidArgument,
+    DeadlineExceeded,
+    NotFound,
+    AlreadyExists,
+    PermissionDenied,
+    ResourceExhausted,
+    FailedPrecondition,
+    Aborted,
+    OutOfRange,
+    Unimplemented,
+    Unavailable,
+    Unauthenticated,
+}
+
+impl Code {
+    /// The HTTP status code used by the Connect protocol for this error code.
+    ///
+    /// Ref: https://connectrpc.com/docs/protocol/#error-codes
+    pub fn status(self) -> StatusCode {
+        match self {
+            Self::Canceled => StatusCode::from_u16(499).expect("499 is a valid status code"),
+            Self::Unknown => StatusCode::INTERNAL_SERVER_ERROR,
+            Self::InvalidArgument | Self::FailedPrecondition | Self::OutOfRange => {
+                StatusCode::BAD_REQUEST
+            }
+            Self::DeadlineExceeded => StatusCode::GATEWAY_TIMEOUT,
+            Self::NotFound => StatusCode::NOT_FOUND,
+            Self::AlreadyExists | Self::Aborted => StatusCode::CONFLICT,
+            Self::PermissionDenied => StatusCode::FORBIDDEN,
+            Self::ResourceExhausted => StatusCode::TOO_MANY_REQUESTS,
+            Self::Unimplemented => StatusCode::NOT_IMPLEMENTED,
+            Self::Unavailable => StatusCode::SERVICE_UNAVAILABLE,
+            Self::Unauthenticated => StatusCode::UNAUTHORIZED,
+        }
+    }
+
+    /// The name Vitess uses for this code in error messages (Eg. `code = AlreadyExists desc = ...`).
+    fn name(self) -> &'static str {
+        match self {
+            Self::Canceled => "Canceled",
+            Self::Unknown => "Unknown",
+            Self::InvalidArgument => "InvalidArgument",
+            Self::DeadlineExceeded => "DeadlineExceeded",
+            Self::NotFound => "NotFound",
+            Self::AlreadyExists => "AlreadyExists",
+            Self::PermissionDenied => "PermissionDenied",
+            Self::ResourceExhausted => "ResourceExhausted",
+            Self::FailedPrecondition => "FailedPrecondition",
+            Self::Aborted => "Aborted",
+            Self::OutOfRange => "OutOfRange",
+            Self::Unimplemented => "Unimplemented",
+            Self::Unavailable => "Unavailable",
+            Self::Unauthenticated => "Unauthenticated",
+        }
+    }
+
+    /// Map a MySQL server error number to the code Vitess would return for it.
+    ///
+    /// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/go/vt/vttablet/tabletserver/tabletserver.go#L1640
+    pub fn from_errno(errno: u16) -> Self {
+        match errno {
+            // ER_NOT_SUPPORTED_YET
+            1235 => Self::Unimplemented,
+            // ER_DISK_FULL, ER_OUTOFMEMORY, ER_OUT_OF_SORTMEMORY, ER_CON_COUNT_ERROR, ER_OUT_OF_RESOURCES,
+            // ER_RECORD_FILE_FULL, ER_HOST_IS_BLOCKED, ER_CANT_CREATE_THREAD, ER_TOO_MANY_DELAYED_THREADS,
+            // ER_NET_PACKET_TOO_LARGE, ER_TOO_MANY_USER_CONNECTIONS, ER_LOCK_TABLE_FULL, ER_USER_LIMIT_REACHED
+            1021 | 1037 | 1038 | 1040 | 1041 | 1114 | 1129 | 1135 | 1151 | 1153 | 1203 | 1206
+            | 1226 => Self::ResourceExhausted,
+            // ER_LOCK_WAIT_TIMEOUT, ER_QUERY_TIMEOUT
+            1205 | 3024 => Self::DeadlineExceeded,
+            // ER_SERVER_SHUTDOWN, CR_CONNECTION_ERROR, CR_CONN_HOST_ERROR, CR_SERVER_GONE_ERROR, CR_SERVER_LOST
+            1053 | 2002 | 2003 | 2006 | 2013 => Self::Unavailable,
+            // ER_KEY_NOT_FOUND, ER_NO_SUCH_THREAD, ER_BAD_TABLE_ERROR, ER_BAD_FIELD_ERROR, ER_CANT_FIND_UDF,
+            // ER_NONEXISTING_GRANT, ER_NO_SUCH_TABLE, ER_NONEXISTING_TABLE_GRANT, ER_KEY_DOES_NOT_EXITS
+            1032 | 1094 | 1051 | 1054 | 1122 | 1141 | 1146 | 1147 | 1176 => Self::NotFound,
+            // ER_DBACCESS_DENIED_ERROR, ER_ACCESS_DENIED_ERROR, ER_KILL_DENIED_ERROR, ER_TABLEACCESS_DENIED_ERROR,
+            // ER_COLUMNACCESS_DENIED_ERROR, ER_NO_PERMISSION_TO_CREATE_USER, ER_SPECIFIC_ACCESS_DENIED_ERROR,
+            // ER_PROCACCESS_DENIED_ERROR
+            1044 | 1045 | 1095 | 1142 | 1143 | 1211 | 1227 | 1370 => Self::PermissionDenied,
+            // ER_DB_CREATE_EXISTS, ER_TABLE_EXISTS_ERROR, ER_DUP_ENTRY, ER_FILE_EXISTS_ERROR, ER_UDF_EXISTS,
+            // ER_DUP_ENTRY_WITH_KEY_NAME
+            1007 | 1050 | 1062 | 1086 | 1125 | 1586 => Self::AlreadyExists,
+            // ER_GOT_SIGNAL, ER_FORCING_CLOSE, ER_ABORTING_CONNECTION, ER_LOCK_DEADLOCK
+            1078 | 1080 | 1152 | 1213 => Self::Aborted,
+            // ER_QUERY_INTERRUPTED
+            1317 => Self::Canceled,
+            // ER_NO_DB_ERROR, ER_NO_SUCH_INDEX, ER_CANT_DROP_FIELD_OR_KEY, ER_TABLE_NOT_LOCKED_FOR_WRITE,
+            // ER_TABLE_NOT_LOCKED, ER_TOO_BIG_SELECT, ER_NOT_ALLOWED_COMMAND, ER_TOO_LONG_STRING, ER_DUP_UNIQUE,
+            // ER_REQUIRES_PRIMARY_KEY, ER_CANT_DO_THIS_DURING_AN_TRANSACTION, ER_CANNOT_ADD_FOREIGN,
+            // ER_NO_REFERENCED_ROW, ER_ROW_IS_REFERENCED, ER_CANT_UPDATE_WITH_READLOCK, ER_OPERAND_COLUMNS,
+            // ER_SUBQUERY_NO_1_ROW, ER_OPTION_PREVENTS_STATEMENT, ER_NO_DEFAULT_FOR_FIELD,
+            // ER_ROW_IS_REFERENCED_2, ER_NO_REFERENCED_ROW_2, ER_CANT_EXECUTE_IN_READ_ONLY_TRANSACTION
+            1046 | 1082 | 1091 | 1099 | 1100 | 1104 | 1148 | 1162 | 1169 | 1173 | 1179 | 1215
+            | 1216 | 1217 | 1223 | 1241 | 1242 | 1290 | 1364 | 1451 | 1452 | 1792 => {
+                Self::FailedPrecondition
+            }
+            // ER_DATA_OUT_OF_RANGE
+            1264 => Self::OutOfRange,
+            // Syntax errors, bad values and everything else which is the fault of the query.
+            // ER_BAD_NULL_ERROR ... ER_TOO_BIG_FIELDLENGTH, ER_SYNTAX_ERROR, ER_WRONG_COLUMN_NAME,
+            // ER_UNKNOWN_SYSTEM_VARIABLE, ER_WRONG_ARGUMENTS, ER_WRONG_USAGE, ER_WRONG_VALUE_FOR_VAR,
+            // ER_WRONG_TYPE_FOR_VAR, ER_DERIVED_MUST_HAVE_ALIAS, WARN_DATA_TRUNCATED, ER_CANT_AGGREGATE_2COLLATIONS,
+            // ER_UNKNOWN_COLLATION, ER_TRUNCATED_WRONG_VALUE, ER_UNKNOWN_TIME_ZONE, ER_INVALID_CHARACTER_STRING,
+            // ER_TRUNCATED_WRONG_VALUE_FOR_FIELD, ER_DATA_TOO_LONG, ER_WRONG_VALUE_FOR_TYPE, ER_INVALID_JSON_TEXT
+            1047..=1049
+            | 1052
+            | 1055..=1061
+            | 1063..=1075
+            | 1109..=1111
+            | 1113
+            | 1115
+            | 1136
+            | 1138
+            | 1149
+            | 1166
+            | 1193
+            | 1210
+            | 1221
+            | 1222
+            | 1231
+            | 1232
+            | 1248
+            | 1265
+            | 1267
+            | 1273
+            | 1292
+            | 1298
+            | 1300
+            | 1366
+            | 1406
+            | 1411
+            | 3140 => Self::InvalidArgument,
+            _ => Self::Unknown,
+        }
+    }
+}
+
+/// An error in the format returned by Vitess and understood by `@planetscale/database-js`.
+#[derive(Debug, Serialize)]
+pub struct VitessError {
+    pub message: String,
+    pub code: Code,
+}
+
+impl VitessError {
+    pub fn new(code: Code, message: impl Into<String>) -> Self {
+        Self {
+            message: message.into(),
+            code,
+        }
+    }
+
+    /// Convert an error from executing a query into the format Vitess would return it in.
+    ///
+    /// The message matches Planetscale (Eg. `... desc = Duplicate entry '1' for key 'PRIMARY' (errno 1062) (sqlstate 23000) ...`)
+    /// as drivers parse the error number and SQL state out of it.
+    pub fn from_mysql(err: &mysql_async::Error, (username, database): &(String, String)) -> Self {
+        match err {
+            mysql_async::Error::Server(err) => {
+                let code = Code::from_errno(err.code);
+                Self::new(
+                    code,
+                    format!(
+                        "target: {database}.-.primary: vttablet: rpc error: code = {} desc = {} (errno {}) (sqlstate {}) (CallerID: {username})",
+                        code.name(),
+                        err.message,
+                        err.code,
+                        err.state
+                    ),
+                )
+            }
+            mysql_async::Error::Driver(
+                DriverError::MissingNamedParam { .. }
+                | DriverError::MixedParams
+                | DriverError::NamedParamsForPositionalQuery
+                | DriverError::StmtParamsMismatch { .. },
+            ) => Self::new(Code::InvalidArgument, err.to_string()),
+            mysql_async::Error::Io(_) => Self::new(Code::Unavailable, err.to_string()),
+            _ => Self::new(Code::Unknown, err.to_string()),
+        }
+    }
+
+    /// Is this an error from the query itself (Eg. a syntax error or duplicate key) rather than the server or the request?
+    /// These are returned inside the result with a `200` status code like Vitess does.
+    pub fn is_query_error(err: &mysql_async::Error) -> bool {
+        matches!(
+            err,
+            mysql_async::Error::Server(_)
+                | mysql_async::Error::Driver(
+                    DriverError::MissingNamedParam { .. }
+                        | DriverError::MixedParams
+                        | DriverError::NamedParamsForPositionalQuery
+                        | DriverError::StmtParamsMismatch { .. }
+                )
+        )
+    }
+}
+
+impl IntoResponse for VitessError {
+    fn into_response(self) -> Response {
+        (self.code.status(), Json(json!({ "error": self }))).into_response()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn maps_errnos() {
+        let cases = [
+            (1235, Code::Unimplemented),
+            (1040, Code::ResourceExhausted),
+            (1226, Code::ResourceExhausted),
+            (1205, Code::DeadlineExceeded),
+            (3024, Code::DeadlineExceeded),
+            (2013, Code::Unavailable),
+            (1146, Code::NotFound),
+            (1054, Code::NotFound),
+            (1045, Code::PermissionDenied),
+            (1142, Code::PermissionDenied),
+            (1062, Code::AlreadyExists),
+            (1050, Code::AlreadyExists),
+            (1213, Code::Aborted),
+            (1317, Code::Canceled),
+            (1046, Code::FailedPrecondition),
+            (1452, Code::FailedPrecondition),
+            (1264, Code::OutOfRange),
+            (1064, Code::InvalidArgument),
+            (1049, Code::InvalidArgument),
+            (1366, Code::InvalidArgument),
+            (3140, Code::InvalidArgument),
+            (1, Code::Unknown),
+            (9999, Code::Unknown),
+        ];
+        for (errno, code) in cases {
+            assert_eq!(Code::from_errno(errno), code, "errno {errno}");
+        }
+    }
+
+    #[test]
+    fn formats_server_errors_like_vitess() {
+        let err = mysql_async::Error::Server(mysql_async::ServerError {
+            code: 1062,
+            message: "Duplicate entry '1' for key 'PRIMARY'".into(),
+            state: "23000".into(),
+        });
+        let err = VitessError::from_mysql(&err, &("user".into(), "db".into()));
+        assert_eq!(err.code, Code::AlreadyExists);
+        assert_eq!(
+            err.message,
+            "target: db.-.primary: vttablet: rpc error: code = AlreadyExists desc = Duplicate entry '1' for key 'PRIMARY' (errno 1062) (sqlstate 23000) (CallerID: user)"
+        );
+    }
+}
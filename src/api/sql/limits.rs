@@ -0,0 +1,98 @@
+use std::{
+    collections::HashMap,
+    sync::{Mutex, PoisonError},
+    time::Instant,
+};
+
+/// A token bucket for each MySQL user which limits how many requests per second they can make.
+///
+/// A user can burst up to one second of requests after being idle.
+#[derive(Default)]
+pub struct RateLimiter {
+    buckets: Mutex<HashMap<String, Bucket>>,
+}
+
+struct Bucket {
+    tokens: f64,
+    updated_at: Instant,
+}
+
+impl RateLimiter {
+    /// Take a token from the user's bucket, returning `false` if it's empty.
+    pub fn check(&self, username: &str, per_second: u32) -> bool {
+        self.take(username, per_second, Instant::now())
+    }
+
+    fn take(&self, username: &str, per_second: u32, now: Instant) -> bool {
+        let rate = f64::from(per_second);
+        let mut buckets = self.buckets.lock().unwrap_or_else(PoisonError::into_inner);
+        let bucket = buckets.entry(username.to_string()).or_insert(Bucket {
+            tokens: rate,
+            updated_at: now,
+        });
+
+        let elapsed = now.duration_since(bucket.updated_at).as_secs_f64();
+        bucket.tokens = (bucket.tokens + elapsed * rate).min(rate);
+        bucket.updated_at = now;
+
+        if bucket.tokens < 1.0 {
+            return false;
+        }
+        bucket.tokens -= 1.0;
+        true
+    }
+
+    /// Remove the buckets which have been idle long enough to be full, as they are the same as a new bucket.
+    pub fn evict_idle(&self) {
+        self.evict_idle_at(Instant::now());
+    }
+
+    fn evict_idle_at(&self, now: Instant) {
+        self.buckets
+            .lock()
+            .unwrap_or_else(PoisonError::into_inner)
+            .retain(|_, bucket| now.duration_since(bucket.updated_at).as_secs() < 1);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::time::Duration;
+
+    use super::*;
+
+    #[test]
+    fn limits_each_user() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+
+        // A new user can burst one second of requests
+        assert!((0..5).all(|_| limiter.take("a", 5, now)));
+        assert!(!limiter.take("a", 5, now));
+        assert!(limiter.take("b", 5, now));
+
+        // Tokens are refilled at the rate
+        let later = now + Duration::from_millis(500);
+        assert!(limiter.take("a", 5, later));
+        assert!(limiter.take("a", 5, later));
+        assert!(!limiter.take("a", 5, later));
+
+        // The bucket doesn't fill past one second of requests
+        let idle = later + Duration::from_secs(10);
+        assert!((0..5).all(|_| limiter.take("a", 5, idle)));
+        assert!(!limiter.take("a", 5, idle));
+    }
+
+    #[test]
+    fn evicts_idle_buckets() {
+        let limiter = RateLimiter::default();
+        let now = Instant::now();
+        limiter.take("idle", 1, now);
+        limiter.take("active", 1, now + Duration::from_millis(1500));
+
+        limiter.evict_idle_at(now + Duration::from_secs(2));
+        let buckets = limiter.buckets.lock().unwrap();
+        assert!(!buckets.contains_key("idle"));
+        assert!(buckets.contains_key("active"));
+    }
+}
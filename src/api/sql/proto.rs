@@ -0,0 +1,602 @@
+use std::collections::HashMap;
+
+use axum::{
+    body::{to_bytes, Body},
+    extract::Request,
+    http::{header, HeaderValue, StatusCode},
+    middleware::Next,
+    response::{IntoResponse, Response},
+};
+use base64::{engine::general_purpose::STANDARD, Engine};
+use prost::Message;
+use serde_json::{json, Value};
+use tracing::error;
+
+use super::error::{Code, VitessError};
+
+/// The content type of a Connect unary request or response using protobuf.
+const CONTENT_TYPE: &str = "application/proto";
+/// The maximum size of a request body, which matches the limit of axum's `Json` extractor.
+const MAX_REQUEST_SIZE: usize = 2 * 1024 * 1024;
+
+/// Support the protobuf encoding of the Connect protocol, which is used by PlanetScale's non-JS clients.
+///
+/// The handlers speak the JSON encoding, which is the canonical JSON mapping of the same messages,
+/// so `application/proto` requests and responses are transcoded to and from it.
+///
+/// Like Connect, error responses are always JSON regardless of the encoding of the request.
+pub async fn transcode(request: Request, next: Next) -> Response {
+    let is_proto = request
+        .headers()
+        .get(header::CONTENT_TYPE)
+        .is_some_and(|v| v.as_bytes().eq_ignore_ascii_case(CONTENT_TYPE.as_bytes()));
+    if !is_proto {
+        return next.run(request).await;
+    }
+
+    let (mut parts, body) = request.into_parts();
+    let method = match parts.uri.path() {
+        "/Execute" => Method::Execute,
+        "/CreateSession" => Method::CreateSession,
+        path => {
+            return VitessError::new(
+                Code::Unimplemented,
+                format!("{path:?} only supports the 'application/json' encoding"),
+            )
+            .into_response()
+        }
+    };
+
+    let Ok(body) = to_bytes(body, MAX_REQUEST_SIZE).await else {
+        return VitessError::new(Code::InvalidArgument, "failed to read request body")
+            .into_response();
+    };
+    let json = match method.decode_request(&body) {
+        Ok(json) => json,
+        Err(err) => return err.into_response(),
+    };
+    parts.headers.insert(
+        header::CONTENT_TYPE,
+        HeaderValue::from_static("application/json"),
+    );
+    let body = serde_json::to_vec(&json).expect("serde_json::Value is always serializable");
+
+    let response = next.run(Request::from_parts(parts, Body::from(body))).await;
+    if response.status() != StatusCode::OK {
+        return response;
+    }
+
+    let (mut parts, body) = response.into_parts();
+    let body = match to_bytes(body, usize::MAX).await {
+        Ok(body) => body,
+        Err(err) => {
+            error!("Error reading response to transcode: {err}");
+            return VitessError::new(Code::Unknown, "failed to encode response").into_response();
+        }
+    };
+    let body = match serde_json::from_slice(&body).map_err(|err| err.to_string()) {
+        Ok(json) => match method.encode_response(&json) {
+            Ok(body) => body,
+            Err(err) => {
+                error!("Error encoding response: {err}");
+                return VitessError::new(Code::Unknown, "failed to encode response")
+                    .into_response();
+            }
+        },
+        Err(err) => {
+            error!("Error parsing response to transcode: {err}");
+            return VitessError::new(Code::Unknown, "failed to encode response").into_response();
+        }
+    };
+    parts
+        .headers
+        .insert(header::CONTENT_TYPE, HeaderValue::from_static(CONTENT_TYPE));
+    parts.headers.remove(header::CONTENT_LENGTH);
+    Response::from_parts(parts, Body::from(body))
+}
+
+#[derive(Debug, Clone, Copy)]
+enum Method {
+    Execute,
+    CreateSession,
+}
+
+impl Method {
+    /// Decode a protobuf request into it's JSON encoding.
+    fn decode_request(self, body: &[u8]) -> Result<Value, VitessError> {
+        let invalid = |err: &dyn std::fmt::Display| {
+            VitessError::new(
+                Code::InvalidArgument,
+                format!("failed to decode request: {err}"),
+            )
+        };
+
+        Ok(match self {
+            Self::Execute => {
+                let req = ExecuteRequest::decode(body).map_err(|err| invalid(&err))?;
+                let session = req
+                    .session
+                    // Clients send an empty session before they have one
+                    .filter(|s| !s.signature.is_empty())
+                    .map(|s| s.to_json())
+                    .transpose()
+                    .map_err(|err| invalid(&format!("invalid session: {err}")))?;
+                let bind_vars = req
+                    .bind_vars
+                    .into_iter()
+                    .map(|(name, var)| {
+                        let var = var
+                            .to_json()
+                            .map_err(|err| invalid(&format!("bind variable {name:?}: {err}")))?;
+                        Ok((name, var))
+                    })
+                    .collect::<Result<serde_json::Map<_, _>, _>>()?;
+
+                json!({
+                    "session": session,
+                    "query": req.query,
+                    "bindVars": bind_vars,
+                })
+            }
+            Self::CreateSession => {
+                CreateSessionRequest::decode(body).map_err(|err| invalid(&err))?;
+                json!({})
+            }
+        })
+    }
+
+    /// Encode the JSON response from a handler as protobuf.
+    fn encode_response(self, json: &Value) -> Result<Vec<u8>, String> {
+        Ok(match self {
+            Self::Execute => ExecuteResponse {
+                session: Session::from_json(&json["session"]),
+                result: json
+                    .get("result")
+                    .filter(|v| !v.is_null())
+                    .map(QueryResult::from_json)
+                    .transpose()?,
+                error: json
+                    .get("error")
+                    .filter(|v| !v.is_null())
+                    .map(RpcError::from_json),
+                timing: json["timing"].as_f64().unwrap_or_default(),
+            }
+            .encode_to_vec(),
+            Self::CreateSession => CreateSessionResponse {
+                session: Session::from_json(&json["session"]),
+            }
+            .encode_to_vec(),
+        })
+    }
+}
+
+/// Ref: https://github.com/planetscale/psdb/blob/main/proto/psdb/v1alpha1/database.proto
+#[derive(Clone, PartialEq, Message)]
+pub struct Session {
+    /// The opaque session state, which is the JSON session object (the transaction ID and current database).
+    #[prost(bytes = "vec", tag = "1")]
+    pub signature: Vec<u8>,
+}
+
+impl Session {
+    fn from_json(json: &Value) -> Option<Self> {
+        json.as_object().filter(|session| !session.is_empty())?;
+        Some(Self {
+            signature: serde_json::to_vec(json).ok()?,
+        })
+    }
+
+    fn to_json(&self) -> Result<Value, serde_json::Error> {
+        serde_json::from_slice(&self.signature)
+    }
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExecuteRequest {
+    #[prost(message, optional, tag = "1")]
+    pub session: Option<Session>,
+    #[prost(string, tag = "2")]
+    pub query: String,
+    #[prost(map = "string, message", tag = "3")]
+    pub bind_vars: HashMap<String, BindVariable>,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct ExecuteResponse {
+    #[prost(message, optional, tag = "1")]
+    pub session: Option<Session>,
+    #[prost(message, optional, tag = "2")]
+    pub result: Option<QueryResult>,
+    #[prost(message, optional, tag = "3")]
+    pub error: Option<RpcError>,
+    #[prost(double, tag = "4")]
+    pub timing: f64,
+}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CreateSessionRequest {}
+
+#[derive(Clone, PartialEq, Message)]
+pub struct CreateSessionResponse {
+    // `branch = 1` and `user = 2` are not supported
+    #[prost(message, optional, tag = "3")]
+    pub session: Option<Session>,
+}
+
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/query.proto#L296
+#[derive(Clone, PartialEq, Message)]
+pub struct BindVariable {
+    #[prost(int32, tag = "1")]
+    pub r#type: i32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+    #[prost(message, repeated, tag = "3")]
+    pub values: Vec<TypedValue>,
+}
+
+impl BindVariable {
+    fn to_json(&self) -> Result<Value, String> {
+        Ok(json!({
+            "type": type_name(self.r#type)?,
+            "value": STANDARD.encode(&self.value),
+            "values": self
+                .values
+                .iter()
+                .map(TypedValue::to_json)
+                .collect::<Result<Vec<_>, _>>()?,
+        }))
+    }
+}
+
+/// A value in a `TUPLE` bind variable (`query.Value`).
+#[derive(Clone, PartialEq, Message)]
+pub struct TypedValue {
+    #[prost(int32, tag = "1")]
+    pub r#type: i32,
+    #[prost(bytes = "vec", tag = "2")]
+    pub value: Vec<u8>,
+}
+
+impl TypedValue {
+    fn to_json(&self) -> Result<Value, String> {
+        Ok(json!({
+            "type": type_name(self.r#type)?,
+            "value": STANDARD.encode(&self.value),
+        }))
+    }
+}
+
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/query.proto#L416
+#[derive(Clone, PartialEq, Message)]
+pub struct QueryResult {
+    #[prost(message, repeated, tag = "1")]
+    pub fields: Vec<Field>,
+    #[prost(uint64, tag = "2")]
+    pub rows_affected: u64,
+    #[prost(uint64, tag = "3")]
+    pub insert_id: u64,
+    #[prost(message, repeated, tag = "4")]
+    pub rows: Vec<Row>,
+}
+
+impl QueryResult {
+    fn from_json(json: &Value) -> Result<Self, String> {
+        Ok(Self {
+            fields: array(&json["fields"])
+                .map(Field::from_json)
+                .collect::<Result<_, _>>()?,
+            rows_affected: uint(&json["rowsAffected"]),
+            insert_id: uint(&json["insertId"]),
+            rows: array(&json["rows"]).map(Row::from_json).collect(),
+        })
+    }
+}
+
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/query.proto#L380
+#[derive(Clone, PartialEq, Message)]
+pub struct Field {
+    #[prost(string, tag = "1")]
+    pub name: String,
+    #[prost(int32, tag = "2")]
+    pub r#type: i32,
+    #[prost(string, tag = "3")]
+    pub table: String,
+    #[prost(string, tag = "4")]
+    pub org_table: String,
+    #[prost(string, tag = "5")]
+    pub database: String,
+    #[prost(string, tag = "6")]
+    pub org_name: String,
+    #[prost(uint32, tag = "7")]
+    pub column_length: u32,
+    #[prost(uint32, tag = "8")]
+    pub charset: u32,
+    #[prost(uint32, tag = "9")]
+    pub decimals: u32,
+    #[prost(uint32, tag = "10")]
+    pub flags: u32,
+}
+
+impl Field {
+    fn from_json(json: &Value) -> Result<Self, String> {
+        let string = |key: &str| json[key].as_str().unwrap_or_default().to_string();
+        let number = |key: &str| u32::try_from(uint(&json[key])).unwrap_or_default();
+
+        Ok(Self {
+            name: string("name"),
+            // Like protobuf, the type being omitted means `NULL_TYPE`
+            r#type: json["type"].as_str().map_or(Ok(0), type_number)?,
+            table: string("table"),
+            org_table: string("orgTable"),
+            database: string("database"),
+            org_name: string("orgName"),
+            column_length: number("columnLength"),
+            charset: number("charset"),
+            decimals: number("decimals"),
+            flags: number("flags"),
+        })
+    }
+}
+
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/query.proto#L407
+#[derive(Clone, PartialEq, Message)]
+pub struct Row {
+    #[prost(sint64, repeated, tag = "1")]
+    pub lengths: Vec<i64>,
+    #[prost(bytes = "vec", tag = "2")]
+    pub values: Vec<u8>,
+}
+
+impl Row {
+    fn from_json(json: &Value) -> Self {
+        Self {
+            lengths: array(&json["lengths"])
+                .map(|v| v.as_i64().unwrap_or(-1))
+                .collect(),
+            values: json["values"]
+                .as_str()
+                .and_then(|v| STANDARD.decode(v).ok())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/vtrpc.proto#L248
+#[derive(Clone, PartialEq, Message)]
+pub struct RpcError {
+    #[prost(string, tag = "2")]
+    pub message: String,
+    #[prost(int32, tag = "3")]
+    pub code: i32,
+}
+
+impl RpcError {
+    fn from_json(json: &Value) -> Self {
+        let code = json["code"].as_str().unwrap_or_default();
+        Self {
+            message: json["message"].as_str().unwrap_or_default().to_string(),
+            code: CODES
+                .iter()
+                .find(|(name, _)| *name == code)
+                .map_or(2 /* UNKNOWN */, |(_, number)| *number),
+        }
+    }
+}
+
+fn array(json: &Value) -> impl Iterator<Item = &Value> {
+    json.as_array().into_iter().flatten()
+}
+
+/// Like the protobuf JSON mapping, 64-bit integers may be strings.
+fn uint(json: &Value) -> u64 {
+    match json {
+        Value::String(v) => v.parse().unwrap_or_default(),
+        v => v.as_u64().unwrap_or_default(),
+    }
+}
+
+/// The `vtrpc.Code` enum.
+const CODES: [(&str, i32); 14] = [
+    ("CANCELED", 1),
+    ("UNKNOWN", 2),
+    ("INVALID_ARGUMENT", 3),
+    ("DEADLINE_EXCEEDED", 4),
+    ("NOT_FOUND", 5),
+    ("ALREADY_EXISTS", 6),
+    ("PERMISSION_DENIED", 7),
+    ("RESOURCE_EXHAUSTED", 8),
+    ("FAILED_PRECONDITION", 9),
+    ("ABORTED", 10),
+    ("OUT_OF_RANGE", 11),
+    ("UNIMPLEMENTED", 12),
+    ("UNAVAILABLE", 14),
+    ("UNAUTHENTICATED", 16),
+];
+
+/// The `query.Type` enum.
+///
+/// Ref: https://github.com/vitessio/vitess/blob/9e40015748ede158357bd7291f583db138abc3df/proto/query.proto#L101
+const TYPES: [(&str, i32); 35] = [
+    ("NULL_TYPE", 0),
+    ("INT8", 257),
+    ("UINT8", 770),
+    ("INT16", 259),
+    ("UINT16", 772),
+    ("INT24", 261),
+    ("UINT24", 774),
+    ("INT32", 263),
+    ("UINT32", 776),
+    ("INT64", 265),
+    ("UINT64", 778),
+    ("FLOAT32", 1035),
+    ("FLOAT64", 1036),
+    ("TIMESTAMP", 2061),
+    ("DATE", 2062),
+    ("TIME", 2063),
+    ("DATETIME", 2064),
+    ("YEAR", 785),
+    ("DECIMAL", 18),
+    ("TEXT", 6163),
+    ("BLOB", 10260),
+    ("VARCHAR", 6165),
+    ("VARBINARY", 10262),
+    ("CHAR", 6167),
+    ("BINARY", 10264),
+    ("BIT", 2073),
+    ("ENUM", 2074),
+    ("SET", 2075),
+    ("TUPLE", 28),
+    ("GEOMETRY", 2077),
+    ("JSON", 2078),
+    ("EXPRESSION", 31),
+    ("HEXNUM", 4128),
+    ("HEXVAL", 4129),
+    ("BITNUM", 4130),
+];
+
+fn type_name(number: i32) -> Result<&'static str, String> {
+    TYPES
+        .iter()
+        .find(|(_, n)| *n == number)
+        .map(|(name, _)| *name)
+        .ok_or_else(|| format!("unknown type {number}"))
+}
+
+fn type_number(name: &str) -> Result<i32, String> {
+    TYPES
+        .iter()
+        .find(|(n, _)| *n == name)
+        .map(|(_, number)| *number)
+        .ok_or_else(|| format!("unknown type {name:?}"))
+}
+
+#[cfg(test)]
+mod tests {
+    use cityscale_client::types::SqlRequest;
+
+    use super::*;
+
+    #[test]
+    fn transcodes_execute_requests() {
+        let session = json!({ "id": "67e55044-10b1-426f-9247-bb680e5fe0c8", "database": "other" });
+        let var = |r#type, value: &str| BindVariable {
+            r#type,
+            value: value.into(),
+            values: Vec::new(),
+        };
+        let request = ExecuteRequest {
+            session: Some(Session::from_json(&session).unwrap()),
+            query: "SELECT * FROM users WHERE id IN ::ids AND name = :name".into(),
+            bind_vars: HashMap::from([
+                ("name".into(), var(6165, "Oscar")),
+                ("count".into(), var(265, "-12")),
+                (
+                    "ids".into(),
+                    BindVariable {
+                        r#type: 28,
+                        value: Vec::new(),
+                        values: vec![TypedValue {
+                            r#type: 778,
+                            value: b"1".to_vec(),
+                        }],
+                    },
+                ),
+            ]),
+        };
+
+        let transcoded = Method::Execute
+            .decode_request(&request.encode_to_vec())
+            .unwrap();
+        let transcoded = serde_json::from_value::<SqlRequest>(transcoded).unwrap();
+        // The same request from `database-js`
+        let expected = serde_json::from_value::<SqlRequest>(json!({
+            "session": session,
+            "query": "SELECT * FROM users WHERE id IN ::ids AND name = :name",
+            "bindVars": {
+                "name": { "type": "VARCHAR", "value": "T3NjYXI=" },
+                "count": { "type": "INT64", "value": "LTEy" },
+                "ids": { "type": "TUPLE", "value": "", "values": [{ "type": "UINT64", "value": "MQ==" }] },
+            },
+        }))
+        .unwrap();
+        assert_eq!(transcoded.query, expected.query);
+        assert_eq!(transcoded.session, expected.session);
+        assert_eq!(transcoded.bind_vars, expected.bind_vars);
+
+        // An empty session is sent before the client has one
+        let request = ExecuteRequest {
+            session: Some(Session::default()),
+            query: "SELECT 1".into(),
+            bind_vars: HashMap::new(),
+        };
+        let transcoded = Method::Execute
+            .decode_request(&request.encode_to_vec())
+            .unwrap();
+        assert!(transcoded["session"].is_null());
+
+        let request = ExecuteRequest {
+            session: None,
+            query: "SELECT :a".into(),
+            bind_vars: HashMap::from([("a".into(), var(12345, "1"))]),
+        };
+        assert!(Method::Execute
+            .decode_request(&request.encode_to_vec())
+            .is_err());
+    }
+
+    #[test]
+    fn transcodes_execute_responses() {
+        let json = json!({
+            "session": { "database": "other" },
+            "result": {
+                "fields": [
+                    { "name": "id", "type": "UINT64", "table": "users", "columnLength": 20, "charset": 63, "flags": 49699 },
+                    { "name": "nothing" },
+                ],
+                "rowsAffected": "2",
+                "insertId": 3,
+                "rows": [{ "lengths": ["1", -1], "values": "MQ==" }],
+            },
+            "error": { "code": "NOT_FOUND", "message": "missing" },
+            "timing": 0.5,
+        });
+
+        let response = Method::Execute.encode_response(&json).unwrap();
+        let response = ExecuteResponse::decode(response.as_slice()).unwrap();
+        assert_eq!(
+            response.session.unwrap().to_json().unwrap(),
+            json["session"]
+        );
+        let result = response.result.unwrap();
+        assert_eq!(
+            result.fields[0],
+            Field {
+                name: "id".into(),
+                r#type: 778,
+                table: "users".into(),
+                column_length: 20,
+                charset: 63,
+                flags: 49699,
+                ..Default::default()
+            }
+        );
+        assert_eq!(result.fields[1].r#type, 0);
+        assert_eq!((result.rows_affected, result.insert_id), (2, 3));
+        assert_eq!(result.rows[0].values, b"1");
+        let error = response.error.unwrap();
+        assert_eq!((error.code, error.message.as_str()), (5, "missing"));
+        assert_eq!(response.timing, 0.5);
+
+        let unknown = json!({ "result": { "fields": [{ "name": "id", "type": "BIGINT" }] } });
+        assert!(Method::Execute.encode_response(&unknown).is_err());
+    }
+
+    #[test]
+    fn maps_every_type() {
+        for (name, number) in TYPES {
+            assert_eq!(type_name(number), Ok(name));
+            assert_eq!(type_number(name), Ok(number));
+        }
+        assert!(type_name(-1).is_err());
+    }
+}
@@ -0,0 +1,264 @@
+use std::{collections::HashMap, sync::Arc};
+
+use axum::{
+    body::to_bytes,
+    extract::ws::{Message, WebSocket},
+    http::{HeaderMap, StatusCode},
+    response::Response,
+};
+use axum_extra::headers::{authorization::Basic, Authorization};
+use cityscale_client::types::{BindVariable, SqlRequest, TransactionSession};
+use serde::Deserialize;
+use serde_json::{json, Value};
+use tokio::task::{JoinError, JoinSet};
+use tracing::{debug, warn};
+
+use super::{
+    database,
+    error::{Code, VitessError},
+    execute, AppState, ConnectionPool,
+};
+
+/// The most queries from one socket which run at once.
+const MAX_CONCURRENT_FRAMES: usize = 16;
+
+/// A frame sent by the client. Each is answered by a frame with the same `id`.
+#[derive(Deserialize)]
+struct Request {
+    /// Chosen by the client to match the response to the request.
+    #[serde(default)]
+    id: Value,
+    #[serde(flatten)]
+    frame: Frame,
+}
+
+#[derive(Deserialize)]
+#[serde(tag = "type", rename_all = "camelCase")]
+enum Frame {
+    /// Credentials for a socket which wasn't opened with an `Authorization` header (Eg. from a browser).
+    /// This must be the first frame. `username` is in the form `username;db` like the HTTP API.
+    Authenticate {
+        username: String,
+        password: String,
+    },
+    Execute {
+        query: String,
+        #[serde(rename = "bindVars", default)]
+        bind_vars: HashMap<String, BindVariable>,
+    },
+    Begin,
+    Commit,
+    Rollback,
+}
+
+/// Serve the SQL API over a WebSocket.
+///
+/// Each frame is run like a request to `/Execute` with the socket's credentials and the socket keeps the session,
+/// so it's transaction is rolled back when the socket is closed.
+///
+/// Queries outside of a transaction run concurrently so their responses can arrive out of order. A frame which uses or
+/// changes the session (Eg. `Begin` or `USE`) waits for the queries before it and runs on it's own to keep their order.
+pub async fn serve(
+    mut socket: WebSocket,
+    state: Arc<AppState>,
+    pool: Arc<ConnectionPool>,
+    mut auth: Option<Basic>,
+    headers: HeaderMap,
+) {
+    let mut session: Option<TransactionSession> = None;
+    let mut running = JoinSet::new();
+
+    loop {
+        let message = tokio::select! {
+            message = socket.recv(), if running.len() < MAX_CONCURRENT_FRAMES => message,
+            Some(frame) = running.join_next() => {
+                if send(&mut socket, finished(frame)).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+        let Some(message) = message else {
+            break;
+        };
+        let text = match message {
+            Ok(Message::Text(text)) => text,
+            Ok(Message::Close(_)) => break,
+            // Pings are answered for us
+            Ok(Message::Ping(_) | Message::Pong(_)) => continue,
+            Ok(Message::Binary(_)) => {
+                let error = VitessError::new(Code::InvalidArgument, "frames must be text");
+                if send(&mut socket, json!({ "error": error })).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+            Err(err) => {
+                debug!("Error receiving from WebSocket: {err}");
+                break;
+            }
+        };
+
+        let Request { id, frame } = match serde_json::from_str::<Request>(&text) {
+            Ok(request) => request,
+            Err(err) => {
+                let error =
+                    VitessError::new(Code::InvalidArgument, format!("invalid frame: {err}"));
+                if send(&mut socket, json!({ "error": error })).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let (query, bind_vars) = match frame {
+            Frame::Execute { query, bind_vars } => (query, bind_vars),
+            Frame::Begin => ("BEGIN".to_string(), HashMap::new()),
+            Frame::Commit => ("COMMIT".to_string(), HashMap::new()),
+            Frame::Rollback => ("ROLLBACK".to_string(), HashMap::new()),
+            Frame::Authenticate { username, password } => {
+                let response = match auth {
+                    Some(_) => {
+                        let error = VitessError::new(
+                            Code::InvalidArgument,
+                            "the socket is already authenticated",
+                        );
+                        json!({ "id": id, "error": error })
+                    }
+                    None => {
+                        // The HTTP API receives the username URL encoded
+                        let Authorization(credentials) =
+                            Authorization::basic(&username.replace(';', "%3B"), &password);
+                        match super::authenticate(&pool, &state, credentials.clone()).await {
+                            Ok(_) => {
+                                auth = Some(credentials);
+                                json!({ "id": id })
+                            }
+                            Err(response) => to_frame(id, response, &state).await,
+                        }
+                    }
+                };
+                if send(&mut socket, response).await.is_err() {
+                    break;
+                }
+                continue;
+            }
+        };
+
+        let Some(credentials) = auth.clone() else {
+            let error = VitessError::new(
+                Code::Unauthenticated,
+                "the first frame must authenticate the socket",
+            );
+            let _ = send(&mut socket, json!({ "id": id, "error": error })).await;
+            break;
+        };
+
+        let sequential = session.as_ref().is_some_and(|s| s.id.is_some())
+            || matches!(query.as_str(), "BEGIN" | "COMMIT" | "ROLLBACK")
+            || database::parse_use(&query).is_some();
+        let request = SqlRequest {
+            query,
+            session: session.clone(),
+            bind_vars,
+        };
+        let run = {
+            let (state, pool, headers) = (state.clone(), pool.clone(), headers.clone());
+            async move {
+                let response = execute(state.clone(), pool, credentials, headers, request)
+                    .await
+                    .unwrap_or_else(|response| response);
+                to_frame(id, response, &state).await
+            }
+        };
+        if !sequential {
+            running.spawn(run);
+            continue;
+        }
+
+        // The queries before this frame must finish first as it may change the session they run in
+        let mut closed = false;
+        while let Some(frame) = running.join_next().await {
+            closed |= send(&mut socket, finished(frame)).await.is_err();
+        }
+        let mut response = run.await;
+        // The socket holds the session so it's not sent to the client
+        if let Some(value) = response.as_object_mut().and_then(|r| r.remove("session")) {
+            session =
+                serde_json::from_value::<Option<TransactionSession>>(value).unwrap_or_default();
+        }
+        if closed || send(&mut socket, response).await.is_err() {
+            break;
+        }
+    }
+    running.shutdown().await;
+
+    // Closing the socket ends it's transaction
+    if let (Some(credentials), Some(open)) = (auth, session.filter(|s| s.id.is_some())) {
+        debug!(
+            "Rolling back transaction {:?} as it's WebSocket closed",
+            open.id
+        );
+        let request = SqlRequest {
+            query: "ROLLBACK".to_string(),
+            session: Some(open),
+            bind_vars: HashMap::new(),
+        };
+        if let Err(response) = execute(state, pool, credentials, headers, request).await {
+            warn!(
+                "Error rolling back transaction of closed WebSocket: {}",
+                response.status()
+            );
+        }
+    }
+}
+
+/// Convert a response from the HTTP API into a frame.
+async fn to_frame(id: Value, response: Response, state: &AppState) -> Value {
+    let status = response.status();
+    // The rows are limited by `max_result_bytes`, which are base64 encoded along with their fields
+    let limit = (state.config.get().max_result_bytes / 3 * 4).saturating_add(1024 * 1024);
+    let body = match to_bytes(response.into_body(), limit).await {
+        Ok(body) => body,
+        Err(err) => {
+            let error = VitessError::new(Code::Unknown, format!("error reading response: {err}"));
+            return json!({ "id": id, "error": error });
+        }
+    };
+
+    let mut frame = match serde_json::from_slice::<Value>(&body) {
+        Ok(Value::Object(frame)) => frame,
+        _ => {
+            let error = VitessError::new(
+                Code::Unknown,
+                format!("unexpected response with status {status}"),
+            );
+            return json!({ "id": id, "error": error });
+        }
+    };
+    if status != StatusCode::OK && !frame.contains_key("error") {
+        let error = VitessError::new(
+            Code::Unknown,
+            format!("request failed with status {status}"),
+        );
+        frame.insert(
+            "error".into(),
+            serde_json::to_value(error).expect("errors are serializable"),
+        );
+    }
+
+    frame.insert("id".into(), id);
+    Value::Object(frame)
+}
+
+/// The frame of a query which ran concurrently.
+fn finished(frame: Result<Value, JoinError>) -> Value {
+    frame.unwrap_or_else(|err| {
+        warn!("Error running WebSocket frame: {err}");
+        json!({ "error": VitessError::new(Code::Unknown, "error running query") })
+    })
+}
+
+async fn send(socket: &mut WebSocket, frame: Value) -> Result<(), axum::Error> {
+    socket.send(Message::Text(frame.to_string())).await
+}
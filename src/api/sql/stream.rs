@@ -0,0 +1,202 @@
+use std::convert::Infallible;
+
+use axum::{
+    body::Body,
+    http::header,
+    response::{IntoResponse, Response},
+};
+use mysql_async::{
+    prelude::{Protocol, Queryable},
+    Params, QueryResult, Row,
+};
+use serde::de::DeserializeOwned;
+use serde_json::json;
+use tokio::sync::mpsc;
+use tokio_stream::wrappers::ReceiverStream;
+
+use super::{
+    cancel::QueryKiller,
+    encode_row,
+    error::{Code, VitessError},
+    field, row_size, QueryError, TransactionSession,
+};
+
+/// The content type of a Connect streaming request or response using JSON.
+const CONTENT_TYPE: &str = "application/connect+json";
+/// Set on an envelope when it's contents are compressed.
+const FLAG_COMPRESSED: u8 = 0b01;
+/// Set on the final envelope of a response, which contains the error (if any) instead of a message.
+const FLAG_END_STREAM: u8 = 0b10;
+/// The number of rows sent in each message.
+const CHUNK_ROWS: usize = 1000;
+/// The approximate number of bytes of row data sent in each message.
+const CHUNK_BYTES: usize = 256 * 1024;
+/// The number of messages which can be buffered before we stop reading from MySQL, so a slow client applies backpressure.
+const BUFFER: usize = 4;
+
+/// Decode the single message of a Connect server-streaming request.
+///
+/// Ref: https://connectrpc.com/docs/protocol/#streaming-request
+pub fn decode_request<T: DeserializeOwned>(body: &[u8]) -> Result<T, VitessError> {
+    let invalid = |msg: &str| VitessError::new(Code::InvalidArgument, msg);
+
+    let Some((&flags, rest)) = body.split_first() else {
+        return Err(invalid("request body is empty"));
+    };
+    if flags & FLAG_COMPRESSED != 0 {
+        return Err(VitessError::new(
+            Code::Unimplemented,
+            "compressed requests are not supported",
+        ));
+    }
+    let Some((len, msg)) = rest.split_first_chunk::<4>() else {
+        return Err(invalid("request envelope is truncated"));
+    };
+    if msg.len() != u32::from_be_bytes(*len) as usize {
+        return Err(invalid("request envelope length does not match the body"));
+    }
+
+    serde_json::from_slice(msg).map_err(|err| invalid(&format!("failed to decode request: {err}")))
+}
+
+/// Wrap a message in a Connect envelope.
+fn envelope(flags: u8, msg: &serde_json::Value) -> Vec<u8> {
+    let msg = serde_json::to_vec(msg).expect("serde_json::Value is always serializable");
+    let len = u32::try_from(msg.len()).expect("a single message is never larger than 4GiB");
+
+    let mut buf = Vec::with_capacity(5 + msg.len());
+    buf.push(flags);
+    buf.extend(len.to_be_bytes());
+    buf.extend(msg);
+    buf
+}
+
+/// The sending half of a streaming response.
+pub struct Sender {
+    sender: mpsc::Sender<Result<Vec<u8>, Infallible>>,
+    session: Option<TransactionSession>,
+    preserve_fractional_seconds: bool,
+}
+
+impl Sender {
+    /// Create a streaming response. It will stay open until the returned `Sender` is ended or dropped.
+    pub fn new(
+        session: Option<TransactionSession>,
+        preserve_fractional_seconds: bool,
+    ) -> (Self, Response) {
+        let (sender, receiver) = mpsc::channel(BUFFER);
+        let response = (
+            [(header::CONTENT_TYPE, CONTENT_TYPE)],
+            Body::from_stream(ReceiverStream::new(receiver)),
+        )
+            .into_response();
+
+        (
+            Self {
+                sender,
+                session,
+                preserve_fractional_seconds,
+            },
+            response,
+        )
+    }
+
+    /// Send a message. Returns `false` if the client has gone away.
+    async fn send(&self, result: serde_json::Value) -> bool {
+        self.sender
+            .send(Ok(envelope(0, &json!({ "result": result }))))
+            .await
+            .is_ok()
+    }
+
+    async fn send_rows(&self, rows: &mut Vec<Row>) -> bool {
+        let rows = rows
+            .drain(..)
+            .map(|row| encode_row(row, self.preserve_fractional_seconds))
+            .collect::<Vec<_>>();
+        self.send(json!({ "rows": rows })).await
+    }
+
+    /// End the stream, with the error which caused it to end early.
+    pub async fn end(self, error: Option<VitessError>) {
+        let msg = match error {
+            Some(error) => json!({ "error": error }),
+            None => json!({}),
+        };
+        // If the client is gone there is no one to tell
+        let _ = self.sender.send(Ok(envelope(FLAG_END_STREAM, &msg))).await;
+    }
+}
+
+/// Run a query, sending it's rows to the client in chunks as they are read from MySQL.
+///
+/// Like Vitess, the first message contains the fields and following messages contain the rows.
+///
+/// If the client goes away the request is canceled using `killer`, so the rest of the result doesn't need to be read.
+pub async fn run_query(
+    conn: &mut impl Queryable,
+    query: &str,
+    params: Params,
+    sender: &Sender,
+    killer: &QueryKiller,
+) -> Result<(), QueryError> {
+    if let Params::Empty = params {
+        stream_result(conn.query_iter(query).await?, sender, killer).await
+    } else {
+        stream_result(conn.exec_iter(query, params).await?, sender, killer).await
+    }
+}
+
+async fn stream_result<P: Protocol>(
+    mut result: QueryResult<'_, 'static, P>,
+    sender: &Sender,
+    killer: &QueryKiller,
+) -> Result<(), QueryError> {
+    let fields = result.columns_ref().iter().map(field).collect::<Vec<_>>();
+    let mut connected = sender
+        .sender
+        .send(Ok(envelope(
+            0,
+            &json!({ "session": sender.session.as_ref().and_then(TransactionSession::non_empty), "result": { "fields": fields } }),
+        )))
+        .await
+        .is_ok();
+
+    let mut rows = Vec::new();
+    let mut size = 0;
+    while connected {
+        let Some(row) = result.next().await? else {
+            break;
+        };
+        size += row_size(&row);
+        rows.push(row);
+
+        if rows.len() >= CHUNK_ROWS || size >= CHUNK_BYTES {
+            connected = sender.send_rows(&mut rows).await;
+            size = 0;
+        }
+    }
+
+    if !connected {
+        killer.cancel();
+    }
+
+    let rows_affected = result.affected_rows();
+    let last_insert_id = result.last_insert_id();
+    // If the client went away this discards what's left of the result so the connection can be reused
+    result.drop_result().await?;
+
+    if connected && !rows.is_empty() {
+        connected = sender.send_rows(&mut rows).await;
+    }
+    if connected && (rows_affected != 0 || last_insert_id.is_some()) {
+        sender
+            .send(json!({
+                "rowsAffected": rows_affected.to_string(),
+                "insertId": last_insert_id.map(|v| v.to_string()),
+            }))
+            .await;
+    }
+
+    Ok(())
+}
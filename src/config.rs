@@ -4,6 +4,7 @@ use std::{
     ops::{Deref, DerefMut},
     path::PathBuf,
     sync::{Arc, PoisonError, RwLock, RwLockReadGuard, RwLockWriteGuard},
+    time::Duration,
 };
 
 use rand::distributions::{Alphanumeric, DistString};
@@ -19,6 +20,266 @@ pub struct Config {
     /// User's who are allowed to access the admin panel.
     #[serde(default, skip_serializing_if = "HashMap::is_empty")]
     pub admins: HashMap<String, String>,
+    /// How long (in seconds) a `psdb` transaction session may sit idle before it is rolled back.
+    #[serde(default = "default_session_idle_timeout")]
+    pub session_idle_timeout: u64,
+    /// The maximum lifetime (in seconds) of a `psdb` transaction session, regardless of activity.
+    #[serde(default = "default_session_max_lifetime")]
+    pub session_max_lifetime: u64,
+    /// Include the fractional seconds of `DATETIME`, `TIMESTAMP` and `TIME` values in `psdb` results.
+    /// Planetscale drops them so this is disabled by default.
+    #[serde(default)]
+    pub preserve_fractional_seconds: bool,
+    /// The number of prepared statements cached on each `psdb` connection.
+    /// They are only reused within a session, as pooled connections are reset when they're returned.
+    #[serde(default = "default_statement_cache_size")]
+    pub statement_cache_size: usize,
+    /// The maximum number of rows `psdb` `Execute` will return. Larger results must use `StreamExecute`.
+    #[serde(default = "default_max_result_rows")]
+    pub max_result_rows: usize,
+    /// The maximum size (in bytes) of the rows `psdb` `Execute` will return. Larger results must use `StreamExecute`.
+    #[serde(default = "default_max_result_bytes")]
+    pub max_result_bytes: usize,
+    /// The default maximum execution time (in seconds) of a query run through the `psdb` API. `0` disables the timeout.
+    /// A request can shorten it with the `Connect-Timeout-Ms` header. Queries run from the dashboard aren't limited.
+    #[serde(default = "default_query_timeout")]
+    pub query_timeout: u64,
+    /// The maximum number of username + database combos the `psdb` API keeps a connection pool for.
+    /// The least recently used is closed when a new one is needed.
+    #[serde(default = "default_credential_cache_size")]
+    pub credential_cache_size: usize,
+    /// How long (in seconds) the `psdb` API trusts credentials it has verified before checking them against MySQL again.
+    /// Changes made through Cityscale take effect immediately, this bounds how long changes made directly in MySQL take.
+    #[serde(default = "default_credential_cache_ttl")]
+    pub credential_cache_ttl: u64,
+    /// The maximum size (in bytes) of the `psdb` result cache. Results are only cached for users or queries with a TTL.
+    #[serde(default = "default_result_cache_size")]
+    pub result_cache_size: usize,
+    /// Settings for individual MySQL users.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub users: HashMap<String, UserConfig>,
+    /// Settings for individual databases.
+    #[serde(default, skip_serializing_if = "HashMap::is_empty")]
+    pub databases: HashMap<String, DatabaseConfig>,
+    /// Which browser origins may call the `psdb` API.
+    #[serde(default)]
+    pub cors: CorsConfig,
+    /// The number of admins, other than it's author, who must approve a deploy request before it can be deployed.
+    #[serde(default = "default_deploy_request_approvals")]
+    pub deploy_request_approvals: usize,
+    /// The number of rows an online schema change copies to the new table at a time.
+    #[serde(default = "default_migration_chunk_size")]
+    pub migration_chunk_size: usize,
+    /// How long (in milliseconds) an online schema change waits between copying chunks, to limit the load it puts on MySQL.
+    #[serde(default)]
+    pub migration_chunk_delay: u64,
+    /// Requests to apply the schema of a branch to it's parent, including the most recent closed and deployed ones.
+    #[serde(default, skip_serializing_if = "Vec::is_empty")]
+    pub deploy_requests: Vec<DeployRequest>,
+}
+
+impl Config {
+    /// The default query timeout for a MySQL user.
+    pub fn query_timeout(&self, username: &str) -> Option<Duration> {
+        let timeout = self
+            .users
+            .get(username)
+            .and_then(|user| user.query_timeout)
+            .unwrap_or(self.query_timeout);
+        (timeout != 0).then(|| Duration::from_secs(timeout))
+    }
+
+    /// The default TTL (in seconds) of cached `psdb` results for a MySQL user.
+    pub fn result_cache_ttl(&self, username: &str) -> Option<u64> {
+        self.users
+            .get(username)
+            .and_then(|user| user.result_cache_ttl)
+    }
+
+    /// The databases which direct schema changes are rejected for.
+    pub fn protected_databases(&self) -> impl Iterator<Item = &str> {
+        self.databases
+            .iter()
+            .filter(|(_, db)| db.protected)
+            .map(|(name, _)| name.as_str())
+    }
+
+    /// The maximum number of rows `psdb` `Execute` will return to a MySQL user.
+    pub fn max_result_rows(&self, username: &str) -> usize {
+        self.users
+            .get(username)
+            .and_then(|user| user.max_result_rows)
+            .map_or(self.max_result_rows, |max| max.min(self.max_result_rows))
+    }
+}
+
+#[derive(Debug, Clone, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct UserConfig {
+    /// Overrides [`Config::query_timeout`] for this user.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub query_timeout: Option<u64>,
+    /// The maximum number of requests per second this user can make to the `psdb` API.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_requests_per_second: Option<u32>,
+    /// The maximum number of `psdb` sessions this user can have open at once.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_sessions: Option<usize>,
+    /// Overrides [`Config::max_result_rows`] for this user. It can only lower the limit.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub max_result_rows: Option<usize>,
+    /// How long (in seconds) the results of this user's `SELECT` queries are cached for.
+    /// A query can override it with a `/* cityscale:cache_ttl=60 */` comment, where `0` disables caching.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub result_cache_ttl: Option<u64>,
+}
+
+#[derive(Debug, Clone, Default, Serialize, Deserialize)]
+pub struct DatabaseConfig {
+    /// Where the database was branched from, if it's a branch.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub branch: Option<Branch>,
+    /// Reject DDL sent directly to the database through the `psdb` API or the dashboard.
+    /// Schema changes must be made on a branch and deployed with a deploy request.
+    #[serde(default, skip_serializing_if = "std::ops::Not::not")]
+    pub protected: bool,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Branch {
+    /// The database this was branched from. It may have since been dropped.
+    pub parent: String,
+    /// Unix timestamp (in seconds) of when the branch was created.
+    pub created_at: u64,
+    /// The admin who created the branch.
+    pub created_by: String,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct DeployRequest {
+    pub id: u64,
+    /// The branch whose schema is being deployed.
+    pub branch: String,
+    /// The database the schema is deployed to, the branch's parent.
+    pub into: String,
+    /// The DDL which is run against `into`, computed when the request was opened.
+    pub statements: Vec<String>,
+    /// The admin who opened the request.
+    pub created_by: String,
+    /// Unix timestamp (in seconds) of when the request was opened.
+    pub created_at: u64,
+    pub approvals: Vec<Approval>,
+    pub state: DeployState,
+    /// Every state the request has been in, oldest first.
+    pub history: Vec<StateChange>,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub enum DeployState {
+    Open,
+    Approved,
+    Deploying,
+    Deployed,
+    Failed,
+    Closed,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct Approval {
+    pub by: String,
+    /// Unix timestamp (in seconds) of the approval.
+    pub at: u64,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct StateChange {
+    pub state: DeployState,
+    /// The admin who caused the change.
+    pub by: String,
+    /// Unix timestamp (in seconds) of the change.
+    pub at: u64,
+    /// Why a deploy failed.
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub message: Option<String>,
+}
+
+#[derive(Debug, Clone, Serialize, Deserialize)]
+pub struct CorsConfig {
+    /// Origins (Eg. `https://example.com`) allowed to call the `psdb` API from a browser, or `*` for any.
+    /// Only listed origins may send credentials, `*` allows other origins without them.
+    /// CORS is disabled when this is empty.
+    #[serde(default)]
+    pub allowed_origins: Vec<String>,
+    #[serde(default = "default_cors_allowed_methods")]
+    pub allowed_methods: Vec<String>,
+    #[serde(default = "default_cors_allowed_headers")]
+    pub allowed_headers: Vec<String>,
+}
+
+impl Default for CorsConfig {
+    fn default() -> Self {
+        Self {
+            allowed_origins: Vec::new(),
+            allowed_methods: default_cors_allowed_methods(),
+            allowed_headers: default_cors_allowed_headers(),
+        }
+    }
+}
+
+fn default_cors_allowed_methods() -> Vec<String> {
+    vec!["GET".into(), "POST".into()]
+}
+
+fn default_cors_allowed_headers() -> Vec<String> {
+    vec![
+        "Authorization".into(),
+        "Content-Type".into(),
+        "Connect-Timeout-Ms".into(),
+    ]
+}
+
+fn default_migration_chunk_size() -> usize {
+    1000
+}
+
+fn default_deploy_request_approvals() -> usize {
+    1
+}
+
+fn default_result_cache_size() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_credential_cache_size() -> usize {
+    250
+}
+
+fn default_credential_cache_ttl() -> u64 {
+    10 * 60
+}
+
+fn default_query_timeout() -> u64 {
+    30
+}
+
+fn default_max_result_rows() -> usize {
+    100_000
+}
+
+fn default_max_result_bytes() -> usize {
+    64 * 1024 * 1024
+}
+
+fn default_statement_cache_size() -> usize {
+    32
+}
+
+fn default_session_idle_timeout() -> u64 {
+    30
+}
+
+fn default_session_max_lifetime() -> u64 {
+    5 * 60
 }
 
 impl Default for Config {
@@ -33,6 +294,23 @@ impl Default for Config {
                 // "admin" argon2 hashed
                 "$argon2id$v=19$m=16,t=2,p=1$Y2l0eXNjYWxl$P3dUCcax9b1yc+LUlDLdWw".to_string(),
             )]),
+            session_idle_timeout: default_session_idle_timeout(),
+            session_max_lifetime: default_session_max_lifetime(),
+            preserve_fractional_seconds: false,
+            statement_cache_size: default_statement_cache_size(),
+            max_result_rows: default_max_result_rows(),
+            max_result_bytes: default_max_result_bytes(),
+            query_timeout: default_query_timeout(),
+            credential_cache_size: default_credential_cache_size(),
+            credential_cache_ttl: default_credential_cache_ttl(),
+            result_cache_size: default_result_cache_size(),
+            users: HashMap::new(),
+            databases: HashMap::new(),
+            cors: CorsConfig::default(),
+            deploy_request_approvals: default_deploy_request_approvals(),
+            migration_chunk_size: default_migration_chunk_size(),
+            migration_chunk_delay: 0,
+            deploy_requests: Vec::new(),
         }
     }
 }
@@ -79,7 +357,7 @@ impl ConfigManager {
         Ok(Self(Arc::new((path, RwLock::new(config)))))
     }
 
-    pub fn get(&self) -> RwLockReadGuard<Config> {
+    pub fn get(&self) -> RwLockReadGuard<'_, Config> {
         self.0 .1.read().unwrap_or_else(PoisonError::into_inner)
     }
 
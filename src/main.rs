@@ -16,6 +16,10 @@ use crate::api::AppState;
 
 mod api;
 mod config;
+mod proxy;
+
+/// The port the MySQL server we manage listens on. It's only bound to localhost as clients connect through our proxy.
+const MYSQL_INTERNAL_PORT: u16 = 3307;
 
 #[tokio::main]
 async fn main() {
@@ -48,12 +52,22 @@ async fn main() {
         process::exit(1);
     };
 
+    let Ok(mysql_port) = env::var("MYSQL_PORT")
+        .map(|v| v.parse::<u16>().map(Some))
+        .unwrap_or(Ok(None))
+        .map_err(|err| error!("Failed to parse 'MYSQL_PORT' environment variable: {err}"))
+    else {
+        process::exit(1);
+    };
+
     let Ok(config) = config::ConfigManager::new(data_dir.join("config.json"))
         .map_err(|err| error!("Error loading configuration: {err}"))
     else {
         process::exit(1);
     };
+    api::fail_interrupted_deploys(&config);
 
+    let external_server = env::var("MYSQL_SERVER").is_ok();
     let db_opts = if let Ok(db_url) = env::var("MYSQL_SERVER") {
         warn!("User provided 'MYSQL_SERVER' environment variable, skipping MySQL server...");
 
@@ -96,6 +110,10 @@ async fn main() {
                 // These args are forwarded to `mysqld``
                 .arg("--datadir")
                 .arg(mysql_dir)
+                // Native clients must go through our proxy so MySQL is only reachable locally
+                .arg("--bind-address=127.0.0.1")
+                .arg(format!("--port={MYSQL_INTERNAL_PORT}"))
+                .arg("--mysqlx-bind-address=127.0.0.1")
                 // Configure the root password in the Docker entrypoints setup script
                 .env(
                     "MYSQL_ROOT_PASSWORD",
@@ -121,7 +139,7 @@ async fn main() {
 
         OptsBuilder::default()
             .ip_or_hostname("127.0.0.1")
-            .tcp_port(3306)
+            .tcp_port(MYSQL_INTERNAL_PORT)
             .user(Some("root"))
             .pass(Some(config.get().mysql_root_password.clone()))
             .into()
@@ -132,8 +150,45 @@ async fn main() {
         db_opts,
         data_dir,
         config,
+        connections: Default::default(),
+        migrations: Default::default(),
     });
 
+    // We take over the public MySQL port from the server we manage, but proxying an external server is opt-in.
+    if let Some(port) = mysql_port.or((!external_server).then_some(3306)) {
+        let proxy_addr = SocketAddr::new(listen_addr.ip(), port);
+        let Ok(listener) = tokio::net::TcpListener::bind(proxy_addr)
+            .await
+            .map_err(|err| error!("Failed to bind to {proxy_addr}: {err}"))
+        else {
+            process::exit(1);
+        };
+
+        // Clients get TLS with the provided certificate, or the one the MySQL server we manage generates
+        let certificate = match (env::var("MYSQL_TLS_CERT"), env::var("MYSQL_TLS_KEY")) {
+            (Ok(cert), Ok(key)) => {
+                let Ok(certificate) = proxy::Certificate::load(cert.into(), key.into())
+                    .map_err(|err| error!("Failed to load the MySQL TLS certificate: {err}"))
+                else {
+                    process::exit(1);
+                };
+                Some(certificate)
+            }
+            _ if !external_server => Some(proxy::Certificate::lazy(
+                state.data_dir.join("mysql").join("server-cert.pem"),
+                state.data_dir.join("mysql").join("server-key.pem"),
+            )),
+            _ => None,
+        };
+
+        info!("MySQL proxy listening on {proxy_addr}");
+        let backend = (
+            state.db_opts.ip_or_hostname().to_string(),
+            state.db_opts.tcp_port(),
+        );
+        tokio::spawn(proxy::serve(listener, backend, certificate, state.clone()));
+    }
+
     let app = api::mount(state);
     let Ok(listener) = tokio::net::TcpListener::bind(listen_addr)
         .await
@@ -0,0 +1,848 @@
+use std::{
+    fs::File,
+    io::{self, BufReader},
+    net::SocketAddr,
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex, PoisonError},
+    time::Duration,
+};
+
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    net::{TcpListener, TcpStream},
+};
+use tokio_rustls::{
+    rustls::{
+        client::danger::{HandshakeSignatureValid, ServerCertVerified, ServerCertVerifier},
+        crypto::{self, ring, WebPkiSupportedAlgorithms},
+        pki_types::{CertificateDer, ServerName, UnixTime},
+        ClientConfig, DigitallySignedStruct, ServerConfig, SignatureScheme,
+    },
+    TlsAcceptor, TlsConnector,
+};
+use tracing::{debug, info, warn};
+
+use crate::api::{AppState, NativeConnection};
+
+/// How long a client has to complete the handshake, like MySQL's `connect_timeout`.
+const HANDSHAKE_TIMEOUT: Duration = Duration::from_secs(10);
+/// The largest packet read during the handshake, so a client which isn't authenticated can't make us allocate much.
+/// MySQL's own packets (Eg. it's RSA public key) are well under this.
+const MAX_HANDSHAKE_PACKET: usize = 16 * 1024;
+
+// Capability flags we need to understand the handshake.
+// Ref: https://dev.mysql.com/doc/dev/mysql-server/latest/group__group__cs__capabilities__flags.html
+const CLIENT_CONNECT_WITH_DB: u32 = 0x0000_0008;
+const CLIENT_COMPRESS: u32 = 0x0000_0020;
+const CLIENT_PROTOCOL_41: u32 = 0x0000_0200;
+const CLIENT_SSL: u32 = 0x0000_0800;
+const CLIENT_SECURE_CONNECTION: u32 = 0x0000_8000;
+const CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA: u32 = 0x0020_0000;
+
+/// `ER_HANDSHAKE_ERROR`
+const ERROR_BAD_HANDSHAKE: (u16, &str) = (1043, "08S01");
+/// `ER_USER_LIMIT_REACHED`
+const ERROR_USER_LIMIT: (u16, &str) = (1226, "42000");
+/// `ER_NOT_SUPPORTED_YET`
+const ERROR_NOT_SUPPORTED: (u16, &str) = (1235, "42000");
+
+/// The command which re-authenticates a connection as another user.
+const COM_CHANGE_USER: u8 = 0x11;
+
+/// A MySQL wire-protocol proxy in front of the MySQL server, so Cityscale sees every native client connection.
+///
+/// The handshake is relayed instead of being terminated so the client's password is verified by MySQL itself,
+/// which is how the HTTP API authenticates users too. Once MySQL accepts the client the user's rate and session
+/// limits are enforced, then the client's commands are relayed as they are, except `COM_CHANGE_USER` which would
+/// switch user without those limits. The compressed protocol isn't supported as the commands couldn't be read.
+///
+/// If a client asks for TLS it's terminated by the proxy with `certificate` and the connection to MySQL is upgraded too,
+/// so the handshake can still be read and MySQL treats the connection as secure (Eg. for `caching_sha2_password`).
+pub async fn serve(
+    listener: TcpListener,
+    backend: (String, u16),
+    certificate: Option<Certificate>,
+    state: Arc<AppState>,
+) {
+    let tls = certificate.and_then(|certificate| {
+        let server_name = ServerName::try_from(backend.0.as_str())
+            .map(|name| name.to_owned())
+            .map_err(|err| warn!("MySQL proxy can't offer TLS for {:?}: {err}", backend.0))
+            .ok()?;
+        let connector = ClientConfig::builder()
+            .dangerous()
+            .with_custom_certificate_verifier(Arc::new(AnyCertificate(
+                ring::default_provider().signature_verification_algorithms,
+            )))
+            .with_no_client_auth();
+
+        Some(Tls {
+            certificate,
+            connector: TlsConnector::from(Arc::new(connector)),
+            server_name,
+        })
+    });
+    let proxy = Arc::new(Proxy {
+        backend,
+        tls,
+        state,
+    });
+
+    loop {
+        let (client, peer) = match listener.accept().await {
+            Ok(conn) => conn,
+            Err(err) => {
+                warn!("Error accepting MySQL connection: {err}");
+                continue;
+            }
+        };
+
+        let proxy = proxy.clone();
+        tokio::spawn(async move {
+            if let Err(err) = proxy.relay(client, peer).await {
+                debug!("MySQL connection from {peer} closed with error: {err}");
+            }
+        });
+    }
+}
+
+/// The certificate the proxy terminates client TLS with.
+pub struct Certificate {
+    cert: PathBuf,
+    key: PathBuf,
+    acceptor: Mutex<Option<TlsAcceptor>>,
+}
+
+impl Certificate {
+    /// Load a certificate up front, so a misconfiguration is caught on startup.
+    pub fn load(cert: PathBuf, key: PathBuf) -> io::Result<Self> {
+        let acceptor = load_acceptor(&cert, &key)?;
+        Ok(Self {
+            cert,
+            key,
+            acceptor: Mutex::new(Some(acceptor)),
+        })
+    }
+
+    /// A certificate which is loaded on first use, as the one MySQL generates doesn't exist until it has started.
+    pub fn lazy(cert: PathBuf, key: PathBuf) -> Self {
+        Self {
+            cert,
+            key,
+            acceptor: Mutex::new(None),
+        }
+    }
+
+    fn acceptor(&self) -> Option<TlsAcceptor> {
+        let mut acceptor = self.acceptor.lock().unwrap_or_else(PoisonError::into_inner);
+        if acceptor.is_none() {
+            *acceptor = load_acceptor(&self.cert, &self.key)
+                .map_err(|err| debug!("MySQL proxy can't offer TLS yet: {err}"))
+                .ok();
+        }
+        acceptor.clone()
+    }
+}
+
+fn load_acceptor(cert: &Path, key: &Path) -> io::Result<TlsAcceptor> {
+    let certs = rustls_pemfile::certs(&mut BufReader::new(File::open(cert)?))
+        .collect::<Result<Vec<_>, _>>()?;
+    let key = rustls_pemfile::private_key(&mut BufReader::new(File::open(key)?))?
+        .ok_or_else(|| invalid("no private key found"))?;
+
+    let config = ServerConfig::builder()
+        .with_no_client_auth()
+        .with_single_cert(certs, key)
+        .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))?;
+    Ok(TlsAcceptor::from(Arc::new(config)))
+}
+
+/// Accepts any certificate from MySQL, like a client with `--ssl-mode=REQUIRED`.
+///
+/// MySQL is either running locally or is an external server the operator chose, and the certificate it generates
+/// itself couldn't be verified anyway. It's only encrypted so MySQL knows the password is safe to send in clear text.
+#[derive(Debug)]
+struct AnyCertificate(WebPkiSupportedAlgorithms);
+
+impl ServerCertVerifier for AnyCertificate {
+    fn verify_server_cert(
+        &self,
+        _end_entity: &CertificateDer<'_>,
+        _intermediates: &[CertificateDer<'_>],
+        _server_name: &ServerName<'_>,
+        _ocsp_response: &[u8],
+        _now: UnixTime,
+    ) -> Result<ServerCertVerified, tokio_rustls::rustls::Error> {
+        Ok(ServerCertVerified::assertion())
+    }
+
+    fn verify_tls12_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls12_signature(message, cert, dss, &self.0)
+    }
+
+    fn verify_tls13_signature(
+        &self,
+        message: &[u8],
+        cert: &CertificateDer<'_>,
+        dss: &DigitallySignedStruct,
+    ) -> Result<HandshakeSignatureValid, tokio_rustls::rustls::Error> {
+        crypto::verify_tls13_signature(message, cert, dss, &self.0)
+    }
+
+    fn supported_verify_schemes(&self) -> Vec<SignatureScheme> {
+        self.0.supported_schemes()
+    }
+}
+
+struct Proxy {
+    backend: (String, u16),
+    tls: Option<Tls>,
+    state: Arc<AppState>,
+}
+
+struct Tls {
+    certificate: Certificate,
+    connector: TlsConnector,
+    server_name: ServerName<'static>,
+}
+
+/// Either side of a connection, which may have been upgraded to TLS during the handshake.
+trait Stream: AsyncRead + AsyncWrite + Unpin + Send {}
+
+impl<T: AsyncRead + AsyncWrite + Unpin + Send> Stream for T {}
+
+/// A connection which MySQL has accepted.
+struct Relay {
+    client: Box<dyn Stream>,
+    server: Box<dyn Stream>,
+    handshake: Handshake,
+    _connection: NativeConnection,
+}
+
+impl Proxy {
+    async fn relay(&self, client: TcpStream, peer: SocketAddr) -> io::Result<()> {
+        client.set_nodelay(true)?;
+        let server = TcpStream::connect((self.backend.0.as_str(), self.backend.1)).await?;
+        server.set_nodelay(true)?;
+
+        let relay = tokio::time::timeout(
+            HANDSHAKE_TIMEOUT,
+            self.handshake(Box::new(client), Box::new(server)),
+        )
+        .await
+        .map_err(|_| io::Error::new(io::ErrorKind::TimedOut, "handshake timed out"))??;
+        let Some(Relay {
+            client,
+            server,
+            handshake: Handshake { username, database },
+            _connection,
+        }) = relay
+        else {
+            return Ok(());
+        };
+
+        info!("MySQL connection from {peer} authenticated as {username:?} on {database:?}");
+        let (client_reader, client_writer) = tokio::io::split(client);
+        let (mut server_reader, mut server_writer) = tokio::io::split(server);
+        let client_writer = tokio::sync::Mutex::new(client_writer);
+        let result = tokio::try_join!(
+            relay_commands(client_reader, &mut server_writer, &client_writer),
+            async {
+                let mut buf = vec![0; 16 * 1024];
+                loop {
+                    let len = server_reader.read(&mut buf).await?;
+                    let mut client = client_writer.lock().await;
+                    if len == 0 {
+                        break client.shutdown().await;
+                    }
+                    client.write_all(&buf[..len]).await?;
+                }
+            },
+        );
+        info!("MySQL connection from {peer} as {username:?} closed");
+        result.map(|_| ())
+    }
+
+    /// Relay the connection phase between the client and MySQL, returning the connection if it was authenticated.
+    ///
+    /// Ref: https://dev.mysql.com/doc/dev/mysql-server/latest/page_protocol_connection_phase.html
+    async fn handshake(
+        &self,
+        mut client: Box<dyn Stream>,
+        mut server: Box<dyn Stream>,
+    ) -> io::Result<Option<Relay>> {
+        let mut greeting = Packet::read(&mut server).await?;
+        if greeting.payload.first() != Some(&10) {
+            // Either an error (Eg. too many connections) or a protocol we don't understand so we let the client handle it
+            greeting.write(&mut client).await?;
+            return Ok(None);
+        }
+
+        // TLS is only offered if we can terminate it and MySQL can encrypt it's side of the connection
+        let tls = match &self.tls {
+            Some(tls) if offers_ssl(&mut greeting.payload)? => {
+                tls.certificate.acceptor().map(|acceptor| (tls, acceptor))
+            }
+            _ => None,
+        };
+        if tls.is_none() {
+            strip_capability(&mut greeting.payload, CLIENT_SSL)?;
+        }
+        strip_capability(&mut greeting.payload, CLIENT_COMPRESS)?;
+        greeting.write(&mut client).await?;
+
+        let mut response = read_handshake(&mut client).await?;
+        if let Some((tls, acceptor)) = tls.filter(|_| is_ssl_request(&response.payload)) {
+            response.write(&mut server).await?;
+            server = Box::new(
+                tls.connector
+                    .connect(tls.server_name.clone(), server)
+                    .await?,
+            );
+            client = Box::new(acceptor.accept(client).await?);
+            response = read_handshake(&mut client).await?;
+        }
+
+        let Some(handshake) = parse_response(&response.payload) else {
+            Packet::error(
+                response.seq.wrapping_add(1),
+                ERROR_BAD_HANDSHAKE,
+                "Bad handshake, pre-4.1 clients are not supported",
+            )
+            .write(&mut client)
+            .await?;
+            return Ok(None);
+        };
+        response.write(&mut server).await?;
+
+        // Relay the authentication exchange until MySQL accepts or rejects the client
+        loop {
+            let packet = Packet::read(&mut server).await?;
+
+            match packet.payload.first() {
+                Some(0x00) => {
+                    let connection = self
+                        .state
+                        .connections
+                        .open_native_connection(&self.state.config.get(), &handshake.username);
+                    let connection = match connection {
+                        Ok(connection) => connection,
+                        Err(message) => {
+                            debug!(
+                                "MySQL connection as {:?} refused: {message}",
+                                handshake.username
+                            );
+                            Packet::error(packet.seq, ERROR_USER_LIMIT, &message)
+                                .write(&mut client)
+                                .await?;
+                            return Ok(None);
+                        }
+                    };
+
+                    packet.write(&mut client).await?;
+                    return Ok(Some(Relay {
+                        client,
+                        server,
+                        handshake,
+                        _connection: connection,
+                    }));
+                }
+                Some(0xFF) => {
+                    packet.write(&mut client).await?;
+                    debug!(
+                        "MySQL rejected the credentials for {:?}",
+                        handshake.username
+                    );
+                    return Ok(None);
+                }
+                // `caching_sha2_password` fast authentication succeeded so MySQL will send the OK next
+                Some(0x01) if packet.payload.get(1) == Some(&0x03) => {
+                    packet.write(&mut client).await?
+                }
+                // An auth switch request or more auth data, which the client responds to
+                _ => {
+                    packet.write(&mut client).await?;
+                    read_handshake(&mut client)
+                        .await?
+                        .write(&mut server)
+                        .await?
+                }
+            }
+        }
+    }
+}
+
+/// The client of a connection which MySQL has accepted.
+#[derive(Debug, PartialEq)]
+struct Handshake {
+    username: String,
+    database: Option<String>,
+}
+
+/// The lower capability flags in MySQL's greeting.
+fn greeting_flags(greeting: &mut [u8]) -> io::Result<&mut [u8]> {
+    // They follow the protocol version, server version, connection ID, auth data and a filler
+    let version_end = greeting
+        .get(1..)
+        .and_then(|version| version.iter().position(|b| *b == 0))
+        .ok_or_else(|| invalid("greeting is missing the server version"))?;
+    let offset = 1 + version_end + 1 + 4 + 8 + 1;
+    greeting
+        .get_mut(offset..offset + 2)
+        .ok_or_else(|| invalid("greeting is missing the capability flags"))
+}
+
+/// Whether MySQL offered TLS in it's greeting.
+fn offers_ssl(greeting: &mut [u8]) -> io::Result<bool> {
+    let flags = greeting_flags(greeting)?;
+    Ok(u32::from(u16::from_le_bytes([flags[0], flags[1]])) & CLIENT_SSL != 0)
+}
+
+/// Stop MySQL offering a capability in it's greeting (Eg. TLS, so the client doesn't encrypt the handshake).
+fn strip_capability(greeting: &mut [u8], capability: u32) -> io::Result<()> {
+    let flags = greeting_flags(greeting)?;
+    let lower = u16::from_le_bytes([flags[0], flags[1]]) & !(capability as u16);
+    flags.copy_from_slice(&lower.to_le_bytes());
+    Ok(())
+}
+
+/// Whether the client's response is an `SSLRequest`, which is a truncated `HandshakeResponse41`.
+fn is_ssl_request(payload: &[u8]) -> bool {
+    payload.len() == 32
+        && payload.get(..4).is_some_and(|flags| {
+            u32::from_le_bytes([flags[0], flags[1], flags[2], flags[3]]) & CLIENT_SSL != 0
+        })
+}
+
+/// Parse a `HandshakeResponse41`, which is `None` if it's not one (Eg. a TLS request we didn't offer) or it asks for compression.
+fn parse_response(payload: &[u8]) -> Option<Handshake> {
+    let flags = u32::from_le_bytes(payload.get(..4)?.try_into().ok()?);
+    if flags & CLIENT_PROTOCOL_41 == 0 || flags & CLIENT_COMPRESS != 0 {
+        return None;
+    }
+
+    // Capability flags, max packet size, character set and filler
+    let mut rest = payload.get(32..)?;
+    let username = take_null_terminated(&mut rest)?;
+
+    let auth_len = if flags & CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA != 0 {
+        take_lenenc_int(&mut rest)?
+    } else if flags & CLIENT_SECURE_CONNECTION != 0 {
+        u64::from(*rest.first()?) + 1
+    } else {
+        take_null_terminated(&mut rest)?;
+        0
+    };
+    rest = rest.get(usize::try_from(auth_len).ok()?..)?;
+
+    let database = if flags & CLIENT_CONNECT_WITH_DB != 0 {
+        Some(take_null_terminated(&mut rest)?).filter(|db| !db.is_empty())
+    } else {
+        None
+    };
+
+    Some(Handshake { username, database })
+}
+
+fn take_null_terminated(buf: &mut &[u8]) -> Option<String> {
+    let end = buf.iter().position(|b| *b == 0)?;
+    let value = String::from_utf8_lossy(&buf[..end]).into_owned();
+    *buf = &buf[end + 1..];
+    Some(value)
+}
+
+fn take_lenenc_int(buf: &mut &[u8]) -> Option<u64> {
+    let (len, value) = match *buf.first()? {
+        0xFC => (
+            3,
+            u64::from(u16::from_le_bytes(buf.get(1..3)?.try_into().ok()?)),
+        ),
+        0xFD => {
+            let bytes = buf.get(1..4)?;
+            (
+                4,
+                u64::from_le_bytes([bytes[0], bytes[1], bytes[2], 0, 0, 0, 0, 0]),
+            )
+        }
+        0xFE => (9, u64::from_le_bytes(buf.get(1..9)?.try_into().ok()?)),
+        value => (1, u64::from(value)),
+    };
+    *buf = &buf[len..];
+    Some(value)
+}
+
+fn invalid(message: &str) -> io::Error {
+    io::Error::new(io::ErrorKind::InvalidData, message.to_string())
+}
+
+/// Read a packet of the handshake from the client, refusing it if it's larger than the handshake needs.
+async fn read_handshake(
+    client: &mut (impl AsyncRead + AsyncWrite + Unpin + ?Sized),
+) -> io::Result<Packet> {
+    let (seq, len) = Packet::read_header(client).await?;
+    if len > MAX_HANDSHAKE_PACKET {
+        Packet::error(
+            seq.wrapping_add(1),
+            ERROR_BAD_HANDSHAKE,
+            "Bad handshake, the packet is too large",
+        )
+        .write(client)
+        .await?;
+        return Err(invalid("handshake packet is too large"));
+    }
+    Packet::read_payload(client, seq, len).await
+}
+
+/// Relay the client's commands to MySQL, refusing `COM_CHANGE_USER`.
+///
+/// Packets are streamed as they can be up to 16MiB, and only the first byte of a command is read.
+async fn relay_commands(
+    mut client: impl AsyncRead + Unpin,
+    server: &mut (impl AsyncWrite + Unpin),
+    client_writer: &tokio::sync::Mutex<impl AsyncWrite + Unpin>,
+) -> io::Result<()> {
+    let mut server = tokio::io::BufWriter::new(server);
+    loop {
+        let (seq, len) = match Packet::read_header(&mut client).await {
+            Ok(header) => header,
+            Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => break,
+            Err(err) => return Err(err),
+        };
+
+        // A command starts a new sequence, the rest of a large command or a `LOAD DATA LOCAL` file follow it
+        let mut command = Vec::new();
+        if seq == 0 && len > 0 {
+            command.push(client.read_u8().await?);
+            if command[0] == COM_CHANGE_USER {
+                tokio::io::copy(
+                    &mut (&mut client).take(len as u64 - 1),
+                    &mut tokio::io::sink(),
+                )
+                .await?;
+                Packet::error(
+                    seq.wrapping_add(1),
+                    ERROR_NOT_SUPPORTED,
+                    "Changing user isn't supported through Cityscale, open a new connection instead",
+                )
+                .write(&mut *client_writer.lock().await)
+                .await?;
+                continue;
+            }
+        }
+
+        server.write_all(&(len as u32).to_le_bytes()[..3]).await?;
+        server.write_u8(seq).await?;
+        server.write_all(&command).await?;
+        let remaining = (len - command.len()) as u64;
+        if tokio::io::copy(&mut (&mut client).take(remaining), &mut server).await? < remaining {
+            break;
+        }
+        server.flush().await?;
+    }
+    server.shutdown().await
+}
+
+/// A MySQL protocol packet. The handshake is always small enough to fit in one.
+struct Packet {
+    seq: u8,
+    payload: Vec<u8>,
+}
+
+impl Packet {
+    /// Read a packet of the handshake from MySQL.
+    async fn read(stream: &mut (impl AsyncRead + Unpin + ?Sized)) -> io::Result<Self> {
+        let (seq, len) = Self::read_header(stream).await?;
+        if len > MAX_HANDSHAKE_PACKET {
+            return Err(invalid("handshake packet is too large"));
+        }
+        Self::read_payload(stream, seq, len).await
+    }
+
+    /// Read the sequence number and payload length of a packet.
+    async fn read_header(
+        stream: &mut (impl AsyncRead + Unpin + ?Sized),
+    ) -> io::Result<(u8, usize)> {
+        let mut header = [0; 4];
+        stream.read_exact(&mut header).await?;
+        let len = u32::from_le_bytes([header[0], header[1], header[2], 0]) as usize;
+        Ok((header[3], len))
+    }
+
+    async fn read_payload(
+        stream: &mut (impl AsyncRead + Unpin + ?Sized),
+        seq: u8,
+        len: usize,
+    ) -> io::Result<Self> {
+        let mut payload = vec![0; len];
+        stream.read_exact(&mut payload).await?;
+        Ok(Self { seq, payload })
+    }
+
+    async fn write(&self, stream: &mut (impl AsyncWrite + Unpin + ?Sized)) -> io::Result<()> {
+        let len = (self.payload.len() as u32).to_le_bytes();
+        let mut buf = Vec::with_capacity(4 + self.payload.len());
+        buf.extend_from_slice(&len[..3]);
+        buf.push(self.seq);
+        buf.extend_from_slice(&self.payload);
+        stream.write_all(&buf).await?;
+        stream.flush().await
+    }
+
+    /// An `ERR_Packet` for a client which we refused during the handshake.
+    fn error(seq: u8, (code, state): (u16, &str), message: &str) -> Self {
+        let mut payload = vec![0xFF];
+        payload.extend_from_slice(&code.to_le_bytes());
+        payload.push(b'#');
+        payload.extend_from_slice(state.as_bytes());
+        payload.extend_from_slice(message.as_bytes());
+        Self { seq, payload }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn greeting(flags: u16) -> Vec<u8> {
+        let mut greeting = vec![10];
+        greeting.extend_from_slice(b"8.0.36\0");
+        greeting.extend_from_slice(&7u32.to_le_bytes());
+        greeting.extend_from_slice(b"12345678");
+        greeting.push(0);
+        greeting.extend_from_slice(&flags.to_le_bytes());
+        // Character set, status flags and the upper capability flags
+        greeting.extend_from_slice(&[0xFF, 2, 0, 0xFF, 0xDF]);
+        greeting
+    }
+
+    fn response(flags: u32, username: &str, auth: &[u8], database: Option<&str>) -> Vec<u8> {
+        let mut response = flags.to_le_bytes().to_vec();
+        response.extend_from_slice(&16_777_216u32.to_le_bytes());
+        response.push(45);
+        response.extend_from_slice(&[0; 23]);
+        response.extend_from_slice(username.as_bytes());
+        response.push(0);
+        if flags & (CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA | CLIENT_SECURE_CONNECTION) != 0 {
+            response.push(auth.len() as u8);
+            response.extend_from_slice(auth);
+        } else {
+            response.extend_from_slice(auth);
+            response.push(0);
+        }
+        if let Some(database) = database {
+            response.extend_from_slice(database.as_bytes());
+            response.push(0);
+        }
+        response.extend_from_slice(b"caching_sha2_password\0");
+        response
+    }
+
+    #[test]
+    fn strips_ssl() {
+        let flags = 0xFFFF;
+        let mut payload = greeting(flags);
+        assert!(offers_ssl(&mut payload).unwrap());
+
+        strip_capability(&mut payload, CLIENT_SSL).unwrap();
+        assert!(!offers_ssl(&mut payload).unwrap());
+        assert_eq!(payload, greeting(flags & !(CLIENT_SSL as u16)));
+
+        // It's a no-op if MySQL didn't offer TLS
+        let mut stripped = payload.clone();
+        strip_capability(&mut stripped, CLIENT_SSL).unwrap();
+        assert_eq!(stripped, payload);
+
+        strip_capability(&mut payload, CLIENT_COMPRESS).unwrap();
+        assert_eq!(
+            payload,
+            greeting(flags & !(CLIENT_SSL as u16) & !(CLIENT_COMPRESS as u16))
+        );
+    }
+
+    #[test]
+    fn strip_capability_rejects_truncated_greetings() {
+        assert!(strip_capability(&mut [10], CLIENT_SSL).is_err());
+        assert!(strip_capability(&mut b"\x0a8.0.36".to_vec(), CLIENT_SSL).is_err());
+        assert!(strip_capability(&mut greeting(0xFFFF)[..20], CLIENT_SSL).is_err());
+    }
+
+    #[test]
+    fn detects_ssl_requests() {
+        let flags = CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION;
+        assert!(is_ssl_request(
+            &response(flags | CLIENT_SSL, "root", b"", None)[..32]
+        ));
+        assert!(!is_ssl_request(&response(flags, "root", b"", None)[..32]));
+        assert!(!is_ssl_request(&response(
+            flags | CLIENT_SSL,
+            "root",
+            b"",
+            None
+        )));
+    }
+
+    #[test]
+    fn parses_responses() {
+        let auth = [0xAB; 20];
+        for flags in [
+            CLIENT_PROTOCOL_41 | CLIENT_PLUGIN_AUTH_LENENC_CLIENT_DATA,
+            CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION,
+            CLIENT_PROTOCOL_41 | CLIENT_SSL | CLIENT_SECURE_CONNECTION,
+        ] {
+            assert_eq!(
+                parse_response(&response(flags, "root", &auth, None)),
+                Some(Handshake {
+                    username: "root".into(),
+                    database: None,
+                }),
+                "flags {flags:#x}",
+            );
+            assert_eq!(
+                parse_response(&response(
+                    flags | CLIENT_CONNECT_WITH_DB,
+                    "app",
+                    &auth,
+                    Some("testing")
+                )),
+                Some(Handshake {
+                    username: "app".into(),
+                    database: Some("testing".into()),
+                }),
+                "flags {flags:#x}",
+            );
+        }
+
+        // Old style null terminated auth data and an empty database
+        let flags = CLIENT_PROTOCOL_41 | CLIENT_CONNECT_WITH_DB;
+        assert_eq!(
+            parse_response(&response(flags, "root", b"secret", Some(""))),
+            Some(Handshake {
+                username: "root".into(),
+                database: None,
+            }),
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_responses() {
+        let flags = CLIENT_PROTOCOL_41 | CLIENT_SECURE_CONNECTION;
+        // Pre-4.1 clients
+        assert_eq!(
+            parse_response(&response(CLIENT_SECURE_CONNECTION, "root", b"", None)),
+            None
+        );
+        // Compression, which we don't offer
+        assert_eq!(
+            parse_response(&response(flags | CLIENT_COMPRESS, "root", b"", None)),
+            None
+        );
+        // An `SSLRequest` when we didn't offer TLS
+        assert_eq!(
+            parse_response(&response(flags | CLIENT_SSL, "root", b"", None)[..32]),
+            None
+        );
+        // Truncated packets
+        assert_eq!(parse_response(&[]), None);
+        let full = response(flags | CLIENT_CONNECT_WITH_DB, "root", &[1; 20], Some("db"));
+        for len in [3, 31, 36, 40, 57] {
+            assert_eq!(parse_response(&full[..len]), None, "length {len}");
+        }
+    }
+
+    #[test]
+    fn takes_lenenc_ints() {
+        let cases: [(&[u8], u64, usize); 5] = [
+            (&[0x00], 0, 1),
+            (&[0xFB, 1], 0xFB, 1),
+            (&[0xFC, 0x34, 0x12, 1], 0x1234, 3),
+            (&[0xFD, 0x56, 0x34, 0x12, 1], 0x12_3456, 4),
+            (&[0xFE, 8, 7, 6, 5, 4, 3, 2, 1, 1], 0x0102_0304_0506_0708, 9),
+        ];
+        for (input, value, len) in cases {
+            let mut buf = input;
+            assert_eq!(take_lenenc_int(&mut buf), Some(value), "{input:x?}");
+            assert_eq!(buf, &input[len..], "{input:x?}");
+        }
+
+        for input in [
+            &[][..],
+            &[0xFC, 1],
+            &[0xFD, 1, 2],
+            &[0xFE, 1, 2, 3, 4, 5, 6, 7],
+        ] {
+            let mut buf = input;
+            assert_eq!(take_lenenc_int(&mut buf), None, "{input:x?}");
+            assert_eq!(buf, input);
+        }
+    }
+
+    #[test]
+    fn encodes_errors() {
+        let packet = Packet::error(2, ERROR_USER_LIMIT, "slow down");
+        assert_eq!(packet.seq, 2);
+        assert_eq!(packet.payload, b"\xff\xca\x04#42000slow down");
+    }
+
+    #[tokio::test]
+    async fn refuses_large_handshake_packets() {
+        let (mut client, mut proxy) = tokio::io::duplex(1024);
+        client.write_all(&[0xFF, 0xFF, 0xFF, 1]).await.unwrap();
+        assert!(read_handshake(&mut proxy).await.is_err());
+
+        let error = Packet::read(&mut client).await.unwrap();
+        assert_eq!(error.seq, 2);
+        assert_eq!(&error.payload[..3], b"\xff\x13\x04");
+
+        // A packet which fits is read
+        Packet::error(0, ERROR_USER_LIMIT, "ok")
+            .write(&mut client)
+            .await
+            .unwrap();
+        assert_eq!(read_handshake(&mut proxy).await.unwrap().seq, 0);
+    }
+
+    #[tokio::test]
+    async fn refuses_changing_user() {
+        let (mut client, proxy) = tokio::io::duplex(1024);
+        let (mut mysql, mut server) = tokio::io::duplex(1024);
+        let (responses, mut refused) = tokio::io::duplex(1024);
+        let responses = tokio::sync::Mutex::new(responses);
+        let relay =
+            tokio::spawn(async move { relay_commands(proxy, &mut server, &responses).await });
+
+        let query = Packet {
+            seq: 0,
+            payload: b"\x03SELECT 1".to_vec(),
+        };
+        let change_user = Packet {
+            seq: 0,
+            payload: b"\x11other\0".to_vec(),
+        };
+        // A `LOAD DATA LOCAL` file can start with any byte
+        let file = Packet {
+            seq: 2,
+            payload: b"\x11data".to_vec(),
+        };
+        for packet in [&query, &change_user, &file] {
+            packet.write(&mut client).await.unwrap();
+        }
+        drop(client);
+        relay.await.unwrap().unwrap();
+
+        let mut relayed = Vec::new();
+        mysql.read_to_end(&mut relayed).await.unwrap();
+        let mut expected = Vec::new();
+        query.write(&mut expected).await.unwrap();
+        file.write(&mut expected).await.unwrap();
+        assert_eq!(relayed, expected);
+
+        let error = Packet::read(&mut refused).await.unwrap();
+        assert_eq!(error.seq, 1);
+        assert_eq!(&error.payload[..3], b"\xff\xd3\x04");
+    }
+}
@@ -0,0 +1,56 @@
+// Test for creating database branches through the dashboard API.
+//
+// Usage: `CITYSCALE_URL="http://localhost:2489" CITYSCALE_ADMIN="admin:admin" npx tsx branch.ts`
+import { check, finish, login } from "./helpers";
+
+const url = new URL(process.env.CITYSCALE_URL ?? "http://localhost:2489");
+const [adminUsername] = (process.env.CITYSCALE_ADMIN ?? "admin:admin").split(":");
+const base = `${url.protocol}//${url.host}`;
+const PARENT = "cityscalebranchtest";
+const SCHEMA_ONLY = "cityscalebranchschema";
+const WITH_DATA = "cityscalebranchdata";
+
+const api = await login(base);
+const execute = async (db: string, stmt: string) => (await api("POST", `/database/${db}/execute`, stmt)).json();
+
+await api("POST", "/database", { name: PARENT });
+await execute(PARENT, "CREATE TABLE users (id INT AUTO_INCREMENT PRIMARY KEY, name VARCHAR(255), upper_name VARCHAR(255) AS (UPPER(name)))");
+await execute(PARENT, "CREATE TABLE posts (id INT PRIMARY KEY, user_id INT, FOREIGN KEY (user_id) REFERENCES users (id))");
+await execute(PARENT, "INSERT INTO users (name) VALUES ('oscar'), ('beaumont')");
+await execute(PARENT, "INSERT INTO posts VALUES (1, 1)");
+
+try {
+  // Schema only
+  const schemaOnly = await api("POST", `/database/${PARENT}/branch`, { name: SCHEMA_ONLY });
+  check("create schema branch", schemaOnly.status === 201, schemaOnly.status);
+  const info = await (await api("GET", `/database/${SCHEMA_ONLY}`)).json();
+  check("branch tables", info.tables.map((t: any) => t.name).sort().join() === "posts,users", info);
+  check("branch lineage", info.branch?.parent === PARENT && info.branch?.created_by === adminUsername, info);
+  const empty = await execute(SCHEMA_ONLY, "SELECT COUNT(*) FROM users");
+  check("schema branch is empty", empty.values?.[0]?.[0] === "0", empty);
+  await execute(SCHEMA_ONLY, "INSERT INTO users (name) VALUES ('new')");
+  const id = await execute(SCHEMA_ONLY, "SELECT id FROM users");
+  check("auto increment restarts", id.values?.[0]?.[0] === "1", id);
+
+  // With data
+  const withData = await api("POST", `/database/${PARENT}/branch`, { name: WITH_DATA, copy_data: true });
+  check("create data branch", withData.status === 201, withData.status);
+  const rows = await execute(WITH_DATA, "SELECT name, upper_name FROM users ORDER BY id");
+  check("data was copied", JSON.stringify(rows.values) === JSON.stringify([["'oscar'", "'OSCAR'"], ["'beaumont'", "'BEAUMONT'"]]), rows);
+  const fk = await execute(WITH_DATA, "INSERT INTO posts VALUES (2, 100)");
+  check("foreign keys were copied", !!fk.error, fk);
+
+  // Errors
+  check("existing name", (await api("POST", `/database/${PARENT}/branch`, { name: WITH_DATA })).status === 409);
+  check("missing parent", (await api("POST", "/database/cityscale_missing/branch", { name: "cityscale_x" })).status === 404);
+
+  // Listing
+  const dbs = await (await api("GET", "/database")).json();
+  const branch = dbs.find((db: any) => db.name === WITH_DATA);
+  check("listing lineage", branch?.branch?.parent === PARENT, dbs);
+  check("parent isn't a branch", dbs.find((db: any) => db.name === PARENT)?.branch === null, dbs);
+} finally {
+  for (const db of [WITH_DATA, SCHEMA_ONLY, PARENT]) await api("DELETE", `/database/${db}`);
+}
+
+finish("Branching works!");
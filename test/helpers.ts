@@ -0,0 +1,39 @@
+// Helpers shared by the tests, which are each run on their own against a running Cityscale server.
+
+let failed = false;
+
+// Record the result of a check. The test fails once it's finished if any of them failed.
+export function check(name: string, ok: boolean, detail?: unknown) {
+  if (!ok) {
+    console.error(`${name} failed`, detail ?? "");
+    failed = true;
+  }
+}
+
+// Exit with the result of the checks.
+export function finish(message: string) {
+  if (failed) process.exit(1);
+  console.log(message);
+}
+
+// Login to the dashboard, returning a function to call it's API with the session.
+//
+// The admin credentials default to `CITYSCALE_ADMIN` (Eg. `admin:admin`).
+export async function login(base: string, username?: string, password?: string) {
+  if (username === undefined) [username, password] = (process.env.CITYSCALE_ADMIN ?? "admin:admin").split(":");
+
+  const res = await fetch(`${base}/api/login`, {
+    method: "POST",
+    headers: { "Content-Type": "application/json" },
+    body: JSON.stringify({ username, password }),
+  });
+  if (!res.ok) throw new Error(`login of ${username} failed: ${res.status}`);
+  const cookie = res.headers.getSetCookie().map((c) => c.split(";")[0]).join("; ");
+
+  return (method: string, path: string, body?: unknown) =>
+    fetch(`${base}/api${path}`, {
+      method,
+      headers: { Cookie: cookie, "Content-Type": "application/json" },
+      body: body === undefined ? undefined : JSON.stringify(body),
+    });
+}